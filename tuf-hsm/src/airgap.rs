@@ -0,0 +1,271 @@
+// A preflight check that the ceremony machine really is offline before
+// anything touches an HSM. The runbook insists on an air gap, but nothing
+// else checks that it's there.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The suffix of the file that records --allow-online overrides. The ultimate
+// path will be of the form {serial}_airgap_override.log.
+pub const OVERRIDE_FILE_SUFFIX: &str = "airgap_override.log";
+
+// From <linux/if.h>, <linux/if_arp.h> and <linux/route.h>.
+const IFF_UP: u32 = 0x1;
+const ARPHRD_LOOPBACK: u32 = 772;
+const RTF_REJECT: u32 = 0x0200;
+
+// The kinds of radio that rfkill has to report as blocked.
+const RADIO_TYPES: &[&str] = &["wlan", "bluetooth"];
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_string())
+}
+
+// Lists a directory's entries by name, in order.
+fn entry_names(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+// Every non-loopback interface that's administratively up.
+fn interfaces(sys: &Path) -> Vec<String> {
+    let dir = sys.join("class/net");
+    let names = match entry_names(&dir) {
+        Ok(names) => names,
+        Err(e) => {
+            return vec![format!(
+                "couldn't list the network interfaces in {:?}: {}",
+                dir, e
+            )]
+        }
+    };
+
+    names
+        .iter()
+        .filter_map(|name| {
+            let iface = dir.join(name);
+            let iface_type = read_trimmed(&iface.join("type")).and_then(|t| t.parse().ok());
+            if iface_type == Some(ARPHRD_LOOPBACK) {
+                return None;
+            }
+
+            let flags = read_trimmed(&iface.join("flags"))
+                .and_then(|flags| u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok());
+            let operstate =
+                read_trimmed(&iface.join("operstate")).unwrap_or_else(|| String::from("unknown"));
+
+            match flags {
+                Some(flags) if flags & IFF_UP == 0 => None,
+                Some(_) => Some(format!(
+                    "network interface {} is up (operstate {})",
+                    name, operstate
+                )),
+                None => Some(format!(
+                    "couldn't tell whether network interface {} is up",
+                    name
+                )),
+            }
+        })
+        .collect()
+}
+
+// Every Wi-Fi or Bluetooth radio that rfkill doesn't report as blocked.
+fn radios(sys: &Path) -> Vec<String> {
+    let dir = sys.join("class/rfkill");
+    let names = match entry_names(&dir) {
+        Ok(names) => names,
+        // No rfkill class means no radios (or no rfkill support at all).
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return vec![],
+        Err(e) => return vec![format!("couldn't list the radios in {:?}: {}", dir, e)],
+    };
+
+    names
+        .iter()
+        .filter_map(|entry| {
+            let radio = dir.join(entry);
+            let radio_type = read_trimmed(&radio.join("type"))?;
+            if !RADIO_TYPES.contains(&radio_type.as_str()) {
+                return None;
+            }
+
+            let soft = read_trimmed(&radio.join("soft"));
+            let hard = read_trimmed(&radio.join("hard"));
+            match (soft.as_deref(), hard.as_deref()) {
+                (Some("1"), _) | (_, Some("1")) => None,
+                _ => Some(format!(
+                    "{} radio {} ({}) isn't blocked by rfkill",
+                    radio_type,
+                    read_trimmed(&radio.join("name")).unwrap_or_default(),
+                    entry
+                )),
+            }
+        })
+        .collect()
+}
+
+// Every IPv4 and IPv6 default route.
+fn default_routes(proc: &Path) -> Vec<String> {
+    let mut findings = vec![];
+
+    let path = proc.join("net/route");
+    match fs::read_to_string(&path) {
+        Ok(routes) => {
+            for route in routes.lines().skip(1) {
+                let fields = route.split_whitespace().collect::<Vec<_>>();
+                if let [iface, "00000000", gateway, _, _, _, _, "00000000", ..] = fields.as_slice()
+                {
+                    // NOTE: The kernel prints addresses as host-order integers
+                    // of network-order bytes.
+                    let gateway = u32::from_str_radix(gateway, 16)
+                        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()).to_string())
+                        .unwrap_or_else(|_| gateway.to_string());
+                    findings.push(format!("default route via {} on {}", gateway, iface));
+                }
+            }
+        }
+        Err(e) => findings.push(format!("couldn't read the routes in {:?}: {}", path, e)),
+    }
+
+    // NOTE(ww): Without IPv6, there's no ipv6_route at all. With it, lo
+    // always has an unreachable default route, which doesn't count.
+    let path = proc.join("net/ipv6_route");
+    match fs::read_to_string(&path) {
+        Ok(routes) => {
+            for route in routes.lines() {
+                let fields = route.split_whitespace().collect::<Vec<_>>();
+                if let [dest, "00", _, _, _, _, _, _, flags, iface] = fields.as_slice() {
+                    let rejected = u32::from_str_radix(flags, 16)
+                        .map(|flags| flags & RTF_REJECT != 0)
+                        .unwrap_or(false);
+                    if dest.chars().all(|c| c == '0') && !rejected {
+                        findings.push(format!("default IPv6 route on {}", iface));
+                    }
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => findings.push(format!("couldn't read the routes in {:?}: {}", path, e)),
+    }
+
+    findings
+}
+
+// Every reason to think that the machine is online, going by the sysfs and
+// procfs mounted at `sys` and `proc`. Anything that can't be checked counts.
+pub fn check(sys: &Path, proc: &Path) -> Vec<String> {
+    let mut findings = interfaces(sys);
+    findings.extend(radios(sys));
+    findings.extend(default_routes(proc));
+    findings
+}
+
+#[cfg(target_os = "linux")]
+const CANT_CHECK: Option<&str> = None;
+
+// NOTE: The checks only understand Linux's sysfs and procfs. Anywhere else
+// (e.g. macOS) there's no telling whether the machine is offline, so the
+// preflight refuses, just as it would on a machine that's online.
+#[cfg(not(target_os = "linux"))]
+const CANT_CHECK: Option<&str> = Some(
+    "unsupported platform: the air-gap preflight can only check Linux; \
+     check the air gap by hand",
+);
+
+// Every reason to think that the running machine is online.
+fn findings() -> Vec<String> {
+    match CANT_CHECK {
+        None => check(Path::new("/sys"), Path::new("/proc")),
+        Some(reason) => vec![format!("{} ({})", reason, std::env::consts::OS)],
+    }
+}
+
+// An operator's decision to provision on a machine that doesn't look offline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+    // Why, in the operator's words (from --allow-online).
+    pub reason: String,
+
+    // What the preflight found.
+    pub findings: Vec<String>,
+}
+
+// Checks the running machine. Any finding is an error, unless the operator
+// passed --allow-online, in which case the override is returned for recording.
+pub fn preflight(allow_online: Option<&str>) -> Result<Option<Override>, String> {
+    let findings = findings();
+    if findings.is_empty() {
+        println!(
+            "Air-gap preflight passed: no network interfaces up, no radios, no default route."
+        );
+        return Ok(None);
+    }
+
+    let found = findings
+        .iter()
+        .map(|finding| format!("  - {}", finding))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    match allow_online {
+        None if CANT_CHECK.is_some() => Err(format!(
+            "can't tell whether this machine is air-gapped:\n{}\nonce you have, pass \
+             --allow-online REASON to override (the override is recorded with the ceremony products)",
+            found
+        )),
+        None => Err(format!(
+            "this machine doesn't look air-gapped:\n{}\ndisconnect it, or pass \
+             --allow-online REASON to override (the override is recorded with the ceremony products)",
+            found
+        )),
+        Some(reason) => {
+            eprintln!(
+                "WARNING: overriding the air-gap preflight ({}). It found:\n{}",
+                reason, found
+            );
+            Ok(Some(Override {
+                reason: reason.into(),
+                findings,
+            }))
+        }
+    }
+}
+
+// Appends an override to {output_dir}/{serial_number}_airgap_override.log.
+pub fn record_override(
+    output_dir: &Path,
+    serial_number: &str,
+    airgap_override: &Override,
+) -> Result<(), String> {
+    let path = output_dir.join(format!("{}_{}", serial_number, OVERRIDE_FILE_SUFFIX));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut record = format!(
+        "{} air-gap preflight overridden: {}\n",
+        now, airgap_override.reason
+    );
+    for finding in &airgap_override.findings {
+        record.push_str(&format!("{}   found: {}\n", now, finding));
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            file.write_all(record.as_bytes())?;
+            file.sync_all()
+        })
+        .map_err(|e| format!("couldn't record the air-gap override in {:?}: {}", path, e))
+}
@@ -0,0 +1,93 @@
+// The operations that provisioning needs from an HSM, whatever its vendor.
+//
+// Each vendor's provisioning flow (yubihsm::provision, pkcs11::provision) is
+// written in terms of these. What differs between the flows is what they do
+// around them: how an interrupted run is resumed, and which products are
+// written.
+
+use std::fmt;
+
+use crate::error::Error;
+use crate::journal::Journal;
+use crate::prompt::Prompt;
+
+// An object on an HSM, as listed by Backend::list_objects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    // The object's ID, as the vendor's own tools show it: e.g. decimal on a
+    // YubiHSM, and hex (as with `pkcs11-tool --id`) on a PKCS#11 token.
+    pub id: String,
+
+    // What kind of object it is, e.g. "asymmetric-key" or "public-key".
+    pub kind: String,
+
+    pub label: String,
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} ({})", self.kind, self.id, self.label)
+    }
+}
+
+pub trait Backend {
+    // How to find the HSM: e.g. the transport or PKCS#11 module to use, and
+    // which HSM to pick if several are attached.
+    type Config;
+
+    // What to generate a key for: the vendor's description of a TUF role.
+    type Role;
+
+    // Finds the one HSM that `config` picks out, and checks that it's the
+    // kind of HSM that this backend can provision.
+    fn discover(config: &Self::Config) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    // The serial number that the HSM reports, which its products are named by.
+    fn serial_number(&self) -> &str;
+
+    // The firmware version that the HSM reports, e.g. "2.2.0".
+    fn firmware(&mut self) -> Result<String, Error>;
+
+    // Wipes the HSM back to its factory state, once the user confirms.
+    // This is IRREVERSIBLE.
+    fn reset(&mut self, prompt: &mut dyn Prompt, journal: &mut Journal) -> Result<(), Error>;
+
+    // Replaces the HSM's factory credentials with new ones from the user, just
+    // powerful enough to generate and use the roles' keys, and logs in with
+    // them.
+    fn set_credentials(
+        &mut self,
+        roles: &[Self::Role],
+        prompt: &mut dyn Prompt,
+        journal: &mut Journal,
+    ) -> Result<(), Error>;
+
+    // Generates a role's keypair. The private half never leaves the HSM.
+    fn generate_key(&mut self, role: &Self::Role) -> Result<(), Error>;
+
+    // The public key of a role's keypair: an uncompressed SEC1 point for EC
+    // keys, or the 32 raw bytes for Ed25519.
+    fn public_key(&mut self, role: &Self::Role) -> Result<Vec<u8>, Error>;
+
+    // A DER-encoded X.509 certificate in which the HSM attests that it
+    // generated a role's keypair, or None if the HSM can't attest to its keys.
+    fn attest(&mut self, role: &Self::Role) -> Result<Option<Vec<u8>>, Error>;
+
+    // Every object on the HSM that the current login can see.
+    fn list_objects(&mut self) -> Result<Vec<Object>, Error>;
+}
+
+// Prints every object on the HSM, so that the operator can see that nothing is
+// left on it but what provisioning put there.
+pub fn show_objects<B: Backend>(hsm: &mut B) -> Result<(), Error> {
+    let objects = hsm.list_objects()?;
+
+    println!("The HSM now holds {} objects:", objects.len());
+    for object in &objects {
+        println!("  {}", object);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+// The parts of the command line that every vendor's provisioner shares.
+
+use clap::{App, Arg, ArgMatches};
+
+use std::path::{Path, PathBuf};
+
+use crate::doctor::{self, Environment, Status};
+use crate::error::Error;
+use crate::manifest::{self, Manifest};
+use crate::{Vendor, CEREMONY_PRODUCTS_DIR};
+
+pub fn allow_online_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("allow-online")
+        .help(
+            "provision even though the air-gap preflight found a way online, \
+             recording REASON (and what it found) with the ceremony products",
+        )
+        .long("allow-online")
+        .value_name("REASON")
+        .multiple(false)
+        .takes_value(true)
+}
+
+pub fn resume_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("resume")
+        .help("pick up a provisioning run that the journal says was interrupted")
+        .long("resume")
+        .multiple(false)
+}
+
+pub fn runbook_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("i-have-read-the-runbook")
+        .help("answer \"yes\" to every confirmation, for unattended rehearsals")
+        .long("i-have-read-the-runbook")
+        .multiple(false)
+}
+
+// The doctor subcommand, minus any vendor-specific arguments.
+pub fn doctor_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .about("checks that this machine is ready for a ceremony, and prints a pass/fail table")
+        .arg(
+            Arg::with_name("output-dir")
+                .help(
+                    "a directory that the products will be written to or copied into, \
+                     e.g. /media/ceremony-products",
+                )
+                .long("output-dir")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .default_value(CEREMONY_PRODUCTS_DIR),
+        )
+}
+
+pub fn doctor(
+    matches: &ArgMatches,
+    vendor: Vendor,
+    pkcs11_module: Option<Result<PathBuf, String>>,
+) -> Result<(), Error> {
+    // NOTE: This unwrap is safe, since the argument has a default.
+    let output_dirs = matches
+        .values_of("output-dir")
+        .unwrap()
+        .map(PathBuf::from)
+        .collect();
+
+    let checks = doctor::run(vendor, &Environment::system(pkcs11_module, output_dirs));
+    print!("{}", doctor::table(&checks));
+
+    match checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count()
+    {
+        0 => Ok(()),
+        failed => Err(Error::Other(format!(
+            "{} of {} checks failed",
+            failed,
+            checks.len()
+        ))),
+    }
+}
+
+pub fn verify_manifest_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .about("rechecks an HSM's ceremony products (or a copy of them) against their manifest")
+        .arg(
+            Arg::with_name("products")
+                .help("the HSM's products directory, e.g. /media/ceremony-products/XXXXXXXXXX")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .help("the manifest to check against (default: the one in the products directory)")
+                .long("manifest")
+                .multiple(false)
+                .takes_value(true),
+        )
+}
+
+pub fn verify_manifest(matches: &ArgMatches) -> Result<(), Error> {
+    // NOTE: This unwrap is safe, since the argument is required.
+    let products_dir = Path::new(matches.value_of("products").unwrap());
+    let manifest_path = match matches.value_of("manifest") {
+        Some(path) => PathBuf::from(path),
+        None => manifest::find(products_dir)?,
+    };
+
+    let report = manifest::verify(products_dir, &Manifest::read(&manifest_path)?)?;
+    println!("{}", report);
+
+    match report.passed() {
+        true => Ok(()),
+        false => Err(Error::Manifest(format!(
+            "{} doesn't match {}",
+            products_dir.display(),
+            manifest_path.display()
+        ))),
+    }
+}
@@ -0,0 +1,509 @@
+// Checks that the ceremony computer is ready for provisioning, i.e. the
+// machine-checkable parts of the pre-ceremony (PRE-CEREMONY.md).
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use crate::Vendor;
+
+// The YubiHSM 2's USB IDs, as matched by assets/99-yubihsm.rules.
+pub const YUBICO_VENDOR_ID: &str = "1050";
+pub const YUBIHSM2_PRODUCT_ID: &str = "0030";
+
+// Where udev looks for rules, in order of precedence.
+pub const UDEV_RULES_DIRS: &[&str] = &[
+    "/etc/udev/rules.d",
+    "/run/udev/rules.d",
+    "/lib/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+];
+
+// Where pcscd listens, depending on the distribution.
+pub const PCSCD_SOCKETS: &[&str] = &["/run/pcscd/pcscd.comm", "/var/run/pcscd/pcscd.comm"];
+
+// The names of the checks that only apply to some vendors' HSMs.
+const USB_PERMISSIONS: &str = "YubiHSM 2 USB permissions";
+const UDEV_RULE: &str = "YubiHSM 2 udev rule";
+const PCSCD: &str = "pcscd";
+
+// The least free space that an output directory needs: the products
+// themselves are tiny, but the journal and the later copy shouldn't fail.
+pub const MIN_FREE_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Pass,
+    Fail,
+    // The check doesn't apply here (e.g. there's nothing attached to check).
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Pass => f.pad("PASS"),
+            Status::Fail => f.pad("FAIL"),
+            Status::Skip => f.pad("SKIP"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Check {
+        Check {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+// Everything that the checks look at, so that tests can point them elsewhere.
+pub struct Environment {
+    // Where sysfs and devtmpfs are mounted.
+    pub sys: PathBuf,
+    pub dev: PathBuf,
+
+    pub udev_rules_dirs: Vec<PathBuf>,
+    pub pcscd_sockets: Vec<PathBuf>,
+
+    // The PKCS#11 module that provisioning would load (or why there isn't
+    // one), if provisioning loads one at all.
+    pub pkcs11_module: Option<Result<PathBuf, String>>,
+
+    pub output_dirs: Vec<PathBuf>,
+}
+
+impl Environment {
+    // The running machine.
+    pub fn system(
+        pkcs11_module: Option<Result<PathBuf, String>>,
+        output_dirs: Vec<PathBuf>,
+    ) -> Environment {
+        Environment {
+            sys: PathBuf::from("/sys"),
+            dev: PathBuf::from("/dev"),
+            udev_rules_dirs: UDEV_RULES_DIRS.iter().map(PathBuf::from).collect(),
+            pcscd_sockets: PCSCD_SOCKETS.iter().map(PathBuf::from).collect(),
+            pkcs11_module,
+            output_dirs,
+        }
+    }
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_string())
+}
+
+fn c_path(path: &Path) -> Result<CString, String> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| format!("{:?} contains a NUL byte", path))
+}
+
+fn accessible(path: &Path, mode: libc::c_int) -> Result<(), String> {
+    let c_path = c_path(path)?;
+    // SAFETY: c_path is a valid C string.
+    match unsafe { libc::access(c_path.as_ptr(), mode) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error().to_string()),
+    }
+}
+
+// Whether the current user can open every attached YubiHSM 2's device node.
+fn usb_permissions(env: &Environment) -> Check {
+    const NAME: &str = USB_PERMISSIONS;
+
+    let devices = env.sys.join("bus/usb/devices");
+    let entries = match fs::read_dir(&devices) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return Check::new(
+                NAME,
+                Status::Fail,
+                format!("couldn't list USB devices in {:?}: {}", devices, e),
+            )
+        }
+    };
+
+    let mut nodes = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|device| {
+            read_trimmed(&device.join("idVendor")).as_deref() == Some(YUBICO_VENDOR_ID)
+                && read_trimmed(&device.join("idProduct")).as_deref() == Some(YUBIHSM2_PRODUCT_ID)
+        })
+        .map(|device| {
+            let number =
+                |attr| read_trimmed(&device.join(attr)).and_then(|n| n.parse::<u32>().ok());
+            match (number("busnum"), number("devnum")) {
+                (Some(bus), Some(dev)) => {
+                    Ok(env.dev.join(format!("bus/usb/{:03}/{:03}", bus, dev)))
+                }
+                _ => Err(format!("couldn't find the device node for {:?}", device)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>();
+
+    if let Ok(nodes) = &mut nodes {
+        nodes.sort();
+    }
+
+    match nodes {
+        Err(e) => Check::new(NAME, Status::Fail, e),
+        Ok(nodes) if nodes.is_empty() => Check::new(
+            NAME,
+            Status::Skip,
+            format!(
+                "no YubiHSM 2 ({}:{}) attached",
+                YUBICO_VENDOR_ID, YUBIHSM2_PRODUCT_ID
+            ),
+        ),
+        Ok(nodes) => {
+            for node in &nodes {
+                if let Err(e) = accessible(node, libc::R_OK | libc::W_OK) {
+                    return Check::new(
+                        NAME,
+                        Status::Fail,
+                        format!(
+                            "can't read and write {:?} ({}); is the udev rule installed?",
+                            node, e
+                        ),
+                    );
+                }
+            }
+
+            Check::new(
+                NAME,
+                Status::Pass,
+                format!("can read and write {} device(s)", nodes.len()),
+            )
+        }
+    }
+}
+
+// Whether a udev rule grants access to the YubiHSM 2, like assets/99-yubihsm.rules.
+fn udev_rule(env: &Environment) -> Check {
+    const NAME: &str = UDEV_RULE;
+
+    let vendor = format!("{{idVendor}}==\"{}\"", YUBICO_VENDOR_ID);
+    let product = format!("{{idProduct}}==\"{}\"", YUBIHSM2_PRODUCT_ID);
+
+    for dir in &env.udev_rules_dirs {
+        let mut rules = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "rules"))
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        rules.sort();
+
+        for rule in rules {
+            let contents = fs::read_to_string(&rule).unwrap_or_default();
+            if contents
+                .lines()
+                .any(|line| line.contains(&vendor) && line.contains(&product))
+            {
+                return Check::new(NAME, Status::Pass, format!("{:?}", rule));
+            }
+        }
+    }
+
+    Check::new(
+        NAME,
+        Status::Fail,
+        format!(
+            "no rule for {}:{} in {}; install assets/99-yubihsm.rules",
+            YUBICO_VENDOR_ID,
+            YUBIHSM2_PRODUCT_ID,
+            env.udev_rules_dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    )
+}
+
+// Whether pcscd, which the Nitrokey HSM is reached through, is listening.
+fn pcscd(env: &Environment) -> Check {
+    const NAME: &str = PCSCD;
+
+    match env.pcscd_sockets.iter().find(|socket| socket.exists()) {
+        Some(socket) => match UnixStream::connect(socket) {
+            Ok(_) => Check::new(NAME, Status::Pass, format!("listening on {:?}", socket)),
+            Err(e) => Check::new(
+                NAME,
+                Status::Fail,
+                format!("couldn't connect to {:?}: {}", socket, e),
+            ),
+        },
+        None => Check::new(
+            NAME,
+            Status::Fail,
+            format!(
+                "no socket at {}; is pcscd installed and running?",
+                env.pcscd_sockets
+                    .iter()
+                    .map(|socket| socket.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        ),
+    }
+}
+
+// Whether the PKCS#11 module loads and looks like a PKCS#11 module.
+fn pkcs11_module(env: &Environment) -> Check {
+    const NAME: &str = "PKCS#11 module";
+
+    let module = match &env.pkcs11_module {
+        None => {
+            return Check::new(
+                NAME,
+                Status::Skip,
+                "not used by this provisioner (pass --module to check one)",
+            )
+        }
+        Some(Err(e)) => return Check::new(NAME, Status::Fail, e.clone()),
+        Some(Ok(module)) => module,
+    };
+
+    let c_module = match c_path(module) {
+        Ok(c_module) => c_module,
+        Err(e) => return Check::new(NAME, Status::Fail, e),
+    };
+
+    // SAFETY: c_module is a valid C string; the handle is closed below.
+    let handle = unsafe { libc::dlopen(c_module.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Check::new(NAME, Status::Fail, format!("couldn't load {:?}", module));
+    }
+
+    // SAFETY: handle is a live handle from dlopen, and the symbol name is a
+    // valid C string.
+    let symbol = unsafe { libc::dlsym(handle, b"C_GetFunctionList\0".as_ptr() as *const _) };
+    unsafe { libc::dlclose(handle) };
+
+    match symbol.is_null() {
+        true => Check::new(
+            NAME,
+            Status::Fail,
+            format!(
+                "{:?} has no C_GetFunctionList; is it a PKCS#11 module?",
+                module
+            ),
+        ),
+        false => Check::new(NAME, Status::Pass, format!("{:?}", module)),
+    }
+}
+
+// The directory itself if it exists, or else the nearest ancestor that does
+// (which is where provisioning would create it).
+fn nearest_existing(dir: &Path) -> Option<&Path> {
+    dir.ancestors()
+        .map(|ancestor| match ancestor.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => ancestor,
+        })
+        .find(|ancestor| ancestor.is_dir())
+}
+
+fn statvfs(path: &Path) -> Result<libc::statvfs, String> {
+    let c_path = c_path(path)?;
+    // SAFETY: c_path is a valid C string, and stat is only read once
+    // statvfs has filled it in.
+    unsafe {
+        let mut stat = std::mem::zeroed::<libc::statvfs>();
+        match libc::statvfs(c_path.as_ptr(), &mut stat) {
+            0 => Ok(stat),
+            _ => Err(format!(
+                "couldn't stat {:?}: {}",
+                path,
+                io::Error::last_os_error()
+            )),
+        }
+    }
+}
+
+// The filesystem type of `path`, and whether it's fit to hold ceremony
+// products: in-memory filesystems vanish on power-off, and network
+// filesystems have no business on an air-gapped machine.
+#[cfg(target_os = "linux")]
+fn filesystem_type(path: &Path) -> Result<(String, bool), String> {
+    let c_path = c_path(path)?;
+    // SAFETY: As in statvfs.
+    let stat = unsafe {
+        let mut stat = std::mem::zeroed::<libc::statfs>();
+        match libc::statfs(c_path.as_ptr(), &mut stat) {
+            0 => stat,
+            _ => {
+                return Err(format!(
+                    "couldn't stat {:?}: {}",
+                    path,
+                    io::Error::last_os_error()
+                ))
+            }
+        }
+    };
+
+    // From statfs(2).
+    Ok(match stat.f_type as u64 {
+        0xEF53 => ("ext2/3/4".into(), true),
+        0x4d44 => ("vfat".into(), true),
+        0x2011_BAB0 => ("exfat".into(), true),
+        0x5346_544e => ("ntfs".into(), true),
+        0x5846_5342 => ("xfs".into(), true),
+        0x9123_683E => ("btrfs".into(), true),
+        0xF2F5_2010 => ("f2fs".into(), true),
+        0x794c_7630 => ("overlayfs".into(), true),
+        0x0102_1994 => ("tmpfs".into(), false),
+        0x8584_58f6 => ("ramfs".into(), false),
+        0x6969 => ("nfs".into(), false),
+        0xFF53_4D42 => ("cifs".into(), false),
+        0xFE53_4D42 => ("smb2".into(), false),
+        f_type => (format!("unknown ({:#x})", f_type), true),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn filesystem_type(_path: &Path) -> Result<(String, bool), String> {
+    Ok(("unknown (not Linux)".into(), true))
+}
+
+fn output_dir(dir: &Path) -> Vec<Check> {
+    let writable = format!("{} writable", dir.display());
+    let filesystem = format!("{} filesystem", dir.display());
+    let free_space = format!("{} free space", dir.display());
+
+    let existing = match nearest_existing(dir) {
+        Some(existing) => existing,
+        None => {
+            return vec![Check::new(
+                writable,
+                Status::Fail,
+                "neither it nor any parent exists",
+            )]
+        }
+    };
+    let note = match existing == dir {
+        true => String::new(),
+        false => format!(" (doesn't exist yet; checked {:?})", existing),
+    };
+
+    let mut checks = vec![];
+
+    checks.push(match accessible(existing, libc::W_OK | libc::X_OK) {
+        Ok(()) => Check::new(writable, Status::Pass, format!("writable{}", note)),
+        Err(e) => Check::new(
+            writable,
+            Status::Fail,
+            format!("not writable: {}{}", e, note),
+        ),
+    });
+
+    checks.push(match filesystem_type(existing) {
+        Ok((name, true)) => Check::new(filesystem, Status::Pass, name),
+        Ok((name, false)) => Check::new(
+            filesystem,
+            Status::Fail,
+            format!("{}, which won't hold the products safely", name),
+        ),
+        Err(e) => Check::new(filesystem, Status::Fail, e),
+    });
+
+    checks.push(match statvfs(existing) {
+        Ok(stat) => {
+            // NOTE: These are only u32 on some 32-bit targets, e.g. the Raspberry Pi.
+            #[allow(clippy::unnecessary_cast)]
+            let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+            let detail = format!(
+                "{} MiB free (need {} MiB)",
+                free / (1024 * 1024),
+                MIN_FREE_BYTES / (1024 * 1024)
+            );
+            match free >= MIN_FREE_BYTES {
+                true => Check::new(free_space, Status::Pass, detail),
+                false => Check::new(free_space, Status::Fail, detail),
+            }
+        }
+        Err(e) => Check::new(free_space, Status::Fail, e),
+    });
+
+    checks
+}
+
+// A check that doesn't apply to `vendor`'s HSMs.
+fn not_used(name: &str, vendor: Vendor) -> Check {
+    Check::new(name, Status::Skip, format!("not used by {} HSMs", vendor))
+}
+
+// Runs every check that applies to `vendor`'s HSMs against `env`. The others
+// are skipped: only the YubiHSM 2 is reached over raw USB, and only the
+// PKCS#11 tokens through pcscd (and, unless one is given, a PKCS#11 module).
+pub fn run(vendor: Vendor, env: &Environment) -> Vec<Check> {
+    let mut checks = match vendor {
+        Vendor::YubiHsm => vec![
+            usb_permissions(env),
+            udev_rule(env),
+            not_used(PCSCD, vendor),
+            pkcs11_module(env),
+        ],
+        Vendor::Nitrokey => vec![
+            not_used(USB_PERMISSIONS, vendor),
+            not_used(UDEV_RULE, vendor),
+            pcscd(env),
+            pkcs11_module(env),
+        ],
+    };
+    for dir in &env.output_dirs {
+        checks.extend(output_dir(dir));
+    }
+    checks
+}
+
+// Formats the checks as a table, with one check per row.
+pub fn table(checks: &[Check]) -> String {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .chain(Some("CHECK".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!("{:<width$}  RESULT  DETAILS\n", "CHECK", width = width);
+    for check in checks {
+        let mut lines = check.detail.lines();
+        table.push_str(&format!(
+            "{:<width$}  {:<6}  {}\n",
+            check.name,
+            check.status,
+            lines.next().unwrap_or_default(),
+            width = width
+        ));
+        for line in lines {
+            table.push_str(&format!(
+                "{:<width$}  {:<6}  {}\n",
+                "",
+                "",
+                line,
+                width = width
+            ));
+        }
+    }
+    table
+}
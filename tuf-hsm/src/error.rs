@@ -0,0 +1,224 @@
+// Why provisioning failed, so that the runbook (and any script around it) can
+// tell the failures apart by exit code, and the operator is told which of its
+// IF/THEN steps to follow.
+//
+// Every variant carries the message that's printed. Helpers that still fail
+// with a plain String (e.g. argument checks) become Error::Other, which exits
+// with 1.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    // The user answered "no" to a confirmation.
+    UserAbort(String),
+
+    // No HSM (or no matching HSM) is attached.
+    DeviceNotFound(String),
+
+    // More than one HSM is attached, and nothing picked one.
+    MultipleDevices(String),
+
+    // The HSM rejected a password, PIN or authentication key.
+    AuthFailure(String),
+
+    // Talking to the HSM failed on the way to it (its connector, USB, or a
+    // session), rather than the HSM refusing anything.
+    Device(String),
+
+    // The two entries of a new password or PIN didn't match.
+    CredentialMismatch(String),
+
+    // A new authentication key, password or PIN couldn't be set, or the
+    // default authentication key couldn't be removed.
+    CredentialChange(String),
+
+    // The journal of earlier runs rules this one out as asked: an earlier run
+    // was interrupted and --resume wasn't given, or there's nothing to resume.
+    Journal(String),
+
+    // The HSM (or its products) isn't in any state that provisioning would
+    // have left it in, so nothing can safely be redone.
+    ManualIntervention(String),
+
+    // The HSM is locked, one PIN attempt away from locking, or failed its
+    // self-check.
+    DeviceLocked(String),
+
+    // A key couldn't be generated, or its public half couldn't be read back.
+    KeyGeneration(String),
+
+    // A key (or the HSM itself) couldn't be attested to.
+    Attestation(String),
+
+    // The HSM's ceremony products already exist.
+    OutputExists(String),
+
+    // Reading or writing the ceremony products failed.
+    Io(String),
+
+    // The ceremony products don't match their manifest.
+    Manifest(String),
+
+    // The ceremony plan couldn't be read, has no such body, or is for a
+    // different HSM.
+    Plan(String),
+
+    // Anything else, e.g. bad arguments.
+    Other(String),
+}
+
+impl Error {
+    // The exit codes, one per variant. These are documented in the README, and
+    // MUST NOT change once a ceremony relies on them.
+    pub const EXIT_OTHER: i32 = 1;
+    pub const EXIT_USER_ABORT: i32 = 10;
+    pub const EXIT_DEVICE_NOT_FOUND: i32 = 11;
+    pub const EXIT_MULTIPLE_DEVICES: i32 = 12;
+    pub const EXIT_AUTH_FAILURE: i32 = 13;
+    pub const EXIT_DEVICE_LOCKED: i32 = 14;
+    pub const EXIT_KEY_GENERATION: i32 = 15;
+    pub const EXIT_ATTESTATION: i32 = 16;
+    pub const EXIT_OUTPUT_EXISTS: i32 = 17;
+    pub const EXIT_IO: i32 = 18;
+    pub const EXIT_MANIFEST: i32 = 19;
+    pub const EXIT_DEVICE: i32 = 20;
+    pub const EXIT_CREDENTIAL_MISMATCH: i32 = 21;
+    pub const EXIT_CREDENTIAL_CHANGE: i32 = 22;
+    pub const EXIT_JOURNAL: i32 = 23;
+    pub const EXIT_MANUAL_INTERVENTION: i32 = 24;
+    pub const EXIT_PLAN: i32 = 25;
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::UserAbort(_) => Error::EXIT_USER_ABORT,
+            Error::DeviceNotFound(_) => Error::EXIT_DEVICE_NOT_FOUND,
+            Error::MultipleDevices(_) => Error::EXIT_MULTIPLE_DEVICES,
+            Error::AuthFailure(_) => Error::EXIT_AUTH_FAILURE,
+            Error::Device(_) => Error::EXIT_DEVICE,
+            Error::CredentialMismatch(_) => Error::EXIT_CREDENTIAL_MISMATCH,
+            Error::CredentialChange(_) => Error::EXIT_CREDENTIAL_CHANGE,
+            Error::Journal(_) => Error::EXIT_JOURNAL,
+            Error::ManualIntervention(_) => Error::EXIT_MANUAL_INTERVENTION,
+            Error::DeviceLocked(_) => Error::EXIT_DEVICE_LOCKED,
+            Error::KeyGeneration(_) => Error::EXIT_KEY_GENERATION,
+            Error::Attestation(_) => Error::EXIT_ATTESTATION,
+            Error::OutputExists(_) => Error::EXIT_OUTPUT_EXISTS,
+            Error::Io(_) => Error::EXIT_IO,
+            Error::Manifest(_) => Error::EXIT_MANIFEST,
+            Error::Plan(_) => Error::EXIT_PLAN,
+            Error::Other(_) => Error::EXIT_OTHER,
+        }
+    }
+
+    // What the runbook says to do about the failure, if it says anything.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Error::UserAbort(_) => Some(
+                "Provisioning stopped at your request. IF you meant to continue, THEN rerun \
+                 with the same arguments; IF it says that an earlier run was interrupted, \
+                 THEN add --resume.",
+            ),
+            Error::DeviceNotFound(_) => Some(
+                "IF the HSM isn't inserted, THEN insert it and rerun. IF it is, THEN check \
+                 the serial number that you passed (if any) against the list command's \
+                 output, and reinsert the HSM.",
+            ),
+            Error::MultipleDevices(_) => Some(
+                "Ensure that exactly one HSM is inserted, then rerun. For rehearsals only, \
+                 pass --serial to pick one.",
+            ),
+            Error::AuthFailure(_) => Some(
+                "IF this is a YubiHSM 2, THEN perform a physical reset and rerun. IF this \
+                 is a Nitrokey HSM, THEN check the PIN against the pre-ceremony sheet before \
+                 rerunning: every wrong PIN costs one of its few retries.",
+            ),
+            Error::Device(_) => Some(
+                "Reinsert the HSM (and restart yubihsm-connector, if it's in use), then \
+                 rerun with the same arguments, adding --resume if asked to.",
+            ),
+            Error::CredentialMismatch(_) => Some(
+                "Rerun with the same arguments, adding --resume if asked to, and enter the \
+                 new password or PIN exactly as it's written on the pre-ceremony sheet.",
+            ),
+            Error::CredentialChange(_) => Some(
+                "Rerun with --resume added to the same arguments. IF it fails again, THEN \
+                 stop: the HSM has to be inspected by hand.",
+            ),
+            Error::Journal(_) => Some(
+                "IF the error says to rerun with --resume, THEN do so, with the same \
+                 arguments. Otherwise, check that this is the HSM (and products directory) \
+                 that you meant. Do NOT delete or edit the journal to get past this.",
+            ),
+            Error::ManualIntervention(_) => Some(
+                "STOP: the HSM has to be inspected by hand. Do NOT delete or edit the \
+                 journal or the products to get past this.",
+            ),
+            Error::DeviceLocked(_) => Some(
+                "STOP: the HSM is locked, or one attempt away from locking. Do NOT try any \
+                 more PINs; the HSM has to be inspected by hand.",
+            ),
+            Error::KeyGeneration(_) => Some(
+                "Rerun with --resume added to the same arguments. IF it fails again, THEN \
+                 stop: the HSM has to be inspected by hand.",
+            ),
+            Error::Attestation(_) => Some(
+                "Rerun with --resume added to the same arguments; keys that were already \
+                 generated are re-attested, not replaced.",
+            ),
+            Error::OutputExists(_) => Some(
+                "This HSM's ceremony products already exist. IF it's being reprovisioned, \
+                 THEN move its ceremony-products directory aside (never delete it) and rerun.",
+            ),
+            Error::Io(_) => Some(
+                "Check that the ceremony-products directory is writable and that the disk \
+                 isn't full, then rerun with --resume added to the same arguments.",
+            ),
+            Error::Manifest(_) => Some(
+                "IF this is a copy of the products, THEN copy them again from the ceremony \
+                 machine and rerun verify-manifest. IF the originals fail too, THEN stop: the \
+                 products have been altered since the ceremony.",
+            ),
+            Error::Plan(_) => Some(
+                "Check --plan and --body against the ceremony plan. IF the plan is for a \
+                 different HSM, THEN insert the one that it names and rerun. Do NOT edit the \
+                 plan to match the HSM.",
+            ),
+            Error::Other(_) => None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::UserAbort(msg)
+            | Error::DeviceNotFound(msg)
+            | Error::MultipleDevices(msg)
+            | Error::AuthFailure(msg)
+            | Error::Device(msg)
+            | Error::CredentialMismatch(msg)
+            | Error::CredentialChange(msg)
+            | Error::Journal(msg)
+            | Error::ManualIntervention(msg)
+            | Error::DeviceLocked(msg)
+            | Error::KeyGeneration(msg)
+            | Error::Attestation(msg)
+            | Error::OutputExists(msg)
+            | Error::Io(msg)
+            | Error::Manifest(msg)
+            | Error::Plan(msg)
+            | Error::Other(msg) => msg,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::Other(msg)
+    }
+}
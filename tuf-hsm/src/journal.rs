@@ -0,0 +1,203 @@
+// A write-ahead journal of provisioning stages, kept alongside the ceremony
+// products as {products_dir}/{serial}/{serial}_journal.log.
+//
+// Each stage is bracketed by "begin" and "end" lines, each of which is fsynced
+// before we go on, so that after a crash (or a Ctrl-C, or a USB hiccup) the
+// journal says exactly which stages finished and which one was in flight.
+// A line looks like:
+//
+//   1600000000 begin keygen root
+//
+// That matters most for credentials: if a PKCS#11 token's SO PIN change was
+// in flight, nobody can say which SO PIN the token now has.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+// The suffix of the journal file. The ultimate path will be of the form
+// XXXXXXXXXX_journal.log, where XXXXXXXXXX is the serial number of the HSM.
+pub const JOURNAL_FILE_SUFFIX: &str = "journal.log";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage {
+    // A whole provisioning run, wrapping all of the stages below.
+    Provision,
+    // Resetting (or, for PKCS#11 tokens, reinitializing) the HSM, which wipes its keys.
+    Reset,
+    // Replacing a YubiHSM's default authentication key.
+    AuthKey,
+    // Changing a PKCS#11 token's SO PIN, and setting its user PIN.
+    SoPin,
+    UserPin,
+    // Key generation for a role, by role name.
+    Keygen(String),
+    // Attestation of a role's key, by role name.
+    Attestation(String),
+    // Writing a product, by filename suffix.
+    Write(String),
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Provision => write!(f, "provision"),
+            Stage::Reset => write!(f, "reset"),
+            Stage::AuthKey => write!(f, "auth-key"),
+            Stage::SoPin => write!(f, "so-pin"),
+            Stage::UserPin => write!(f, "user-pin"),
+            Stage::Keygen(role) => write!(f, "keygen {}", role),
+            Stage::Attestation(role) => write!(f, "attestation {}", role),
+            Stage::Write(suffix) => write!(f, "write {}", suffix),
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Stage, String> {
+        let mut parts = s.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("provision"), None) => Ok(Stage::Provision),
+            (Some("reset"), None) => Ok(Stage::Reset),
+            (Some("auth-key"), None) => Ok(Stage::AuthKey),
+            (Some("so-pin"), None) => Ok(Stage::SoPin),
+            (Some("user-pin"), None) => Ok(Stage::UserPin),
+            (Some("keygen"), Some(role)) => Ok(Stage::Keygen(role.into())),
+            (Some("attestation"), Some(role)) => Ok(Stage::Attestation(role.into())),
+            (Some("write"), Some(suffix)) => Ok(Stage::Write(suffix.into())),
+            _ => Err(format!("unknown stage {:?}", s)),
+        }
+    }
+}
+
+pub fn path(output_dir: &Path, serial_number: &str) -> PathBuf {
+    output_dir.join(format!("{}_{}", serial_number, JOURNAL_FILE_SUFFIX))
+}
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    // Opens the journal at `path` for appending, creating it if need be.
+    pub fn open(path: &Path) -> Result<Journal, Error> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(Journal { file }),
+            Err(e) => Err(Error::Io(format!(
+                "couldn't open journal {:?}: {}",
+                path, e
+            ))),
+        }
+    }
+
+    fn append(&mut self, event: &str, stage: &Stage) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        // NOTE(ww): The fsync is the whole point: a "begin" line has to be on
+        // disk before the stage touches the HSM.
+        writeln!(self.file, "{} {} {}", now, event, stage)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| Error::Io(format!("couldn't write to the journal: {}", e)))
+    }
+
+    pub fn begin(&mut self, stage: &Stage) -> Result<(), Error> {
+        self.append("begin", stage)
+    }
+
+    pub fn end(&mut self, stage: &Stage) -> Result<(), Error> {
+        self.append("end", stage)
+    }
+
+    // Runs `f` as the given stage, recording its beginning and (successful) end.
+    pub fn record<T, E>(
+        &mut self,
+        stage: Stage,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, Error>
+    where
+        Error: From<E>,
+    {
+        self.begin(&stage)?;
+        let result = f()?;
+        self.end(&stage)?;
+        Ok(result)
+    }
+}
+
+// What a journal says about the runs that wrote it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovery {
+    // Every stage that ended, across all runs.
+    pub completed: Vec<Stage>,
+
+    // The stages that the latest run began but never ended, outermost first.
+    pub interrupted: Vec<Stage>,
+}
+
+impl Recovery {
+    // Whether the latest run got all the way to the end.
+    pub fn finished(&self) -> bool {
+        self.interrupted.is_empty() && self.completed.last() == Some(&Stage::Provision)
+    }
+
+    // The innermost stage that was in flight when the latest run stopped.
+    pub fn interrupted_stage(&self) -> Option<&Stage> {
+        self.interrupted.last()
+    }
+
+    pub fn completed(&self, stage: &Stage) -> bool {
+        self.completed.contains(stage)
+    }
+}
+
+pub fn parse(contents: &str) -> Result<Recovery, String> {
+    let mut recovery = Recovery::default();
+
+    for (n, line) in contents.lines().enumerate() {
+        let bad = |why: &str| format!("malformed journal line {}: {}: {:?}", n + 1, why, line);
+
+        let mut parts = line.splitn(3, ' ');
+        let (event, stage) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(time), Some(event), Some(stage)) if time.parse::<u64>().is_ok() => {
+                (event, stage.parse::<Stage>().map_err(|e| bad(&e))?)
+            }
+            _ => return Err(bad("expected TIME EVENT STAGE")),
+        };
+
+        match event {
+            // A new run abandons whatever the last one left in flight.
+            "begin" if stage == Stage::Provision => recovery.interrupted = vec![stage],
+            "begin" => recovery.interrupted.push(stage),
+            "end" if recovery.interrupted.last() == Some(&stage) => {
+                recovery.interrupted.pop();
+                recovery.completed.push(stage);
+            }
+            "end" => return Err(bad("end of a stage that wasn't begun")),
+            _ => return Err(bad("unknown event")),
+        }
+    }
+
+    Ok(recovery)
+}
+
+// Reads the journal at `path`, if there is one.
+pub fn read(path: &Path) -> Result<Option<Recovery>, Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => parse(&contents).map(Some).map_err(Error::Journal),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io(format!(
+            "couldn't read journal {:?}: {}",
+            path, e
+        ))),
+    }
+}
@@ -0,0 +1,96 @@
+// Provisioning HSMs for TUF: resetting them, replacing their factory
+// credentials, and generating (and exporting, and where possible attesting)
+// a key for each TUF role.
+//
+// Every vendor implements backend::Backend; yubihsm covers the YubiHSM 2 and
+// pkcs11 covers PKCS#11 tokens (i.e. the Nitrokey HSM). Each vendor module also
+// has the command line that its provisioner (and `tuf-hsm provision --vendor`)
+// runs.
+
+use std::fmt;
+use std::process;
+
+pub mod airgap;
+pub mod backend;
+pub mod cli;
+pub mod doctor;
+pub mod error;
+pub mod journal;
+pub mod manifest;
+pub mod pkcs11;
+pub mod prompt;
+pub mod secret;
+pub mod transcript;
+pub mod yubihsm;
+
+use error::Error;
+use prompt::Prompt;
+
+// The parent directory that all ceremony products go into.
+// Each HSM's products go into {CEREMONY_PRODUCTS_DIR}/XXXXXXXXXX/, where
+// XXXXXXXXXX is the serial number of the HSM.
+pub const CEREMONY_PRODUCTS_DIR: &str = "ceremony-products";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Vendor {
+    YubiHsm,
+    Nitrokey,
+}
+
+impl Vendor {
+    // The names accepted by --vendor.
+    pub const NAMES: &'static [&'static str] = &["yubihsm", "nitrokey"];
+
+    pub fn from_name(name: &str) -> Option<Vendor> {
+        match name {
+            "yubihsm" => Some(Vendor::YubiHsm),
+            "nitrokey" => Some(Vendor::Nitrokey),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Vendor::YubiHsm => write!(f, "yubihsm"),
+            Vendor::Nitrokey => write!(f, "nitrokey"),
+        }
+    }
+}
+
+pub fn confirm(prompt: &mut dyn Prompt, msg: &str) -> Result<(), Error> {
+    match prompt.confirm(msg)? {
+        true => Ok(()),
+        false => Err(Error::UserAbort(String::from(
+            "user interrupted provisioning",
+        ))),
+    }
+}
+
+// Shows a vendor's warning banner, and makes the user acknowledge it.
+pub fn big_scary_banner(prompt: &mut dyn Prompt, banner: &str) -> Result<(), Error> {
+    println!("{}", banner);
+    confirm(prompt, "Continue?")
+}
+
+// Runs a provisioner's command line and exits with its status: 0 on success,
+// or the failure's exit code (see error::Error). Core dumps are disabled first,
+// so that no secret it goes on to read can end up in one.
+pub fn main(run: impl FnOnce() -> Result<(), Error>) -> ! {
+    let result = match secret::disable_core_dumps() {
+        Ok(()) => run(),
+        Err(e) => Err(Error::from(e)),
+    };
+
+    process::exit(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Fatal: {}", e);
+            if let Some(hint) = e.hint() {
+                eprintln!("{}", hint);
+            }
+            e.exit_code()
+        }
+    });
+}
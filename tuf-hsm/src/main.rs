@@ -0,0 +1,153 @@
+// One command line for provisioning every HSM that we support:
+//
+//   tuf-hsm provision --vendor yubihsm|nitrokey [ARGS]...
+//   tuf-hsm list --vendor yubihsm|nitrokey [ARGS]...
+//   tuf-hsm doctor --vendor yubihsm|nitrokey [ARGS]...
+//   tuf-hsm verify [ARGS]...
+//   tuf-hsm verify-manifest [ARGS]...
+//
+// Everything after --vendor is handed to that vendor's command line, so ARGS
+// are the same as yubihsm-provision's and nitrohsm-provision's. In particular,
+// other PKCS#11 tokens are provisioned with --vendor nitrokey --profile NAME.
+// Only YubiHSMs attest to their keys, so there's nothing to pick for verify,
+// and every vendor's manifest is checked the same way.
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use tuf_hsm::error::Error;
+use tuf_hsm::{cli, pkcs11, yubihsm, Vendor};
+
+fn args_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("args")
+        .help("the vendor's own arguments; pass --help after --vendor to see them")
+        .multiple(true)
+        .allow_hyphen_values(true)
+}
+
+// A subcommand that hands all of its arguments to another command line, --help
+// included.
+fn passthrough<'a, 'b>(name: &str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .setting(AppSettings::TrailingVarArg)
+        .setting(AppSettings::AllowLeadingHyphen)
+        .setting(AppSettings::DisableHelpFlags)
+        .arg(args_arg())
+}
+
+// A subcommand that hands everything after --vendor to the vendor's command
+// line, --help included.
+fn forwarding<'a, 'b>(name: &str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .setting(AppSettings::TrailingVarArg)
+        .setting(AppSettings::AllowLeadingHyphen)
+        .setting(AppSettings::DisableHelpFlags)
+        .arg(
+            Arg::with_name("vendor")
+                .help(
+                    "the kind of HSM; this must come before any of the vendor's arguments \
+                     (for other PKCS#11 tokens, use nitrokey and pass its --profile)",
+                )
+                .long("vendor")
+                .multiple(false)
+                .takes_value(true)
+                .required(true)
+                .possible_values(Vendor::NAMES),
+        )
+        .arg(args_arg())
+}
+
+// The arguments to hand to a vendor's command line, starting with the name
+// that its usage and errors should show.
+fn forwarded(name: &str, matches: &ArgMatches) -> Vec<String> {
+    let mut args = vec![name.to_string()];
+    args.extend(
+        matches
+            .values_of("args")
+            .into_iter()
+            .flatten()
+            .map(String::from),
+    );
+    args
+}
+
+// A vendor's help, minus the header line: clap would show the name that
+// forwarded gives it as "tuf-hsm-provision---vendor-yubihsm".
+const FORWARDED_HELP: &str = "{about}\n\nUSAGE:\n    {usage}\n\n{all-args}";
+
+fn parse<'a>(app: App<'a, '_>, args: Vec<String>) -> ArgMatches<'a> {
+    app.template(FORWARDED_HELP).get_matches_from(args)
+}
+
+fn run() -> Result<(), Error> {
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Provisions YubiHSMs and PKCS#11 tokens (e.g. the Nitrokey HSM) for TUF")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(forwarding(
+            "provision",
+            "resets an HSM and generates its TUF keys",
+        ))
+        .subcommand(forwarding("list", "lists the attached HSMs"))
+        .subcommand(forwarding(
+            "doctor",
+            "checks that this machine is ready for a ceremony, and prints a pass/fail table",
+        ))
+        .subcommand(passthrough(
+            "verify",
+            "verifies the attestations in a YubiHSM's ceremony products",
+        ))
+        .subcommand(passthrough(
+            "verify-manifest",
+            "rechecks an HSM's ceremony products (or a copy of them) against their manifest",
+        ))
+        .get_matches();
+
+    // NOTE: This is unreachable, since a subcommand is required.
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(matches)) => (command, matches),
+        _ => unreachable!(),
+    };
+
+    if command == "verify" {
+        let args = forwarded("tuf-hsm verify", matches);
+        return yubihsm::cli::verify(&parse(yubihsm::cli::verify_app("verify"), args));
+    }
+
+    if command == "verify-manifest" {
+        let args = forwarded("tuf-hsm verify-manifest", matches);
+        return cli::verify_manifest(&parse(cli::verify_manifest_app(command), args));
+    }
+
+    // NOTE: This unwrap is safe due to the flag restrictions in possible_values.
+    let vendor = Vendor::from_name(matches.value_of("vendor").unwrap()).unwrap();
+    let args = forwarded(&format!("tuf-hsm {} --vendor {}", command, vendor), matches);
+
+    match (command, vendor) {
+        ("provision", Vendor::YubiHsm) => {
+            yubihsm::cli::provision(&parse(yubihsm::cli::provision_app(command), args))
+        }
+        ("provision", Vendor::Nitrokey) => {
+            pkcs11::cli::provision(&parse(pkcs11::cli::provision_app(command), args))
+        }
+        ("list", Vendor::YubiHsm) => {
+            parse(yubihsm::cli::list_app(command), args);
+            yubihsm::cli::list()
+        }
+        ("list", Vendor::Nitrokey) => {
+            pkcs11::cli::list(&parse(pkcs11::cli::list_app(command), args))
+        }
+        ("doctor", Vendor::YubiHsm) => {
+            yubihsm::cli::doctor(&parse(yubihsm::cli::doctor_app(command), args))
+        }
+        ("doctor", Vendor::Nitrokey) => {
+            pkcs11::cli::doctor(&parse(pkcs11::cli::doctor_app(command), args))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    tuf_hsm::main(run)
+}
@@ -0,0 +1,297 @@
+// A manifest of a ceremony's products, written once provisioning finishes as
+// {products_dir}/{serial}/{serial}_manifest.json.
+//
+// The manifest says which HSM the products came from and which keys it holds,
+// and lists the other files in the products directory with their sizes and
+// digests. The journal and transcript are left out, since they're append-only
+// logs that a later run can add to after the manifest is written. Once the products are copied off of the ceremony machine (e.g. onto
+// the flash stick), verify-manifest rechecks the copy against it.
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::Error;
+use crate::journal::JOURNAL_FILE_SUFFIX;
+use crate::transcript::{hex, TRANSCRIPT_FILE_SUFFIX};
+
+// The suffix of the manifest file. The ultimate path will be of the form
+// XXXXXXXXXX_manifest.json, where XXXXXXXXXX is the serial number of the HSM.
+pub const MANIFEST_FILE_SUFFIX: &str = "manifest.json";
+
+// The HSM that the products came from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    // e.g. "yubihsm" or a PKCS#11 profile name like "nitrokey".
+    pub vendor: String,
+    pub serial_number: String,
+    pub firmware: String,
+}
+
+// A role whose key the HSM holds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+
+    // The key's object ID, as the vendor's own tools show it.
+    pub object_id: String,
+    pub label: String,
+    pub key_type: String,
+}
+
+// A product file, by filename (not path).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Product {
+    pub file: String,
+    pub size: u64,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+impl Product {
+    pub fn new(file: &str, contents: &[u8]) -> Product {
+        Product {
+            file: file.into(),
+            size: contents.len() as u64,
+            sha256: hex(digest::digest(&digest::SHA256, contents).as_ref()),
+            sha512: hex(digest::digest(&digest::SHA512, contents).as_ref()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub device: Device,
+    pub roles: Vec<Role>,
+    pub products: Vec<Product>,
+}
+
+pub fn path(output_dir: &Path, serial_number: &str) -> PathBuf {
+    output_dir.join(format!("{}_{}", serial_number, MANIFEST_FILE_SUFFIX))
+}
+
+fn is_manifest(file: &str) -> bool {
+    file.ends_with(&format!("_{}", MANIFEST_FILE_SUFFIX))
+}
+
+// Whether `file` is one of the append-only logs, which a manifest doesn't cover.
+fn is_log(file: &str) -> bool {
+    [JOURNAL_FILE_SUFFIX, TRANSCRIPT_FILE_SUFFIX]
+        .iter()
+        .any(|suffix| file.ends_with(&format!("_{}", suffix)))
+}
+
+// The names of the files in `dir` that a manifest covers, in order: every
+// regular file but the manifest itself and the logs.
+fn product_files(dir: &Path) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(Error::Io(format!("couldn't list {:?}: {}", dir, e))),
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(Error::Io(format!("couldn't list {:?}: {}", dir, e))),
+        };
+
+        let file = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_file() && !is_manifest(&file) && !is_log(&file) {
+            files.push(file);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn read_product(dir: &Path, file: &str) -> Result<Vec<u8>, String> {
+    // NOTE: Filenames come straight from the manifest, so anything that would
+    // reach outside of `dir` (e.g. "../../etc/shadow" or "/etc/shadow") has to
+    // be refused before it's joined onto it.
+    let mut components = Path::new(file).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !file.contains(&['/', '\\'][..]) => {}
+        _ => return Err(format!("{:?} isn't a plain filename", file)),
+    }
+
+    fs::read(dir.join(file)).map_err(|e| format!("couldn't read {}: {}", file, e))
+}
+
+impl Manifest {
+    // Describes the products currently in `output_dir`.
+    pub fn for_dir(output_dir: &Path, device: Device, roles: Vec<Role>) -> Result<Manifest, Error> {
+        let mut products = vec![];
+        for file in product_files(output_dir)? {
+            let contents = read_product(output_dir, &file).map_err(Error::Io)?;
+            products.push(Product::new(&file, &contents));
+        }
+
+        Ok(Manifest {
+            device,
+            roles,
+            products,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Manifest, Error> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(Error::Io(format!("couldn't read {:?}: {}", path, e))),
+        };
+
+        serde_json::from_slice(&contents)
+            .map_err(|e| Error::Manifest(format!("malformed manifest {:?}: {}", path, e)))
+    }
+
+    // Writes the manifest to `path`, replacing any earlier one.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        // NOTE: This unwrap is safe, since a Manifest is only ever strings and numbers.
+        let json = serde_json::to_string_pretty(self).unwrap();
+
+        File::create(path)
+            .and_then(|mut file| {
+                writeln!(file, "{}", json)?;
+                file.sync_all()
+            })
+            .map_err(|e| Error::Io(format!("couldn't write the manifest {:?}: {}", path, e)))
+    }
+}
+
+// Describes the products in {output_dir} and writes the manifest alongside
+// them. This has to come last, once nothing else will write to output_dir.
+pub fn write(output_dir: &Path, device: Device, roles: Vec<Role>) -> Result<Manifest, Error> {
+    let path = path(output_dir, &device.serial_number);
+    let manifest = Manifest::for_dir(output_dir, device, roles)?;
+    manifest.write(&path)?;
+
+    println!(
+        "Wrote a manifest of {} products to {}",
+        manifest.products.len(),
+        path.display()
+    );
+    Ok(manifest)
+}
+
+// Finds the one manifest in `dir`.
+pub fn find(dir: &Path) -> Result<PathBuf, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(Error::Io(format!("couldn't list {:?}: {}", dir, e))),
+    };
+
+    let mut manifests = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|file| is_manifest(file))
+        .collect::<Vec<_>>();
+    manifests.sort();
+
+    match manifests.as_slice() {
+        [manifest] => Ok(dir.join(manifest)),
+        [] => Err(Error::Manifest(format!(
+            "no *_{} in {:?}",
+            MANIFEST_FILE_SUFFIX, dir
+        ))),
+        _ => Err(Error::Manifest(format!(
+            "more than one manifest in {:?} ({}); refusing to guess",
+            dir,
+            manifests.join(", ")
+        ))),
+    }
+}
+
+pub struct Check {
+    // What was checked, e.g. "XXXXXXXXXX_root_pubkey.pem matches the manifest".
+    pub description: String,
+
+    // Why the check failed, if it did.
+    pub result: Result<(), String>,
+}
+
+pub struct Report {
+    pub device: Device,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Verification of the manifest for {} {} (firmware {}):",
+            self.device.vendor, self.device.serial_number, self.device.firmware
+        )?;
+        for check in &self.checks {
+            match &check.result {
+                Ok(()) => writeln!(f, "  PASS: {}", check.description)?,
+                Err(e) => writeln!(f, "  FAIL: {}: {}", check.description, e)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_product(dir: &Path, expected: &Product) -> Result<(), String> {
+    let actual = Product::new(&expected.file, &read_product(dir, &expected.file)?);
+
+    if actual.size != expected.size {
+        return Err(format!(
+            "size is {}, but the manifest says {}",
+            actual.size, expected.size
+        ));
+    }
+    if actual.sha256 != expected.sha256 {
+        return Err(format!(
+            "SHA-256 is {}, but the manifest says {}",
+            actual.sha256, expected.sha256
+        ));
+    }
+    if actual.sha512 != expected.sha512 {
+        return Err(format!(
+            "SHA-512 is {}, but the manifest says {}",
+            actual.sha512, expected.sha512
+        ));
+    }
+
+    Ok(())
+}
+
+// Rechecks the products in `dir` (e.g. a copy of them) against `manifest`:
+// every product that it lists has to be there, unchanged, and nothing else
+// can have been added.
+pub fn verify(dir: &Path, manifest: &Manifest) -> Result<Report, Error> {
+    let mut checks = vec![];
+
+    for product in &manifest.products {
+        checks.push(Check {
+            description: format!("{} matches the manifest", product.file),
+            result: check_product(dir, product),
+        });
+    }
+
+    for file in product_files(dir)? {
+        if !manifest.products.iter().any(|product| product.file == file) {
+            checks.push(Check {
+                description: format!("{} is listed in the manifest", file),
+                result: Err(String::from("unexpected file")),
+            });
+        }
+    }
+
+    Ok(Report {
+        device: manifest.device.clone(),
+        checks,
+    })
+}
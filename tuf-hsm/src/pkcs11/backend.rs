@@ -0,0 +1,579 @@
+// The Backend for PKCS#11 tokens, i.e. the Nitrokey HSM (through OpenSC), a
+// YubiKey's PIV application (through YKCS11) and, for rehearsals, SoftHSM2.
+
+use pkcs11::{errors, types, Ctx};
+
+use std::path::PathBuf;
+
+use super::piv;
+use super::profile::{Profile, Reset};
+use super::role::Role;
+use super::token::Selection;
+use super::{current_so_pin, find_hsm, pubkey, token_in_deadly_state, KeyType};
+use crate::backend::{Backend, Object};
+use crate::confirm;
+use crate::error::Error;
+use crate::journal::{Journal, Stage};
+use crate::prompt::{Prompt, SecretKind};
+use crate::secret::Secret;
+
+// How many object handles to ask for at a time when listing a token's objects.
+const FIND_OBJECTS_BATCH: types::CK_ULONG = 32;
+
+// Classifies a failure of something that checks a PIN (logging in, or
+// reinitializing the token) by what the token said about the PIN.
+fn pin_error(context: &str, e: errors::Error) -> Error {
+    let msg = format!("{}: {}", context, e);
+    match e {
+        errors::Error::Pkcs11(types::CKR_PIN_INCORRECT) => Error::AuthFailure(msg),
+        errors::Error::Pkcs11(types::CKR_PIN_LOCKED) => Error::DeviceLocked(msg),
+        _ => Error::Other(msg),
+    }
+}
+
+// How to find the token to provision.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // The PKCS#11 module to load; see find_pkcs11_module.
+    pub module: PathBuf,
+
+    // What kind of token to accept.
+    pub profile: Profile,
+
+    // The token's serial number, if several are attached.
+    pub serial: Option<String>,
+
+    // The token's slot ID, if several are attached.
+    pub slot: Option<types::CK_SLOT_ID>,
+}
+
+pub struct Pkcs11Token {
+    ctx: Ctx,
+    slot: types::CK_SLOT_ID,
+    serial_number: String,
+    profile: Profile,
+
+    // The token's current SO PIN, if it's already known. Otherwise, reset
+    // prompts for it.
+    so_pin: Option<Secret>,
+
+    // The session that set_credentials left logged in as whoever generates
+    // keys (see Profile::generate_as).
+    session: Option<types::CK_SESSION_HANDLE>,
+}
+
+impl Pkcs11Token {
+    pub fn new(ctx: Ctx, slot: types::CK_SLOT_ID, serial_number: String, profile: Profile) -> Self {
+        Pkcs11Token {
+            ctx,
+            slot,
+            serial_number,
+            profile,
+            so_pin: None,
+            session: None,
+        }
+    }
+
+    pub fn ctx(&self) -> &Ctx {
+        &self.ctx
+    }
+
+    pub fn slot(&self) -> types::CK_SLOT_ID {
+        self.slot
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    // Supplies the token's current SO PIN up front, e.g. from --so-pin.
+    pub fn set_so_pin(&mut self, so_pin: Secret) {
+        self.so_pin = Some(so_pin);
+    }
+
+    pub fn has_so_pin(&self) -> bool {
+        self.so_pin.is_some()
+    }
+
+    // Logs out and closes the user session, if there is one.
+    pub fn close_session(&mut self) -> Result<(), Error> {
+        match self.session.take() {
+            Some(session) => self
+                .ctx
+                .close_session(session)
+                .map_err(|e| Error::Device(format!("Failed to close session: {}", e))),
+            None => Ok(()),
+        }
+    }
+
+    fn session(&self) -> Result<types::CK_SESSION_HANDLE, String> {
+        match self.session {
+            Some(session) => Ok(session),
+            None => Err(String::from("not logged in to the HSM")),
+        }
+    }
+
+    fn open_session(&self) -> Result<types::CK_SESSION_HANDLE, Error> {
+        match self.ctx.open_session(
+            self.slot,
+            types::CKF_SERIAL_SESSION | types::CKF_RW_SESSION,
+            None,
+            None,
+        ) {
+            Ok(session) => Ok(session),
+            Err(e) => Err(Error::Device(format!(
+                "failed to open session with HSM: {}",
+                e
+            ))),
+        }
+    }
+
+    // Sets the new SO and user PINs in a session that's logged in as the SO,
+    // leaving it logged in as whoever generates keys. Returns the new SO PIN.
+    fn set_pins(
+        &self,
+        session: types::CK_SESSION_HANDLE,
+        so_pin: &Secret,
+        prompt: &mut dyn Prompt,
+        journal: &mut Journal,
+    ) -> Result<Secret, Error> {
+        if let Err(e) = self
+            .ctx
+            .login(session, types::CKU_SO, Some(so_pin.expose()))
+        {
+            return Err(pin_error("failed to login as Security Officer", e));
+        }
+
+        // Change the SO PIN to a new one from the user.
+        let new_so_pin =
+            prompt.password(SecretKind::NewSoPin, "Enter your NEW Security Officer PIN")?;
+        self.profile.check_so_pin(new_so_pin.expose())?;
+        if new_so_pin
+            != prompt.password(
+                SecretKind::NewSoPin,
+                "Re-enter your NEW Security Officer PIN",
+            )?
+        {
+            return Err(Error::CredentialMismatch(String::from(
+                "SO PIN does not match!",
+            )));
+        }
+
+        journal.record(Stage::SoPin, || {
+            self.ctx
+                .set_pin(session, Some(so_pin.expose()), Some(new_so_pin.expose()))
+                .map_err(|e| Error::CredentialChange(format!("Failed to set new SO PIN: {}", e)))
+        })?;
+
+        // Re-login so that we can set a new user PIN: as the SO with our new SO
+        // PIN or, since a PIV token's SO can't set the user PIN, as the user
+        // with the default PIN that the reset left.
+        if let Err(e) = self.ctx.logout(session) {
+            return Err(Error::Device(format!(
+                "Failed to cycle SO session (logout): {}",
+                e
+            )));
+        }
+
+        let new_user_pin = prompt.password(SecretKind::NewUserPin, "Enter your NEW user PIN")?;
+        self.profile.check_user_pin(new_user_pin.expose())?;
+        if new_user_pin != prompt.password(SecretKind::NewUserPin, "Re-enter your NEW user PIN")? {
+            return Err(Error::CredentialMismatch(String::from(
+                "User PIN does not match!",
+            )));
+        }
+
+        let login = match self.profile.reset {
+            Reset::InitToken => self
+                .ctx
+                .login(session, types::CKU_SO, Some(new_so_pin.expose())),
+            Reset::PivApplet => self
+                .ctx
+                .login(session, types::CKU_USER, Some(piv::DEFAULT_PIN)),
+        };
+        if let Err(e) = login {
+            return Err(pin_error("Failed to cycle SO session (login)", e));
+        }
+
+        journal.record(Stage::UserPin, || {
+            match self.profile.reset {
+                Reset::InitToken => self.ctx.init_pin(session, Some(new_user_pin.expose())),
+                Reset::PivApplet => {
+                    self.ctx
+                        .set_pin(session, Some(piv::DEFAULT_PIN), Some(new_user_pin.expose()))
+                }
+            }
+            .map_err(|e| Error::CredentialChange(format!("Failed to set new user PIN: {}", e)))
+        })?;
+
+        println!("Success! We've reinitialized the token with a new SO PIN and user PIN.");
+
+        // Swap our login for one that can generate keys.
+        if let Err(e) = self.ctx.logout(session) {
+            return Err(Error::Device(format!(
+                "Failed to cycle session (logout): {}",
+                e
+            )));
+        }
+
+        let pin = match self.profile.generate_as {
+            types::CKU_SO => &new_so_pin,
+            _ => &new_user_pin,
+        };
+        if let Err(e) = self
+            .ctx
+            .login(session, self.profile.generate_as, Some(pin.expose()))
+        {
+            return Err(pin_error("Failed to log in to generate keys", e));
+        }
+
+        Ok(new_so_pin)
+    }
+
+    // Finds the single object of the given class with a role's CKA_ID.
+    fn find_key(
+        &self,
+        session: types::CK_SESSION_HANDLE,
+        class: types::CK_OBJECT_CLASS,
+        role: &Role,
+    ) -> Result<types::CK_OBJECT_HANDLE, String> {
+        let key_id = [role.key_id];
+        let template = vec![
+            types::CK_ATTRIBUTE::new(types::CKA_CLASS).with_ck_ulong(&class),
+            types::CK_ATTRIBUTE::new(types::CKA_ID).with_bytes(&key_id),
+        ];
+
+        let objects = self.find_objects(session, &template)?;
+        match objects.as_slice() {
+            [object] => Ok(*object),
+            [] => Err(format!("no {} key with ID {:02x}", role.label, role.key_id)),
+            _ => Err(format!(
+                "more than one {} key with ID {:02x}",
+                role.label, role.key_id
+            )),
+        }
+    }
+
+    fn find_objects(
+        &self,
+        session: types::CK_SESSION_HANDLE,
+        template: &[types::CK_ATTRIBUTE],
+    ) -> Result<Vec<types::CK_OBJECT_HANDLE>, String> {
+        if let Err(e) = self.ctx.find_objects_init(session, template) {
+            return Err(format!("couldn't search the HSM's objects: {}", e));
+        }
+
+        let mut objects = vec![];
+        let found = loop {
+            match self.ctx.find_objects(session, FIND_OBJECTS_BATCH) {
+                Ok(batch) if batch.is_empty() => break Ok(()),
+                Ok(batch) => objects.extend(batch),
+                Err(e) => break Err(format!("couldn't search the HSM's objects: {}", e)),
+            }
+        };
+
+        // NOTE(ww): The search has to be finished even if it failed, or the
+        // session can't be used for anything else.
+        if let Err(e) = self.ctx.find_objects_final(session) {
+            return Err(format!(
+                "couldn't finish searching the HSM's objects: {}",
+                e
+            ));
+        }
+
+        found.map(|_| objects)
+    }
+
+    // Returns the value of a variable-length attribute, or None if the object
+    // doesn't have it (or won't reveal it).
+    fn attribute(
+        &self,
+        session: types::CK_SESSION_HANDLE,
+        object: types::CK_OBJECT_HANDLE,
+        attribute: types::CK_ATTRIBUTE_TYPE,
+    ) -> Result<Option<Vec<u8>>, String> {
+        // Ask for the attribute's length first, then for its value.
+        let mut template = vec![types::CK_ATTRIBUTE::new(attribute)];
+        match self.ctx.get_attribute_value(session, object, &mut template) {
+            Ok((types::CKR_OK, _)) => {}
+            Ok(_) => return Ok(None),
+            Err(e) => return Err(format!("couldn't get attribute {:#x}: {}", attribute, e)),
+        }
+
+        let value = vec![0; template[0].ulValueLen as usize];
+        template[0].set_bytes(&value);
+        match self.ctx.get_attribute_value(session, object, &mut template) {
+            Ok((types::CKR_OK, template)) => match template[0].get_bytes() {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(format!("couldn't get attribute {:#x}: {}", attribute, e)),
+            },
+            Ok((rv, _)) => Err(format!(
+                "couldn't get attribute {:#x}: CKR {:#x}",
+                attribute, rv
+            )),
+            Err(e) => Err(format!("couldn't get attribute {:#x}: {}", attribute, e)),
+        }
+    }
+
+    fn class(
+        &self,
+        session: types::CK_SESSION_HANDLE,
+        object: types::CK_OBJECT_HANDLE,
+    ) -> Result<types::CK_OBJECT_CLASS, String> {
+        let class: types::CK_OBJECT_CLASS = 0;
+        let mut template = vec![types::CK_ATTRIBUTE::new(types::CKA_CLASS).with_ck_ulong(&class)];
+        match self.ctx.get_attribute_value(session, object, &mut template) {
+            Ok((types::CKR_OK, template)) => template[0]
+                .get_ck_ulong()
+                .map_err(|e| format!("couldn't get object class: {}", e)),
+            Ok((rv, _)) => Err(format!("couldn't get object class: CKR {:#x}", rv)),
+            Err(e) => Err(format!("couldn't get object class: {}", e)),
+        }
+    }
+}
+
+// A name for a PKCS#11 object class, for list_objects.
+fn class_name(class: types::CK_OBJECT_CLASS) -> String {
+    match class {
+        types::CKO_DATA => String::from("data"),
+        types::CKO_CERTIFICATE => String::from("certificate"),
+        types::CKO_PUBLIC_KEY => String::from("public-key"),
+        types::CKO_PRIVATE_KEY => String::from("private-key"),
+        types::CKO_SECRET_KEY => String::from("secret-key"),
+        other => format!("class-{:#x}", other),
+    }
+}
+
+impl Backend for Pkcs11Token {
+    type Config = Config;
+    type Role = Role;
+
+    fn discover(config: &Config) -> Result<Self, Error> {
+        let selection = Selection {
+            serial: config.serial.as_deref(),
+            slot: config.slot,
+        };
+        let (ctx, slot, serial_number) = find_hsm(&config.module, &config.profile, &selection)?;
+
+        Ok(Pkcs11Token::new(
+            ctx,
+            slot,
+            serial_number,
+            config.profile.clone(),
+        ))
+    }
+
+    fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+
+    fn firmware(&mut self) -> Result<String, Error> {
+        match self.ctx.get_token_info(self.slot) {
+            // NOTE: CK_VERSION is packed, so its fields have to be copied out.
+            Ok(token) => {
+                let version = token.firmwareVersion;
+                let (major, minor) = (version.major, version.minor);
+                Ok(format!("{}.{}", major, minor))
+            }
+            Err(e) => Err(Error::Device(format!(
+                "couldn't get info for token with slot #{}: {}",
+                self.slot, e
+            ))),
+        }
+    }
+
+    // Reinitializes the token with its current SO PIN, which wipes every key
+    // on it and leaves the SO PIN as it was. A YubiKey's PIV application is
+    // reset instead, which leaves the default management key as its SO PIN.
+    fn reset(&mut self, prompt: &mut dyn Prompt, journal: &mut Journal) -> Result<(), Error> {
+        confirm(
+            prompt,
+            "Continue with factory reset? This step is IRREVERSIBLE!",
+        )?;
+
+        let token = match self.ctx.get_token_info(self.slot) {
+            Ok(token) => token,
+            Err(e) => {
+                return Err(Error::Device(format!(
+                    "couldn't get info for token with slot #{}: {}",
+                    self.slot, e
+                )))
+            }
+        };
+
+        if self.profile.reset == Reset::PivApplet {
+            // NOTE: The reset blocks the PIN anyway, so only a token that
+            // failed its self-check is refused.
+            if token.flags & types::CKF_ERROR_STATE != 0 {
+                return Err(Error::DeviceLocked(String::from(
+                    "HSM failed its self-check; requires manual intervention",
+                )));
+            }
+
+            self.close_session()?;
+            let serial_number = &self.serial_number;
+            journal.record(Stage::Reset, || piv::reset(serial_number))?;
+            self.so_pin = Some(Secret::from(piv::DEFAULT_MANAGEMENT_KEY));
+
+            println!("Success! Reset the YubiKey's PIV application.");
+            return Ok(());
+        }
+
+        // First, check to see if we're in a dead or deadly state.
+        // Don't attempt to perform any automatic steps if we are.
+        if token_in_deadly_state(&token) {
+            return Err(Error::DeviceLocked(String::from(
+                "HSM is either locked or one step away from locking; requires manual intervention",
+            )));
+        }
+
+        let so_pin = match self.so_pin.take() {
+            Some(so_pin) => so_pin,
+            None => current_so_pin(&self.profile, prompt, false)?,
+        };
+
+        // Next, initialize (or reinitialize) the HSM with the current SO PIN.
+        self.close_session()?;
+        let (ctx, slot, label) = (&self.ctx, self.slot, self.profile.token_label);
+        let result = journal.record(Stage::Reset, || {
+            ctx.init_token(slot, Some(so_pin.expose()), label)
+                .map_err(|e| pin_error("failed to (re)initialize HSM", e))
+        });
+        self.so_pin = Some(so_pin);
+        result?;
+
+        println!("Success! Reinitialized the HSM.");
+        Ok(())
+    }
+
+    // Changes the SO PIN and sets a new user PIN, both from the user, and
+    // leaves a session logged in as the user. PKCS#11 tokens don't restrict
+    // what their user can do with each key, so the roles don't matter here.
+    fn set_credentials(
+        &mut self,
+        _roles: &[Role],
+        prompt: &mut dyn Prompt,
+        journal: &mut Journal,
+    ) -> Result<(), Error> {
+        let so_pin = match self.so_pin.take() {
+            Some(so_pin) => so_pin,
+            None => current_so_pin(&self.profile, prompt, false)?,
+        };
+
+        let session = self.open_session()?;
+        match self.set_pins(session, &so_pin, prompt, journal) {
+            Ok(new_so_pin) => {
+                self.so_pin = Some(new_so_pin);
+                self.session = Some(session);
+                Ok(())
+            }
+            Err(e) => {
+                self.so_pin = Some(so_pin);
+                self.ctx
+                    .close_session(session)
+                    .unwrap_or_else(|e| eprintln!("Error while closing session: {}", e));
+                Err(e)
+            }
+        }
+    }
+
+    fn generate_key(&mut self, role: &Role) -> Result<(), Error> {
+        let session = self.session()?;
+        new_ec_keypair(&self.ctx, session, role.key_type, &role.label, role.key_id)
+    }
+
+    fn public_key(&mut self, role: &Role) -> Result<Vec<u8>, Error> {
+        let session = self.session()?;
+        let public_key = self
+            .find_key(session, types::CKO_PUBLIC_KEY, role)
+            .map_err(Error::KeyGeneration)?;
+
+        match self.attribute(session, public_key, types::CKA_EC_POINT)? {
+            Some(ec_point) => pubkey::ec_point_from_attribute(role.key_type, &ec_point)
+                .map_err(Error::KeyGeneration),
+            None => Err(Error::KeyGeneration(format!(
+                "couldn't get {} public key",
+                role.label
+            ))),
+        }
+    }
+
+    // PKCS#11 has no standard way for a token to attest to its keys.
+    fn attest(&mut self, _role: &Role) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    fn list_objects(&mut self) -> Result<Vec<Object>, Error> {
+        let session = self.session()?;
+
+        let mut objects = vec![];
+        for object in self.find_objects(session, &[])? {
+            let id = self
+                .attribute(session, object, types::CKA_ID)?
+                .unwrap_or_default();
+            let label = self
+                .attribute(session, object, types::CKA_LABEL)?
+                .unwrap_or_default();
+
+            objects.push(Object {
+                id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+                kind: class_name(self.class(session, object)?),
+                label: String::from_utf8_lossy(&label).into_owned(),
+            });
+        }
+
+        Ok(objects)
+    }
+}
+
+// Generates an EC keypair on the token with the given label and ID. The session
+// must be logged in as the user.
+pub fn new_ec_keypair(
+    pkcs11_ctx: &Ctx,
+    session: types::CK_SESSION_HANDLE,
+    key_type: KeyType,
+    label: &str,
+    key_id: u8,
+) -> Result<(), Error> {
+    let mechanism = types::CK_MECHANISM {
+        mechanism: types::CKM_EC_KEY_PAIR_GEN,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    };
+
+    let key_id = [key_id];
+    let public_class = types::CKO_PUBLIC_KEY;
+    let private_class = types::CKO_PRIVATE_KEY;
+    let ec_key_type = types::CKK_EC;
+
+    let public_template = vec![
+        types::CK_ATTRIBUTE::new(types::CKA_CLASS).with_ck_ulong(&public_class),
+        types::CK_ATTRIBUTE::new(types::CKA_KEY_TYPE).with_ck_ulong(&ec_key_type),
+        types::CK_ATTRIBUTE::new(types::CKA_TOKEN).with_bool(&types::CK_TRUE),
+        types::CK_ATTRIBUTE::new(types::CKA_VERIFY).with_bool(&types::CK_TRUE),
+        types::CK_ATTRIBUTE::new(types::CKA_EC_PARAMS).with_bytes(key_type.ec_params()),
+        types::CK_ATTRIBUTE::new(types::CKA_ID).with_bytes(&key_id),
+        types::CK_ATTRIBUTE::new(types::CKA_LABEL).with_string(label),
+    ];
+
+    // NOTE(ww): The private half must never leave the HSM.
+    let private_template = vec![
+        types::CK_ATTRIBUTE::new(types::CKA_CLASS).with_ck_ulong(&private_class),
+        types::CK_ATTRIBUTE::new(types::CKA_KEY_TYPE).with_ck_ulong(&ec_key_type),
+        types::CK_ATTRIBUTE::new(types::CKA_TOKEN).with_bool(&types::CK_TRUE),
+        types::CK_ATTRIBUTE::new(types::CKA_PRIVATE).with_bool(&types::CK_TRUE),
+        types::CK_ATTRIBUTE::new(types::CKA_SENSITIVE).with_bool(&types::CK_TRUE),
+        types::CK_ATTRIBUTE::new(types::CKA_EXTRACTABLE).with_bool(&types::CK_FALSE),
+        types::CK_ATTRIBUTE::new(types::CKA_SIGN).with_bool(&types::CK_TRUE),
+        types::CK_ATTRIBUTE::new(types::CKA_ID).with_bytes(&key_id),
+        types::CK_ATTRIBUTE::new(types::CKA_LABEL).with_string(label),
+    ];
+
+    match pkcs11_ctx.generate_key_pair(session, &mechanism, &public_template, &private_template) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::KeyGeneration(format!(
+            "couldn't generate {} keypair: {}",
+            label, e
+        ))),
+    }
+}
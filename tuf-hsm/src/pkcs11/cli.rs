@@ -0,0 +1,396 @@
+// The command line for provisioning PKCS#11 tokens, as run by
+// nitrohsm-provision and `tuf-hsm provision --vendor nitrokey`.
+
+use clap::{App, AppSettings, Arg, ArgMatches};
+
+use std::path::{Path, PathBuf};
+
+use super::backend::{Config, Pkcs11Token};
+use super::profile::Profile;
+use super::token;
+use super::{
+    find_pkcs11_module, load_module, plan, role, tokens, KeyType, Options, BIG_SCARY_BANNER,
+};
+use crate::backend::Backend;
+use crate::error::Error;
+use crate::prompt::{self, Prompt, SecretKind, Terminal, Unattended};
+use crate::secret::Secret;
+use crate::{airgap, big_scary_banner, cli, Vendor, CEREMONY_PRODUCTS_DIR};
+
+const SO_PIN_ARGV_WARNING: &str = r#"
+#####################################################
+###                   WARNING!                    ###
+###                                               ###
+###   --so-pin is DEPRECATED. It leaves the SO    ###
+###   PIN in your shell history and in the        ###
+###   process list, where anyone can see it.      ###
+###                                               ###
+###   Leave it out to be prompted for the SO      ###
+###   PIN instead.                                ###
+###                                               ###
+#####################################################
+"#;
+
+fn module_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("module")
+        .help("the PKCS#11 module to use (default: search for OpenSC's)")
+        .short("m")
+        .long("module")
+        .multiple(false)
+        .takes_value(true)
+}
+
+fn is_valid_slot(val: String) -> Result<(), String> {
+    match val.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("invalid slot ID (expected a number): {}", val)),
+    }
+}
+
+// Provisioning, with its arguments but without any subcommands.
+pub fn provision_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .about("Resets a Nitrokey HSM (or another PKCS#11 token) and generates its TUF keys")
+        .arg(
+            Arg::with_name("so-pin")
+                .help(
+                    "DEPRECATED: the current Security Officer PIN, which ends up in shell \
+                     history and the process list (default: prompt for it)",
+                )
+                .short("p")
+                .long("so-pin")
+                .multiple(false)
+                .takes_value(true)
+                .conflicts_with("current-so-pin-fd"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help(
+                    "the kind of token to provision, which decides the manufacturer, PINs, \
+                     key types and key IDs that it'll take (default: nitrokey)",
+                )
+                .long("profile")
+                .multiple(false)
+                .takes_value(true)
+                .possible_values(Profile::NAMES)
+                .conflicts_with("plan"),
+        )
+        .arg(
+            Arg::with_name("type")
+                .help("the type of key to generate")
+                .short("t")
+                .long("type")
+                .multiple(false)
+                .takes_value(true)
+                .possible_values(KeyType::NAMES)
+                .required_unless("plan")
+                .conflicts_with("plan"),
+        )
+        .arg(
+            Arg::with_name("role")
+                .help(
+                    "a role to generate a key for, as NAME:ID:LABEL[:TYPE] with a hex ID \
+                     (default: root:12:root and targets:13:targets)",
+                )
+                .long("role")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .conflicts_with("plan"),
+        )
+        .arg(
+            Arg::with_name("plan")
+                .help("a TOML ceremony plan to take the key type and roles from")
+                .long("plan")
+                .multiple(false)
+                .takes_value(true)
+                .requires("body"),
+        )
+        .arg(
+            Arg::with_name("body")
+                .help("the signing body ID in the ceremony plan, e.g. \"Nitrokey HSM-4\"")
+                .long("body")
+                .multiple(false)
+                .takes_value(true)
+                .requires("plan"),
+        )
+        .arg(cli::allow_online_arg())
+        .arg(module_arg())
+        .arg(cli::resume_arg())
+        .arg(
+            Arg::with_name("serial")
+                .help("the serial number of the token to provision, if several are attached")
+                .long("serial")
+                .multiple(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slot")
+                .help("the slot ID of the token to provision, if several are attached")
+                .long("slot")
+                .multiple(false)
+                .takes_value(true)
+                .validator(is_valid_slot),
+        )
+        .arg(
+            Arg::with_name("current-so-pin-fd")
+                .help(
+                    "read the current Security Officer PIN from this file descriptor \
+                     (e.g. 5, with 5<named-pipe) instead of prompting",
+                )
+                .long("current-so-pin-fd")
+                .multiple(false)
+                .takes_value(true)
+                .validator(prompt::is_valid_fd),
+        )
+        .arg(
+            Arg::with_name("so-pin-fd")
+                .help(
+                    "read the NEW Security Officer PIN from this file descriptor \
+                     (e.g. 3, with 3<named-pipe) instead of prompting",
+                )
+                .long("so-pin-fd")
+                .multiple(false)
+                .takes_value(true)
+                .validator(prompt::is_valid_fd),
+        )
+        .arg(
+            Arg::with_name("user-pin-fd")
+                .help(
+                    "read the NEW user PIN from this file descriptor \
+                     (e.g. 4, with 4<named-pipe) instead of prompting",
+                )
+                .long("user-pin-fd")
+                .multiple(false)
+                .takes_value(true)
+                .validator(prompt::is_valid_fd),
+        )
+        .arg(cli::runbook_arg())
+        .arg(
+            Arg::with_name("force-unattended")
+                .help(
+                    "allow --so-pin-fd, --user-pin-fd and --i-have-read-the-runbook \
+                     with a terminal on stdin",
+                )
+                .long("force-unattended")
+                .multiple(false),
+        )
+}
+
+pub fn doctor_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    cli::doctor_app(name).arg(module_arg())
+}
+
+pub fn list_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .about("lists every token's slot, manufacturer, model, serial number and flags")
+        .arg(module_arg())
+}
+
+// Provisioning, plus the doctor, list-tokens and verify-manifest subcommands.
+pub fn app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    provision_app(name)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(doctor_app("doctor"))
+        .subcommand(list_app("list-tokens"))
+        .subcommand(cli::verify_manifest_app("verify-manifest"))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Error> {
+    if let Some(matches) = matches.subcommand_matches("doctor") {
+        return doctor(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("list-tokens") {
+        return list(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify-manifest") {
+        return cli::verify_manifest(matches);
+    }
+
+    provision(matches)
+}
+
+pub fn doctor(matches: &ArgMatches) -> Result<(), Error> {
+    let module = find_pkcs11_module(matches.value_of("module").map(Path::new));
+    cli::doctor(matches, Vendor::Nitrokey, Some(module))
+}
+
+pub fn list(matches: &ArgMatches) -> Result<(), Error> {
+    let ctx = load_module(&find_pkcs11_module(
+        matches.value_of("module").map(Path::new),
+    )?)?;
+
+    let tokens = tokens(&ctx)?;
+    if tokens.is_empty() {
+        return Err(Error::DeviceNotFound(String::from("no tokens detected")));
+    }
+
+    for (slot, token) in &tokens {
+        println!("{}", token::describe(*slot, token));
+    }
+
+    Ok(())
+}
+
+// Builds the prompt: the Terminal, unless secrets or answers were supplied up
+// front for an unattended rehearsal.
+fn prompt(matches: &ArgMatches) -> Result<Box<dyn Prompt>, String> {
+    let confirm_all = matches.is_present("i-have-read-the-runbook");
+    let current_so_pin_fd = matches.value_of("current-so-pin-fd");
+    let so_pin_fd = matches.value_of("so-pin-fd");
+    let user_pin_fd = matches.value_of("user-pin-fd");
+    if !confirm_all && current_so_pin_fd.is_none() && so_pin_fd.is_none() && user_pin_fd.is_none() {
+        return Ok(Box::new(Terminal));
+    }
+
+    if prompt::stdin_is_tty() && !matches.is_present("force-unattended") {
+        return Err(String::from(
+            "refusing to run unattended with a terminal on stdin; drop --current-so-pin-fd, \
+             --so-pin-fd, --user-pin-fd and --i-have-read-the-runbook, or pass --force-unattended",
+        ));
+    }
+
+    // NOTE: These unwraps are safe, since clap has already validated the descriptors.
+    // Each descriptor is closed once it's read, so it can't be given twice.
+    let mut fds: Vec<u32> = [current_so_pin_fd, so_pin_fd, user_pin_fd]
+        .iter()
+        .flatten()
+        .map(|fd| fd.parse().unwrap())
+        .collect();
+    let given = fds.len();
+    fds.sort_unstable();
+    fds.dedup();
+    if fds.len() != given {
+        return Err(String::from(
+            "--current-so-pin-fd, --so-pin-fd and --user-pin-fd must be different file descriptors",
+        ));
+    }
+
+    let mut unattended = Unattended::new(confirm_all);
+    if let Some(fd) = current_so_pin_fd {
+        unattended = unattended.with_secret(
+            SecretKind::CurrentSoPin,
+            prompt::read_secret_fd(fd.parse().unwrap())?,
+        );
+    }
+    if let Some(fd) = so_pin_fd {
+        unattended = unattended.with_secret(
+            SecretKind::NewSoPin,
+            prompt::read_secret_fd(fd.parse().unwrap())?,
+        );
+    }
+    if let Some(fd) = user_pin_fd {
+        unattended = unattended.with_secret(
+            SecretKind::NewUserPin,
+            prompt::read_secret_fd(fd.parse().unwrap())?,
+        );
+    }
+
+    Ok(Box::new(unattended))
+}
+
+pub fn provision(matches: &ArgMatches) -> Result<(), Error> {
+    // NOTE: Any secret file descriptors have to be read before anything else
+    // opens a file; see prompt::read_secret_fd.
+    let mut prompt = prompt(matches)?;
+
+    let argv_so_pin = matches.value_of("so-pin");
+    if argv_so_pin.is_some() {
+        eprintln!("{}", SO_PIN_ARGV_WARNING);
+    }
+
+    let body = match (matches.value_of("plan"), matches.value_of("body")) {
+        (Some(path), Some(body_id)) => {
+            let body = plan::load_body(Path::new(path), body_id).map_err(Error::Plan)?;
+            println!(
+                "Following the plan for {}: a {} token with serial number {}",
+                body.id, body.profile.name, body.serial
+            );
+            Some(body)
+        }
+        _ => None,
+    };
+
+    // Refuse to go anywhere near the HSM on a machine that looks online.
+    let airgap_override = airgap::preflight(matches.value_of("allow-online"))?;
+
+    // NOTE: clap 2 treats a default_value as given, which trips
+    // conflicts_with("plan"), so --profile's default lives here.
+    // This unwrap is safe, since clap has already checked --profile against Profile::NAMES.
+    let profile = match &body {
+        Some(body) => body.profile.clone(),
+        None => Profile::from_name(matches.value_of("profile").unwrap_or("nitrokey")).unwrap(),
+    };
+    if let Some(so_pin) = argv_so_pin {
+        profile.check_so_pin(so_pin)?;
+    }
+
+    let options = match body {
+        Some(ref body) => Options {
+            roles: body.roles.clone(),
+            products_dir: body.products_dir.clone(),
+            resume: matches.is_present("resume"),
+            airgap_override,
+        },
+        None => {
+            // NOTE: clap has already checked this against KeyType::NAMES.
+            let key_type = KeyType::from_name(matches.value_of("type").unwrap()).unwrap();
+            let roles = match matches.values_of("role") {
+                Some(specs) => specs
+                    .map(|spec| role::parse(spec, key_type))
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => role::default_roles(key_type),
+            };
+            role::check_roles(&roles)?;
+            profile.check_roles(&roles)?;
+
+            Options {
+                roles,
+                products_dir: PathBuf::from(CEREMONY_PRODUCTS_DIR),
+                resume: matches.is_present("resume"),
+                airgap_override,
+            }
+        }
+    };
+
+    let module = find_pkcs11_module(matches.value_of("module").map(Path::new))?;
+
+    big_scary_banner(&mut *prompt, BIG_SCARY_BANNER)?;
+
+    // NOTE: This unwrap is safe, since clap has already validated --slot.
+    let mut token = Pkcs11Token::discover(&Config {
+        module,
+        profile,
+        serial: matches.value_of("serial").map(String::from),
+        slot: matches.value_of("slot").map(|slot| slot.parse().unwrap()),
+    })?;
+    let serial_number = token.serial_number().to_string();
+
+    // Refuse to touch an HSM that isn't the one that the plan is for.
+    if let Some(body) = &body {
+        body.check_serial(&serial_number).map_err(Error::Plan)?;
+    }
+
+    // NOTE(ww): This copy gets wiped, but the one in argv doesn't.
+    // Without --so-pin, provision asks for it once it's read the journal.
+    if let Some(so_pin) = argv_so_pin {
+        token.set_so_pin(Secret::from(so_pin));
+    }
+
+    // Ensure that the Nitrokey is in an acceptable state and generate our keys. This includes:
+    //  1. Reinitializing the HSM using the current SO PIN.
+    //  2. Setting a new SO PIN.
+    //  3. Creating the normal user account and PIN.
+    //  4. Generating a keypair for each TUF role as that user.
+    super::provision(&mut token, &mut *prompt, &options)?;
+
+    println!(
+        "Wrote public keys to {}",
+        options.products_dir.join(&serial_number).display()
+    );
+    println!("This HSM's serial number is: {}", serial_number);
+
+    Ok(())
+}
@@ -0,0 +1,573 @@
+use pkcs11::{types, Ctx};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub mod backend;
+pub mod cli;
+pub mod piv;
+pub mod plan;
+pub mod profile;
+pub mod pubkey;
+pub mod role;
+pub mod token;
+
+use crate::backend::Backend;
+use crate::error::Error;
+use crate::journal::{self, Journal, Recovery, Stage};
+use crate::manifest;
+use crate::prompt::{Prompt, SecretKind};
+use crate::secret::Secret;
+use crate::transcript::{self, Event, Transcript};
+use crate::{airgap, confirm};
+use backend::Pkcs11Token;
+use profile::{Profile, Reset};
+use role::Role;
+use token::Selection;
+
+// The key IDs of the default TUF keypairs. See role::default_roles.
+// NOTE(ww): These are contrived. They're high enough to be above any default
+// object IDs that might be baked into the Nitrokey HSM, and match the IDs that
+// `pkcs11-tool --id 12` and `--id 13` produced in earlier ceremonies.
+pub const TUF_ROOT_KEY_ID: u8 = 0x12;
+pub const TUF_TARGETS_KEY_ID: u8 = 0x13;
+
+pub const BIG_SCARY_BANNER: &str = r#"
+!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
+!!!                    DANGER!                    !!!
+!!!                                               !!!
+!!!   This program will reset and reprovision     !!!
+!!!   your Nitrokey HSM for TUF purposes.         !!!
+!!!                                               !!!
+!!!   Make sure to read the runbook before        !!!
+!!!   using this program. Failure to do so        !!!
+!!!   will cause PERMANENT key loss and MAY       !!!
+!!!   leave your HSM in an unusable state.        !!!
+!!!                                               !!!
+!!!   Hit "y" (case insensitive) to continue.     !!!
+!!!                                               !!!
+!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
+"#;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+    P256,
+    P384,
+}
+
+impl KeyType {
+    // The names accepted by --type.
+    pub const NAMES: &'static [&'static str] = &["p256", "p384"];
+
+    pub fn from_name(name: &str) -> Option<KeyType> {
+        match name {
+            "p256" => Some(KeyType::P256),
+            "p384" => Some(KeyType::P384),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyType::P256 => "p256",
+            KeyType::P384 => "p384",
+        }
+    }
+}
+
+pub struct Options {
+    // The roles to generate keys for; usually role::default_roles.
+    pub roles: Vec<Role>,
+
+    // The parent directory for ceremony products; usually CEREMONY_PRODUCTS_DIR.
+    pub products_dir: PathBuf,
+
+    // Whether to pick up a run that the journal says was interrupted.
+    pub resume: bool,
+
+    // The operator's override of a failed air-gap preflight, if any, to be
+    // recorded with the products.
+    pub airgap_override: Option<airgap::Override>,
+}
+
+// The environment variable that, when set, overrides the PKCS#11 module search
+// (but not an explicit --module).
+pub const PKCS11_MODULE_ENV: &str = "NITROHSM_PKCS11_MODULE";
+
+// Well-known locations for the OpenSC PKCS#11 module, in the order that we
+// search them. Raspbian comes first, since that's what the ceremony runs on.
+pub const OPENSC_PKCS11_SO_PATHS: &[&str] = &[
+    // Debian, Ubuntu, and Raspbian (multiarch)
+    "/usr/lib/arm-linux-gnueabihf/opensc-pkcs11.so",
+    "/usr/lib/aarch64-linux-gnu/opensc-pkcs11.so",
+    "/usr/lib/x86_64-linux-gnu/opensc-pkcs11.so",
+    "/usr/lib/i386-linux-gnu/opensc-pkcs11.so",
+    // Fedora, RHEL, and openSUSE
+    "/usr/lib64/opensc-pkcs11.so",
+    "/usr/lib64/pkcs11/opensc-pkcs11.so",
+    // Arch, Alpine, and other non-multiarch distributions
+    "/usr/lib/opensc-pkcs11.so",
+    "/usr/lib/pkcs11/opensc-pkcs11.so",
+    // macOS (Homebrew on Intel, Homebrew on Apple Silicon, OpenSC installer)
+    "/usr/local/lib/opensc-pkcs11.so",
+    "/opt/homebrew/lib/opensc-pkcs11.so",
+    "/Library/OpenSC/lib/opensc-pkcs11.so",
+];
+
+// Picks the PKCS#11 module to load: an explicitly supplied module if there is
+// one, then the module named by PKCS11_MODULE_ENV, and finally the first
+// OpenSC module found in OPENSC_PKCS11_SO_PATHS.
+pub fn find_pkcs11_module(module: Option<&Path>) -> Result<PathBuf, String> {
+    if let Some(module) = module {
+        return match module.is_file() {
+            true => Ok(module.into()),
+            false => Err(format!(
+                "No PKCS#11 module at {} (from --module)",
+                module.display()
+            )),
+        };
+    }
+
+    if let Some(module) = env::var_os(PKCS11_MODULE_ENV) {
+        let module = PathBuf::from(module);
+        return match module.is_file() {
+            true => Ok(module),
+            false => Err(format!(
+                "No PKCS#11 module at {} (from ${})",
+                module.display(),
+                PKCS11_MODULE_ENV
+            )),
+        };
+    }
+
+    match OPENSC_PKCS11_SO_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.is_file())
+    {
+        Some(module) => Ok(module.into()),
+        None => Err(format!(
+            "No OpenSC PKCS#11 shared object found; pass --module or set ${}. Tried:\n  {}",
+            PKCS11_MODULE_ENV,
+            OPENSC_PKCS11_SO_PATHS.join("\n  ")
+        )),
+    }
+}
+
+// How many times the current SO PIN may be entered malformed before we give up.
+pub const SO_PIN_ATTEMPTS: usize = 3;
+
+// Prompts for the token's current SO PIN, asking again if what's entered
+// doesn't even look like one. Nothing reaches the token here, so a typo is
+// free; a well-formed but wrong PIN still costs one of the token's retries.
+// `changed` says that an interrupted run already replaced the SO PIN, so the
+// current one is the new one that it set.
+pub fn current_so_pin(
+    profile: &Profile,
+    prompt: &mut dyn Prompt,
+    changed: bool,
+) -> Result<Secret, String> {
+    let text = match changed {
+        true => {
+            "Enter your CURRENT Security Officer PIN (the NEW one that the interrupted run set)"
+        }
+        false => "Enter your CURRENT Security Officer PIN",
+    };
+
+    for _ in 0..SO_PIN_ATTEMPTS {
+        let so_pin = prompt.password(SecretKind::CurrentSoPin, text)?;
+        match profile.check_so_pin(so_pin.expose()) {
+            Ok(()) => return Ok(so_pin),
+            Err(e) => eprintln!("That isn't an SO PIN: {}; try again.", e),
+        }
+    }
+
+    Err(String::from("too many malformed SO PINs; aborting"))
+}
+
+// Loads and initializes the PKCS#11 module at `pkcs11_so_path`.
+pub fn load_module(pkcs11_so_path: &Path) -> Result<Ctx, String> {
+    if !pkcs11_so_path.exists() {
+        return Err(format!(
+            "No PKCS#11 shared object: {}",
+            pkcs11_so_path.display()
+        ));
+    }
+
+    // Open up our PKCS#11 context, using the (usually OpenSC) PKCS#11 shared object.
+    // A failure here indicates something fundamentally wrong with either
+    // the shared object or these bindings and *NOT* the HSM itself.
+    match Ctx::new_and_initialize(pkcs11_so_path) {
+        Ok(ctx) => Ok(ctx),
+        Err(e) => Err(format!(
+            "Couldn't load and initialize the PKCS#11 interface at {}: {}",
+            pkcs11_so_path.display(),
+            e
+        )),
+    }
+}
+
+// Returns the slot ID and token info of every slot with a token present.
+pub fn tokens(ctx: &Ctx) -> Result<Vec<(types::CK_SLOT_ID, types::CK_TOKEN_INFO)>, String> {
+    let slots = match ctx.get_slot_list(true) {
+        Ok(slots) => slots,
+        Err(e) => return Err(format!("Couldn't get slot list: {}", e)),
+    };
+
+    let mut tokens = vec![];
+    for slot in slots {
+        match ctx.get_token_info(slot) {
+            Ok(token) => tokens.push((slot, token)),
+            Err(e) => {
+                return Err(format!(
+                    "couldn't get info for token with slot #{}: {}",
+                    slot, e
+                ))
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+pub fn find_hsm(
+    pkcs11_so_path: &Path,
+    profile: &Profile,
+    selection: &Selection,
+) -> Result<(Ctx, types::CK_SLOT_ID, String), Error> {
+    let ctx = load_module(pkcs11_so_path)?;
+
+    // Grab the list of available tokens. A token can have more than
+    // one slot, but (experimentally) the Nitrokey HSM only has one.
+    let tokens: Vec<(types::CK_SLOT_ID, String)> = tokens(&ctx)?
+        .into_iter()
+        .filter(|(_, token)| {
+            !profile.ignore_uninitialized || token.flags & types::CKF_TOKEN_INITIALIZED != 0
+        })
+        .map(|(slot, token)| (slot, String::from(token.serialNumber)))
+        .collect();
+
+    // Sanity checks: we expect to be run with exactly one HSM plugged in (or
+    // one picked with --serial or --slot), so anything else is a user error.
+    let slot = token::select_slot(&tokens, selection)?;
+
+    // Sanity-check the token that's backing our single slot.
+    // Don't allow an HSM from the wrong manufacturer to progress beyond this point.
+    let manufacturer_id = match ctx.get_slot_info(slot) {
+        Ok(slot_info) => String::from(slot_info.manufacturerID),
+        Err(e) => {
+            return Err(Error::Device(format!(
+                "unable to get token info for slot #{}: {}",
+                slot, e
+            )))
+        }
+    };
+
+    if !profile.manufacturer.matches(&manufacturer_id) {
+        return Err(Error::DeviceNotFound(format!(
+            "unknown HSM: {}",
+            manufacturer_id
+        )));
+    }
+
+    // Finally, grab our HSM's serial number, so that we can write
+    // unique files to disk.
+    let serial_number = match ctx.get_token_info(slot) {
+        Ok(token) => String::from(token.serialNumber),
+        Err(e) => {
+            return Err(Error::Device(format!(
+                "couldn't get info for token with slot #{}: {}",
+                slot, e
+            )))
+        }
+    };
+
+    println!(
+        "Successfully discovered a {} HSM with Slot #{}",
+        manufacturer_id.trim_end(),
+        slot
+    );
+
+    Ok((ctx, slot, serial_number))
+}
+
+pub fn token_in_deadly_state(token: &types::CK_TOKEN_INFO) -> bool {
+    // Our HSM is said to be in a "deadly" state if it is either
+    // one PIN attempt away from locking out a user, has already
+    // locked out a user, or failed its own self-check.
+    (token.flags
+        & (types::CKF_USER_PIN_FINAL_TRY
+            | types::CKF_USER_PIN_LOCKED
+            | types::CKF_SO_PIN_FINAL_TRY
+            | types::CKF_SO_PIN_LOCKED
+            | types::CKF_ERROR_STATE))
+        >= 1
+}
+
+fn file_presence_checks(
+    output_dir: &Path,
+    serial_number: &str,
+    roles: &[Role],
+) -> Result<(), Error> {
+    for role in roles {
+        for suffix in &[role::PUBKEY_FILE_SUFFIX, role::PEM_FILE_SUFFIX] {
+            let filename =
+                output_dir.join(format!("{}_{}", serial_number, role.file_suffix(suffix)));
+            if filename.exists() {
+                return Err(Error::OutputExists(format!(
+                    "Public key file already exists: {:?}; aborting",
+                    filename
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Removes whatever products an interrupted run left behind. Only call this
+// once the token has been reinitialized, since that's what makes them stale.
+fn remove_stale_products(
+    output_dir: &Path,
+    serial_number: &str,
+    roles: &[Role],
+) -> Result<(), Error> {
+    for role in roles {
+        for suffix in &[role::PUBKEY_FILE_SUFFIX, role::PEM_FILE_SUFFIX] {
+            let filename =
+                output_dir.join(format!("{}_{}", serial_number, role.file_suffix(suffix)));
+            if !filename.exists() {
+                continue;
+            }
+
+            println!(
+                "Removing {:?}, left over from the interrupted run",
+                filename
+            );
+            if let Err(e) = fs::remove_file(&filename) {
+                return Err(Error::Io(format!("couldn't remove {:?}: {}", filename, e)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Generates a keypair for each role on a token that's already been logged
+// into as the user, returning the files to write (suffix and contents).
+fn generate_tuf_keys(
+    token: &mut Pkcs11Token,
+    roles: &[Role],
+    journal: &mut Journal,
+    transcript: &mut Transcript,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut products = vec![];
+
+    for role in roles {
+        println!("Performing {} key generation", role.name);
+        let point = journal.record(Stage::Keygen(role.name.clone()), || {
+            token.generate_key(role)?;
+            token.public_key(role)
+        })?;
+        transcript.record(&Event::KeyGenerated {
+            role: role.name.clone(),
+            object_id: format!("{:02x}", role.key_id),
+            algorithm: role.key_type.name().into(),
+        })?;
+        let der = pubkey::spki_der(role.key_type, &point);
+        let pem = pubkey::spki_pem(&der);
+
+        products.push((role.file_suffix(role::PUBKEY_FILE_SUFFIX), der));
+        products.push((role.file_suffix(role::PEM_FILE_SUFFIX), pem.into_bytes()));
+    }
+
+    Ok(products)
+}
+
+// Writes the manifest of everything that the run left in output_dir.
+fn write_manifest(token: &mut Pkcs11Token, output_dir: &Path, roles: &[Role]) -> Result<(), Error> {
+    let device = manifest::Device {
+        vendor: token.profile().name.into(),
+        serial_number: token.serial_number().to_string(),
+        firmware: token.firmware()?,
+    };
+    let roles = roles
+        .iter()
+        .map(|role| manifest::Role {
+            name: role.name.clone(),
+            object_id: format!("{:02x}", role.key_id),
+            label: role.label.clone(),
+            key_type: role.key_type.name().into(),
+        })
+        .collect();
+
+    manifest::write(output_dir, device, roles).map(|_| ())
+}
+
+// Decides, from the journal left by earlier runs, whether this run can go
+// ahead. Without --resume, an earlier run that died anywhere (even before
+// its first stage) is an error; with it, there has to be something to
+// resume, and the SO PIN can't be in doubt.
+fn check_recovery(
+    recovery: Option<&Recovery>,
+    resuming: bool,
+    journal_path: &Path,
+) -> Result<(), Error> {
+    match (recovery, resuming) {
+        (None, true) => Err(Error::Journal(format!(
+            "there's no journal at {:?}, so there's nothing to resume",
+            journal_path
+        ))),
+        (Some(recovery), true) if recovery.finished() => Err(Error::Journal(format!(
+            "the journal at {:?} shows that this HSM was already provisioned; nothing to resume",
+            journal_path
+        ))),
+        (Some(recovery), true) if recovery.interrupted_stage() == Some(&Stage::SoPin) => {
+            Err(Error::ManualIntervention(format!(
+                "the journal at {:?} shows that an earlier run was interrupted while changing \
+                 the SO PIN, so the HSM may have either the old or the new one; \
+                 manual intervention required",
+                journal_path
+            )))
+        }
+        (Some(recovery), false) if !recovery.interrupted.is_empty() => {
+            Err(Error::Journal(format!(
+                "the journal at {:?} shows that an earlier run was interrupted during {}; \
+             rerun with --resume",
+                journal_path,
+                recovery.interrupted_stage().unwrap()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Performs the whole ceremony for one token: factory reset, new PINs, and
+// TUF key generation, all in the same session. The public keys (and a journal
+// of the stages) are written to {products_dir}/{serial_number}/.
+//
+// When resuming, the token is simply reinitialized again: that wipes whatever
+// keys the interrupted run generated, so its products are removed and
+// everything is redone.
+pub fn provision(
+    token: &mut Pkcs11Token,
+    prompt: &mut dyn Prompt,
+    options: &Options,
+) -> Result<(), Error> {
+    let serial_number = token.serial_number().to_string();
+
+    // Refuse bad or colliding roles before anything is generated.
+    role::check_roles(&options.roles)?;
+    token.profile().check_roles(&options.roles)?;
+
+    let output_dir = options.products_dir.join(&serial_number);
+    if let Err(e) = fs::create_dir_all(&output_dir) {
+        return Err(Error::Io(format!(
+            "couldn't create output directory: {}",
+            e
+        )));
+    }
+
+    let journal_path = journal::path(&output_dir, &serial_number);
+    let recovery = journal::read(&journal_path)?;
+    check_recovery(recovery.as_ref(), options.resume, &journal_path)?;
+
+    // Refuse to do anything destructive if this token's products already exist.
+    if !options.resume {
+        file_presence_checks(&output_dir, &serial_number, &options.roles)?;
+    }
+
+    // NOTE(ww): Getting the SO PIN wrong burns one of its few retries, so
+    // make sure that we ask for (or were given) the one that the token has.
+    let so_pin_changed = options.resume
+        && recovery
+            .as_ref()
+            .is_some_and(|recovery| recovery.completed(&Stage::SoPin));
+    // A PIV reset takes no SO PIN, so there's nothing to ask for.
+    match (token.profile().reset, token.has_so_pin(), so_pin_changed) {
+        (Reset::PivApplet, _, _) => {}
+        (Reset::InitToken, false, changed) => {
+            let so_pin = current_so_pin(token.profile(), prompt, changed)?;
+            token.set_so_pin(so_pin);
+        }
+        (Reset::InitToken, true, true) => confirm(
+            prompt,
+            "An earlier run already changed the SO PIN, so the current SO PIN is the NEW one \
+             that it set. Is that the one you gave?",
+        )?,
+        (Reset::InitToken, true, false) => {}
+    }
+
+    if let Some(airgap_override) = &options.airgap_override {
+        airgap::record_override(&output_dir, &serial_number, airgap_override)?;
+    }
+
+    let mut journal = Journal::open(&journal_path)?;
+    let mut transcript = Transcript::open(&transcript::path(&output_dir, &serial_number))?;
+    transcript.record(&Event::DeviceDiscovered {
+        vendor: token.profile().name.into(),
+        serial_number: serial_number.clone(),
+    })?;
+
+    journal.begin(&Stage::Provision)?;
+
+    token.reset(prompt, &mut journal)?;
+    transcript.record(&Event::ResetConfirmed)?;
+
+    token.set_credentials(&options.roles, prompt, &mut journal)?;
+    for credential in &["so-pin", "user-pin"] {
+        transcript.record(&Event::CredentialChanged {
+            credential: credential.to_string(),
+            object_id: None,
+        })?;
+    }
+
+    if options.resume {
+        remove_stale_products(&output_dir, &serial_number, &options.roles)?;
+    }
+
+    let products = generate_tuf_keys(token, &options.roles, &mut journal, &mut transcript)
+        .and_then(|products| {
+            crate::backend::show_objects(token)?;
+            Ok(products)
+        });
+
+    if let Err(e) = token.close_session() {
+        match products {
+            Ok(_) => return Err(e),
+            Err(_) => eprintln!("Error while closing session: {}", e),
+        }
+    }
+
+    for (suffix, contents) in products? {
+        let filename = output_dir.join(format!("{}_{}", serial_number, suffix));
+        journal.record(Stage::Write(suffix.clone()), || {
+            let mut file = match File::create(&filename) {
+                Ok(file) => file,
+                Err(e) => {
+                    return Err(Error::Io(format!(
+                        "public key file creation failed: {}: {}",
+                        suffix, e
+                    )))
+                }
+            };
+
+            match file.write_all(&contents).and_then(|_| file.sync_all()) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(Error::Io(format!(
+                    "public key file I/O failed: {}: {}",
+                    suffix, e
+                ))),
+            }
+        })?;
+        transcript.record(&Event::file_written(&filename, &contents))?;
+    }
+
+    journal.end(&Stage::Provision)?;
+    write_manifest(token, &output_dir, &options.roles)?;
+    println!("Success! Generated the TUF keys and wrote their public keys.");
+
+    Ok(())
+}
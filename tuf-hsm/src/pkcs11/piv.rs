@@ -0,0 +1,440 @@
+// Resetting a YubiKey's PIV application. YKCS11 can't do that (C_InitToken
+// doesn't wipe anything), so we talk to the application directly, over
+// PC/SC.
+//
+// The PIV application only resets once both its PIN and its PUK are
+// blocked, so we block them with wrong guesses first. The reset restores the
+// default management key, PIN and PUK; we then block the PUK again, since the
+// well-known default one would let anybody set a new PIN and use the keys.
+
+use libc::{c_char, c_void};
+use rand::Rng;
+
+use std::ffi::{CStr, CString};
+
+use crate::error::Error;
+
+// What the PIV application's credentials are right after a reset.
+pub const DEFAULT_MANAGEMENT_KEY: &str = "010203040506070801020304050607080102030405060708";
+pub const DEFAULT_PIN: &str = "123456";
+
+const PIV_AID: &[u8] = &[0xa0, 0x00, 0x00, 0x03, 0x08];
+
+const SW_SUCCESS: u16 = 0x9000;
+const SW_AUTH_BLOCKED: u16 = 0x6983;
+
+// The most wrong guesses that blocking the PIN or PUK can take. YubiKeys
+// allow up to 255 retries.
+const MAX_GUESSES: usize = 256;
+
+// A smartcard that we can send APDUs to.
+pub trait Card {
+    // Sends a command APDU, and returns the whole response (including its
+    // trailing status word).
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+// Sends a command APDU, and splits its response into data and status word.
+fn command(card: &mut dyn Card, apdu: &[u8]) -> Result<(Vec<u8>, u16), Error> {
+    let mut response = card.transmit(apdu).map_err(Error::Device)?;
+    if response.len() < 2 {
+        return Err(Error::Device(String::from(
+            "the YubiKey sent a truncated response",
+        )));
+    }
+
+    let sw = response.split_off(response.len() - 2);
+    Ok((response, u16::from_be_bytes([sw[0], sw[1]])))
+}
+
+fn select(card: &mut dyn Card) -> Result<(), Error> {
+    let mut apdu = vec![0x00, 0xa4, 0x04, 0x00, PIV_AID.len() as u8];
+    apdu.extend_from_slice(PIV_AID);
+
+    match command(card, &apdu)? {
+        (_, SW_SUCCESS) => Ok(()),
+        (_, sw) => Err(Error::Device(format!(
+            "couldn't select the PIV application: {:04x}",
+            sw
+        ))),
+    }
+}
+
+// Returns the YubiKey's serial number, which YKCS11 reports as the token's.
+fn serial(card: &mut dyn Card) -> Result<u32, Error> {
+    match command(card, &[0x00, 0xf8, 0x00, 0x00])? {
+        (data, SW_SUCCESS) if data.len() == 4 => {
+            Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+        }
+        (_, sw) => Err(Error::Device(format!(
+            "couldn't get the YubiKey's serial number: {:04x}",
+            sw
+        ))),
+    }
+}
+
+// Eight random digits, which are (almost certainly) the wrong PIN or PUK.
+fn guess() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    (0..8).map(|_| rng.gen_range(b'0', b'9' + 1)).collect()
+}
+
+// Sends wrong guesses until the card says that `what` is blocked. `apdu`
+// builds a command that tries a guess.
+fn block(card: &mut dyn Card, what: &str, apdu: impl Fn(&[u8]) -> Vec<u8>) -> Result<(), Error> {
+    for _ in 0..MAX_GUESSES {
+        match command(card, &apdu(&guess()))? {
+            (_, SW_AUTH_BLOCKED) => return Ok(()),
+            (_, sw) if sw == SW_SUCCESS || sw & 0xfff0 == 0x63c0 => continue,
+            (_, sw) => {
+                return Err(Error::Device(format!(
+                    "couldn't block the PIV {}: {:04x}",
+                    what, sw
+                )))
+            }
+        }
+    }
+
+    Err(Error::Device(format!(
+        "the PIV {} still isn't blocked after {} wrong guesses",
+        what, MAX_GUESSES
+    )))
+}
+
+fn block_pin(card: &mut dyn Card) -> Result<(), Error> {
+    // VERIFY, against the PIN.
+    block(card, "PIN", |pin| {
+        let mut apdu = vec![0x00, 0x20, 0x00, 0x80, 0x08];
+        apdu.extend_from_slice(pin);
+        apdu
+    })
+}
+
+fn block_puk(card: &mut dyn Card) -> Result<(), Error> {
+    // CHANGE REFERENCE DATA, from the guess to itself, against the PUK.
+    block(card, "PUK", |puk| {
+        let mut apdu = vec![0x00, 0x24, 0x00, 0x81, 0x10];
+        apdu.extend_from_slice(puk);
+        apdu.extend_from_slice(puk);
+        apdu
+    })
+}
+
+// Resets the PIV application on `card`, after checking that it belongs to
+// the YubiKey with `serial_number`, and leaves its PUK blocked.
+pub fn reset_card(card: &mut dyn Card, serial_number: &str) -> Result<(), Error> {
+    select(card)?;
+
+    let found = serial(card)?;
+    if serial_number.trim() != found.to_string() {
+        return Err(Error::DeviceNotFound(format!(
+            "expected the YubiKey with serial number {}, not {}",
+            serial_number.trim(),
+            found
+        )));
+    }
+
+    block_pin(card)?;
+    block_puk(card)?;
+    match command(card, &[0x00, 0xfb, 0x00, 0x00])? {
+        (_, SW_SUCCESS) => {}
+        (_, sw) => {
+            return Err(Error::Device(format!(
+                "couldn't reset the PIV application: {:04x}",
+                sw
+            )))
+        }
+    }
+
+    block_puk(card)
+}
+
+// Finds the YubiKey with `serial_number` among the PC/SC readers, and resets
+// its PIV application.
+pub fn reset(serial_number: &str) -> Result<(), Error> {
+    let pcsc = Pcsc::open()?;
+    for reader in pcsc.readers()? {
+        // Skip anything that isn't a YubiKey with a PIV application.
+        let mut card = match pcsc.connect(&reader) {
+            Ok(card) => card,
+            Err(_) => continue,
+        };
+        let found = select(&mut card).and_then(|_| serial(&mut card));
+        if matches!(found, Ok(found) if serial_number.trim() == found.to_string()) {
+            return reset_card(&mut card, serial_number);
+        }
+    }
+
+    Err(Error::DeviceNotFound(format!(
+        "no YubiKey with serial number {} in any PC/SC reader",
+        serial_number.trim()
+    )))
+}
+
+// NOTE: PC/SC's DWORD and LONG are C longs in pcsc-lite, but 32 bits in
+// macOS's PCSC framework.
+#[cfg(target_os = "macos")]
+type Dword = u32;
+#[cfg(target_os = "macos")]
+type Long = i32;
+#[cfg(not(target_os = "macos"))]
+type Dword = libc::c_ulong;
+#[cfg(not(target_os = "macos"))]
+type Long = libc::c_long;
+
+#[cfg(target_os = "macos")]
+const PCSC_LIBRARY: &str = "/System/Library/Frameworks/PCSC.framework/PCSC";
+#[cfg(not(target_os = "macos"))]
+const PCSC_LIBRARY: &str = "libpcsclite.so.1";
+
+const SCARD_S_SUCCESS: Long = 0;
+const SCARD_SCOPE_SYSTEM: Dword = 2;
+const SCARD_SHARE_SHARED: Dword = 2;
+const SCARD_PROTOCOL_T0: Dword = 1;
+const SCARD_PROTOCOL_T1: Dword = 2;
+const SCARD_LEAVE_CARD: Dword = 0;
+
+// The longest response that we expect: a short APDU's 256 bytes, plus the
+// status word.
+const MAX_RESPONSE: usize = 258;
+
+#[repr(C)]
+struct IoRequest {
+    protocol: Dword,
+    length: Dword,
+}
+
+type EstablishContext =
+    unsafe extern "C" fn(Dword, *const c_void, *const c_void, *mut Long) -> Long;
+type ReleaseContext = unsafe extern "C" fn(Long) -> Long;
+type ListReaders = unsafe extern "C" fn(Long, *const c_char, *mut c_char, *mut Dword) -> Long;
+type Connect =
+    unsafe extern "C" fn(Long, *const c_char, Dword, Dword, *mut Long, *mut Dword) -> Long;
+type Disconnect = unsafe extern "C" fn(Long, Dword) -> Long;
+type Transmit = unsafe extern "C" fn(
+    Long,
+    *const IoRequest,
+    *const u8,
+    Dword,
+    *mut IoRequest,
+    *mut u8,
+    *mut Dword,
+) -> Long;
+
+// A dynamically loaded library, closed when dropped.
+struct Library(*mut c_void);
+
+impl Library {
+    fn open(path: &str) -> Result<Library, Error> {
+        let c_path = CString::new(path).unwrap();
+        let library = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW) };
+        match library.is_null() {
+            true => Err(Error::Device(format!(
+                "couldn't load {}; is PC/SC installed?",
+                path
+            ))),
+            false => Ok(Library(library)),
+        }
+    }
+
+    // Looks up `name`.
+    // NOTE: The caller has to know the symbol's real type.
+    unsafe fn symbol<T>(&self, name: &str) -> Result<T, Error> {
+        let c_name = CString::new(name).unwrap();
+        let symbol = libc::dlsym(self.0, c_name.as_ptr());
+        match symbol.is_null() {
+            true => Err(Error::Device(format!("PC/SC has no {}", name))),
+            false => Ok(std::mem::transmute_copy(&symbol)),
+        }
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.0);
+        }
+    }
+}
+
+// The PC/SC library, loaded at runtime (like a PKCS#11 module is) so that
+// only YubiKey users need it, and a PC/SC context.
+struct Pcsc {
+    context: Long,
+    release_context: ReleaseContext,
+    list_readers: ListReaders,
+    connect: Connect,
+    disconnect: Disconnect,
+    transmit: Transmit,
+
+    // NOTE: This has to be dropped last, after the context is released.
+    _library: Library,
+}
+
+impl Pcsc {
+    fn open() -> Result<Pcsc, Error> {
+        let library = Library::open(PCSC_LIBRARY)?;
+        let establish_context: EstablishContext =
+            unsafe { library.symbol("SCardEstablishContext")? };
+        let release_context: ReleaseContext = unsafe { library.symbol("SCardReleaseContext")? };
+        let list_readers: ListReaders = unsafe { library.symbol("SCardListReaders")? };
+        let connect: Connect = unsafe { library.symbol("SCardConnect")? };
+        let disconnect: Disconnect = unsafe { library.symbol("SCardDisconnect")? };
+        let transmit: Transmit = unsafe { library.symbol("SCardTransmit")? };
+
+        let mut context: Long = 0;
+        let rv = unsafe {
+            establish_context(
+                SCARD_SCOPE_SYSTEM,
+                std::ptr::null(),
+                std::ptr::null(),
+                &mut context,
+            )
+        };
+        if rv != SCARD_S_SUCCESS {
+            return Err(Error::Device(format!(
+                "couldn't connect to the PC/SC daemon (is pcscd running?): {:#x}",
+                rv
+            )));
+        }
+
+        Ok(Pcsc {
+            context,
+            release_context,
+            list_readers,
+            connect,
+            disconnect,
+            transmit,
+            _library: library,
+        })
+    }
+
+    fn readers(&self) -> Result<Vec<CString>, Error> {
+        // Ask for the size of the list first, then for the list.
+        let mut length: Dword = 0;
+        let rv = unsafe {
+            (self.list_readers)(
+                self.context,
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                &mut length,
+            )
+        };
+        if rv != SCARD_S_SUCCESS {
+            return Err(Error::DeviceNotFound(format!(
+                "couldn't list the PC/SC readers: {:#x}",
+                rv
+            )));
+        }
+
+        let mut buffer = vec![0 as c_char; length as usize];
+        let rv = unsafe {
+            (self.list_readers)(
+                self.context,
+                std::ptr::null(),
+                buffer.as_mut_ptr(),
+                &mut length,
+            )
+        };
+        if rv != SCARD_S_SUCCESS {
+            return Err(Error::DeviceNotFound(format!(
+                "couldn't list the PC/SC readers: {:#x}",
+                rv
+            )));
+        }
+
+        // The list is a run of NUL-terminated names, ended by an empty one.
+        let mut readers = vec![];
+        let mut offset = 0;
+        while offset < buffer.len() && buffer[offset] != 0 {
+            let name = unsafe { CStr::from_ptr(buffer[offset..].as_ptr()) };
+            offset += name.to_bytes().len() + 1;
+            readers.push(name.to_owned());
+        }
+
+        Ok(readers)
+    }
+
+    fn connect(&self, reader: &CStr) -> Result<PcscCard<'_>, Error> {
+        let mut handle: Long = 0;
+        let mut protocol: Dword = 0;
+        let rv = unsafe {
+            (self.connect)(
+                self.context,
+                reader.as_ptr(),
+                SCARD_SHARE_SHARED,
+                SCARD_PROTOCOL_T0 | SCARD_PROTOCOL_T1,
+                &mut handle,
+                &mut protocol,
+            )
+        };
+
+        match rv {
+            SCARD_S_SUCCESS => Ok(PcscCard {
+                pcsc: self,
+                handle,
+                protocol,
+            }),
+            _ => Err(Error::Device(format!(
+                "couldn't connect to {}: {:#x}",
+                reader.to_string_lossy(),
+                rv
+            ))),
+        }
+    }
+}
+
+impl Drop for Pcsc {
+    fn drop(&mut self) {
+        unsafe {
+            (self.release_context)(self.context);
+        }
+    }
+}
+
+// A card in one of the PC/SC readers. Other programs (like YKCS11) may use it
+// at the same time.
+struct PcscCard<'a> {
+    pcsc: &'a Pcsc,
+    handle: Long,
+    protocol: Dword,
+}
+
+impl Card for PcscCard<'_> {
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, String> {
+        let request = IoRequest {
+            protocol: self.protocol,
+            length: std::mem::size_of::<IoRequest>() as Dword,
+        };
+        let mut response = vec![0; MAX_RESPONSE];
+        let mut length = response.len() as Dword;
+
+        let rv = unsafe {
+            (self.pcsc.transmit)(
+                self.handle,
+                &request,
+                apdu.as_ptr(),
+                apdu.len() as Dword,
+                std::ptr::null_mut(),
+                response.as_mut_ptr(),
+                &mut length,
+            )
+        };
+
+        match rv {
+            SCARD_S_SUCCESS => {
+                response.truncate(length as usize);
+                Ok(response)
+            }
+            _ => Err(format!("couldn't talk to the YubiKey: {:#x}", rv)),
+        }
+    }
+}
+
+impl Drop for PcscCard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            (self.pcsc.disconnect)(self.handle, SCARD_LEAVE_CARD);
+        }
+    }
+}
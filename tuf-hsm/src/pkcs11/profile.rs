@@ -0,0 +1,256 @@
+// Vendor profiles: the PKCS#11 tokens that we're willing to provision, and
+// what each of them accepts. Almost everything that we do to a token
+// (init_token, set_pin, init_pin and key generation) is plain PKCS#11, so a
+// profile mostly has to say how to recognize the token and what it'll take.
+// The exception is the YubiKey, which has to be reset through its PIV
+// application; see piv.rs.
+
+use pkcs11::types::{self, CK_USER_TYPE};
+use regex::Regex;
+
+use std::ops::RangeInclusive;
+
+use super::role::Role;
+use super::KeyType;
+
+// How a profile recognizes its tokens by their slot's manufacturer ID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Manufacturer {
+    // The manufacturer ID must be exactly this.
+    Exact(&'static str),
+
+    // The manufacturer ID must start with this.
+    Prefix(&'static str),
+
+    // Any manufacturer will do.
+    Any,
+}
+
+impl Manufacturer {
+    // NOTE: PKCS#11 pads manufacturer IDs with spaces, so we ignore those.
+    pub fn matches(&self, manufacturer_id: &str) -> bool {
+        let manufacturer_id = manufacturer_id.trim_end();
+        match self {
+            Manufacturer::Exact(manufacturer) => manufacturer_id == *manufacturer,
+            Manufacturer::Prefix(prefix) => manufacturer_id.starts_with(prefix),
+            Manufacturer::Any => true,
+        }
+    }
+}
+
+// How a profile's tokens are wiped before provisioning.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reset {
+    // C_InitToken with the token's current SO PIN, which it keeps.
+    InitToken,
+
+    // A reset of a YubiKey's PIV application, over PC/SC. It takes no PIN,
+    // and restores the default management key (YKCS11's SO PIN) and PIN.
+    PivApplet,
+}
+
+// The shape of a PIN that a token accepts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PinFormat {
+    // A regular expression that the whole PIN must match.
+    pub pattern: &'static str,
+
+    // The pattern in words, for error messages.
+    pub description: &'static str,
+}
+
+impl PinFormat {
+    // Checks that `pin` looks like a `kind` (e.g. "SO PIN"). The error never
+    // includes the PIN.
+    pub fn check(&self, kind: &str, pin: &str) -> Result<(), String> {
+        let pattern = match Regex::new(self.pattern) {
+            Ok(pattern) => pattern,
+            Err(e) => return Err(format!("bad {} pattern {:?}: {}", kind, self.pattern, e)),
+        };
+
+        match pattern.is_match(pin) {
+            true => Ok(()),
+            false => Err(format!("invalid {} (expected {})", kind, self.description)),
+        }
+    }
+}
+
+// A description of the PKCS#11 tokens that we're willing to provision.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    // A short, human-readable name for this profile, as given to --profile
+    // and as a plan's vendor.
+    pub name: &'static str,
+
+    // The slot manufacturer ID that a discovered token must report.
+    pub manufacturer: Manufacturer,
+
+    // The label that the token is given when (re)initialized.
+    pub token_label: &'static str,
+
+    // Whether to ignore slots whose token hasn't been initialized yet.
+    // SoftHSM2 always exposes one spare, uninitialized slot alongside its
+    // real tokens, which would otherwise look like a second HSM.
+    pub ignore_uninitialized: bool,
+
+    // How the token is wiped.
+    pub reset: Reset,
+
+    // Who has to be logged in to generate keys: CKU_USER, or CKU_SO.
+    pub generate_as: CK_USER_TYPE,
+
+    // What the token accepts as a new SO PIN and user PIN.
+    pub so_pin: PinFormat,
+    pub user_pin: PinFormat,
+
+    // The key types that the token can generate.
+    pub key_types: &'static [KeyType],
+
+    // The CKA_IDs that the token can give its keys.
+    pub key_ids: RangeInclusive<u8>,
+}
+
+// The Nitrokey HSM, through OpenSC.
+pub const NITROKEY_PROFILE: Profile = Profile {
+    name: "nitrokey",
+    manufacturer: Manufacturer::Exact("Nitrokey"),
+    token_label: "Nitrokey HSM - TUF",
+    ignore_uninitialized: false,
+    reset: Reset::InitToken,
+    generate_as: types::CKU_USER,
+    so_pin: PinFormat {
+        pattern: "^[[:xdigit:]]{16}$",
+        description: "16 hex digits",
+    },
+    user_pin: PinFormat {
+        pattern: "^[a-z0-9]{6}$",
+        description: "6 alphanum characters",
+    },
+    key_types: &[KeyType::P256, KeyType::P384],
+    key_ids: 0x01..=0xff,
+};
+
+// A YubiKey's PIV application, through Yubico's YKCS11 module.
+// NOTE: YKCS11 logs the SO in with the PIV management key, and only lets the
+// SO generate keys. It maps CKA_IDs 01 through 04 onto the PIV slots 9a, 9c,
+// 9d and 9e, which have their own purposes (9e signs without the PIN), so we
+// only use 05 through 18: the retired key slots 82 through 95. The token's
+// label is YKCS11's own, since nothing reinitializes it.
+pub const YKCS11_PROFILE: Profile = Profile {
+    name: "ykcs11",
+    manufacturer: Manufacturer::Prefix("Yubico"),
+    token_label: "YubiKey PIV",
+    ignore_uninitialized: false,
+    reset: Reset::PivApplet,
+    generate_as: types::CKU_SO,
+    so_pin: PinFormat {
+        pattern: "^[[:xdigit:]]{48}$",
+        description: "a 48 hex digit management key",
+    },
+    user_pin: PinFormat {
+        pattern: "^.{6,8}$",
+        description: "6 to 8 characters",
+    },
+    key_types: &[KeyType::P256, KeyType::P384],
+    key_ids: 0x05..=0x18,
+};
+
+// A profile for rehearsing and testing against a local SoftHSM2 token.
+// NOTE: This MUST NOT be used during a ceremony: SoftHSM2 keeps its
+// "HSM" state in ordinary files on disk.
+pub const SOFTHSM2_TEST_PROFILE: Profile = Profile {
+    name: "softhsm2-test",
+    manufacturer: Manufacturer::Exact("SoftHSM project"),
+    token_label: "SoftHSM2 - TUF TEST",
+    ignore_uninitialized: true,
+    reset: Reset::InitToken,
+    generate_as: types::CKU_USER,
+    so_pin: PinFormat {
+        pattern: "^.{4,255}$",
+        description: "4 to 255 characters",
+    },
+    user_pin: PinFormat {
+        pattern: "^.{4,255}$",
+        description: "4 to 255 characters",
+    },
+    key_types: &[KeyType::P256, KeyType::P384],
+    key_ids: 0x00..=0xff,
+};
+
+// Any other PKCS#11 token, taken at its word. The token still has to support
+// everything that provisioning does, which we only find out by trying.
+pub const GENERIC_PROFILE: Profile = Profile {
+    name: "generic",
+    manufacturer: Manufacturer::Any,
+    token_label: "TUF",
+    ignore_uninitialized: false,
+    reset: Reset::InitToken,
+    generate_as: types::CKU_USER,
+    so_pin: PinFormat {
+        pattern: "^.{4,}$",
+        description: "at least 4 characters",
+    },
+    user_pin: PinFormat {
+        pattern: "^.{4,}$",
+        description: "at least 4 characters",
+    },
+    key_types: &[KeyType::P256, KeyType::P384],
+    key_ids: 0x00..=0xff,
+};
+
+pub const PROFILES: &[Profile] = &[
+    NITROKEY_PROFILE,
+    YKCS11_PROFILE,
+    SOFTHSM2_TEST_PROFILE,
+    GENERIC_PROFILE,
+];
+
+impl Profile {
+    // The names accepted by --profile.
+    pub const NAMES: &'static [&'static str] = &["nitrokey", "ykcs11", "softhsm2-test", "generic"];
+
+    pub fn from_name(name: &str) -> Option<Profile> {
+        PROFILES
+            .iter()
+            .find(|profile| profile.name == name)
+            .cloned()
+    }
+
+    // Checks that `so_pin` looks like an SO PIN. The error never includes the PIN.
+    pub fn check_so_pin(&self, so_pin: &str) -> Result<(), String> {
+        self.so_pin.check("SO PIN", so_pin)
+    }
+
+    // Checks that `user_pin` looks like a user PIN. The error never includes the PIN.
+    pub fn check_user_pin(&self, user_pin: &str) -> Result<(), String> {
+        self.user_pin.check("user PIN", user_pin)
+    }
+
+    // Checks that the token can generate every role's key, before anything
+    // touches it.
+    pub fn check_roles(&self, roles: &[Role]) -> Result<(), String> {
+        for role in roles {
+            if !self.key_types.contains(&role.key_type) {
+                return Err(format!(
+                    "role {}: {} tokens can't generate {} keys",
+                    role.name,
+                    self.name,
+                    role.key_type.name()
+                ));
+            }
+
+            if !self.key_ids.contains(&role.key_id) {
+                return Err(format!(
+                    "role {}: key ID {:02x} is outside of {} tokens' range ({:02x} to {:02x})",
+                    role.name,
+                    role.key_id,
+                    self.name,
+                    self.key_ids.start(),
+                    self.key_ids.end()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
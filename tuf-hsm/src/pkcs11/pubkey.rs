@@ -0,0 +1,115 @@
+// Helpers for turning the EC points that the HSM hands us into
+// SubjectPublicKeyInfo (RFC 5480) public keys, in both DER and PEM.
+//
+// NOTE(ww): We only ever need to encode two fixed structures here, so we
+// build the DER by hand rather than pulling in an ASN.1 library.
+
+use super::KeyType;
+
+// id-ecPublicKey (1.2.840.10045.2.1), DER-encoded.
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+// secp256r1 (1.2.840.10045.3.1.7), DER-encoded.
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+// secp384r1 (1.3.132.0.34), DER-encoded.
+const P384_OID: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+const DER_BIT_STRING: u8 = 0x03;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_SEQUENCE: u8 = 0x30;
+
+// The leading byte of an uncompressed EC point (SEC 1, section 2.3.3).
+const UNCOMPRESSED_POINT: u8 = 0x04;
+
+impl KeyType {
+    // The DER-encoded curve OID, suitable for CKA_EC_PARAMS.
+    pub fn ec_params(self) -> &'static [u8] {
+        match self {
+            KeyType::P256 => P256_OID,
+            KeyType::P384 => P384_OID,
+        }
+    }
+
+    // The length of an uncompressed point on this key type's curve.
+    pub fn point_len(self) -> usize {
+        match self {
+            KeyType::P256 => 1 + 2 * 32,
+            KeyType::P384 => 1 + 2 * 48,
+        }
+    }
+}
+
+fn der_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag];
+
+    if contents.len() < 0x80 {
+        tlv.push(contents.len() as u8);
+    } else {
+        let len = contents.len().to_be_bytes();
+        let len = &len[len.iter().position(|b| *b != 0).unwrap()..];
+        tlv.push(0x80 | len.len() as u8);
+        tlv.extend_from_slice(len);
+    }
+
+    tlv.extend_from_slice(contents);
+    tlv
+}
+
+// Extracts the raw, uncompressed EC point from a CKA_EC_POINT value.
+// PKCS#11 says that this is a DER-encoded OCTET STRING, but some older
+// modules return the bare point instead; we accept either.
+pub fn ec_point_from_attribute(key_type: KeyType, value: &[u8]) -> Result<Vec<u8>, String> {
+    let point_len = key_type.point_len();
+
+    let point = if value.len() == point_len {
+        value
+    } else if value.len() == point_len + 2
+        && value[0] == DER_OCTET_STRING
+        && value[1] as usize == point_len
+    {
+        &value[2..]
+    } else {
+        return Err(format!(
+            "unexpected CKA_EC_POINT ({} bytes) for a {:?} key",
+            value.len(),
+            key_type
+        ));
+    };
+
+    if point[0] != UNCOMPRESSED_POINT {
+        return Err(String::from(
+            "HSM returned a compressed or malformed EC point",
+        ));
+    }
+
+    Ok(point.to_vec())
+}
+
+// Wraps an uncompressed EC point in a DER-encoded SubjectPublicKeyInfo.
+pub fn spki_der(key_type: KeyType, point: &[u8]) -> Vec<u8> {
+    let algorithm = der_tlv(
+        DER_SEQUENCE,
+        &[EC_PUBLIC_KEY_OID, key_type.ec_params()].concat(),
+    );
+
+    // A BIT STRING's first content byte is the number of unused bits.
+    let subject_public_key = der_tlv(DER_BIT_STRING, &[&[0x00], point].concat());
+
+    der_tlv(DER_SEQUENCE, &[algorithm, subject_public_key].concat())
+}
+
+// PEM-encodes a DER-encoded SubjectPublicKeyInfo.
+pub fn spki_pem(der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+
+    let mut pem = String::from("-----BEGIN PUBLIC KEY-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        // NOTE: base64 output is always ASCII, so this can't fail.
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str("-----END PUBLIC KEY-----\n");
+
+    pem
+}
@@ -0,0 +1,130 @@
+// Picking a token out of the ones that the PKCS#11 module exposes, and
+// describing them for list-tokens.
+
+use pkcs11::types::{self, CK_FLAGS, CK_SLOT_ID, CK_TOKEN_INFO};
+
+use crate::error::Error;
+
+// Which token to provision, when more than one is attached. Either or both
+// may be given; when both are, they have to agree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Selection<'a> {
+    // The token's serial number (CK_TOKEN_INFO.serialNumber).
+    pub serial: Option<&'a str>,
+
+    // The token's slot ID.
+    pub slot: Option<CK_SLOT_ID>,
+}
+
+// Picks one slot from `tokens`, a list of (slot ID, serial number) pairs.
+// Without a selection there has to be exactly one token; anything ambiguous
+// is an error.
+pub fn select_slot(
+    tokens: &[(CK_SLOT_ID, String)],
+    selection: &Selection,
+) -> Result<CK_SLOT_ID, Error> {
+    if tokens.is_empty() {
+        return Err(Error::DeviceNotFound(String::from("no HSMs detected")));
+    }
+
+    // NOTE: PKCS#11 pads serial numbers with spaces, and the padding tends to
+    // survive copy-and-paste.
+    let candidates: Vec<&(CK_SLOT_ID, String)> = tokens
+        .iter()
+        .filter(|(slot, serial)| {
+            selection.slot.is_none_or(|wanted| wanted == *slot)
+                && selection
+                    .serial
+                    .is_none_or(|wanted| wanted.trim() == serial.trim())
+        })
+        .collect();
+
+    let found = tokens
+        .iter()
+        .map(|(slot, serial)| format!("slot #{} ({})", slot, serial.trim()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match candidates.as_slice() {
+        [(slot, _)] => Ok(*slot),
+        [] => Err(Error::DeviceNotFound(format!(
+            "no matching token detected (found {})",
+            found
+        ))),
+        _ if *selection == Selection::default() => Err(Error::MultipleDevices(format!(
+            "more than one HSM or token detected ({}); pass --serial or --slot to pick one",
+            found
+        ))),
+        _ => Err(Error::MultipleDevices(format!(
+            "more than one token matches (found {}); refusing to continue",
+            found
+        ))),
+    }
+}
+
+const FLAG_NAMES: &[(CK_FLAGS, &str)] = &[
+    (types::CKF_RNG, "rng"),
+    (types::CKF_WRITE_PROTECTED, "write-protected"),
+    (types::CKF_LOGIN_REQUIRED, "login-required"),
+    (types::CKF_USER_PIN_INITIALIZED, "user-pin-initialized"),
+    (
+        types::CKF_PROTECTED_AUTHENTICATION_PATH,
+        "protected-auth-path",
+    ),
+    (types::CKF_TOKEN_INITIALIZED, "token-initialized"),
+    (types::CKF_ERROR_STATE, "error-state"),
+    (types::CKF_USER_PIN_TO_BE_CHANGED, "user-pin-to-be-changed"),
+    (types::CKF_SO_PIN_TO_BE_CHANGED, "so-pin-to-be-changed"),
+];
+
+// The names of the interesting flags set in a token's flags. The PIN
+// counter flags are reported separately by pin_status.
+pub fn flag_names(flags: CK_FLAGS) -> Vec<&'static str> {
+    FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+// PKCS#11 doesn't expose the actual retry counters, only whether they're
+// getting low. Describe the user (or SO) PIN's counter from the flags.
+pub fn pin_status(flags: CK_FLAGS, so: bool) -> &'static str {
+    let (locked, final_try, count_low) = match so {
+        true => (
+            types::CKF_SO_PIN_LOCKED,
+            types::CKF_SO_PIN_FINAL_TRY,
+            types::CKF_SO_PIN_COUNT_LOW,
+        ),
+        false => (
+            types::CKF_USER_PIN_LOCKED,
+            types::CKF_USER_PIN_FINAL_TRY,
+            types::CKF_USER_PIN_COUNT_LOW,
+        ),
+    };
+
+    if flags & locked != 0 {
+        "locked"
+    } else if flags & final_try != 0 {
+        "final try"
+    } else if flags & count_low != 0 {
+        "count low"
+    } else {
+        "ok"
+    }
+}
+
+// A one-token summary for list-tokens.
+pub fn describe(slot: CK_SLOT_ID, token: &CK_TOKEN_INFO) -> String {
+    format!(
+        "slot #{}: {} {} with serial number {}\n  flags: {} ({:#x})\n  user PIN: {}, SO PIN: {}",
+        slot,
+        String::from(token.manufacturerID),
+        String::from(token.model),
+        String::from(token.serialNumber),
+        flag_names(token.flags).join(", "),
+        token.flags,
+        pin_status(token.flags, false),
+        pin_status(token.flags, true),
+    )
+}
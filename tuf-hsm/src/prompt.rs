@@ -0,0 +1,182 @@
+use dialoguer::{Confirmation, PasswordInput};
+use zeroize::Zeroize;
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::secret::Secret;
+
+// The longest secret that read_secret_fd will read.
+const MAX_SECRET_LEN: usize = 1024;
+
+// Which secret a password prompt asks for. Re-entering a new secret (to
+// confirm it) asks for the same kind again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecretKind {
+    // The password of the YubiHSM authentication key that provisioning creates.
+    AuthKeyPassword,
+
+    // A PKCS#11 token's SO PIN as it is now, before provisioning changes it.
+    CurrentSoPin,
+
+    // The SO PIN and user PIN that provisioning gives a PKCS#11 token.
+    NewSoPin,
+    NewUserPin,
+}
+
+// Everything that provisioning asks of the operator.
+// The interactive implementation is Terminal; tests supply scripted answers.
+pub trait Prompt {
+    fn confirm(&mut self, msg: &str) -> Result<bool, String>;
+
+    // Asks for a secret of the given kind, showing `prompt` to the operator.
+    fn password(&mut self, kind: SecretKind, prompt: &str) -> Result<Secret, String>;
+}
+
+pub struct Terminal;
+
+impl Prompt for Terminal {
+    fn confirm(&mut self, msg: &str) -> Result<bool, String> {
+        match Confirmation::new().with_text(msg).default(false).interact() {
+            Ok(answer) => Ok(answer),
+            Err(e) => Err(format!("prompt error: {}", e)),
+        }
+    }
+
+    fn password(&mut self, _kind: SecretKind, prompt: &str) -> Result<Secret, String> {
+        match PasswordInput::new().with_prompt(prompt).interact() {
+            Ok(password) => Ok(Secret::new(password)),
+            Err(e) => Err(format!("prompt failed: {}", e)),
+        }
+    }
+}
+
+// Answers prompts without a TTY, for scripted rehearsals: confirmations are
+// pre-answered (with --i-have-read-the-runbook) and secrets come from file
+// descriptors. Anything that it wasn't given falls through to the Terminal.
+pub struct Unattended {
+    confirm_all: bool,
+
+    // The secret to answer each kind of password prompt with.
+    secrets: Vec<(SecretKind, Secret)>,
+}
+
+impl Unattended {
+    pub fn new(confirm_all: bool) -> Self {
+        Unattended {
+            confirm_all,
+            secrets: vec![],
+        }
+    }
+
+    // Answers every password prompt for `kind` with `secret`.
+    pub fn with_secret(mut self, kind: SecretKind, secret: Secret) -> Self {
+        self.secrets.push((kind, secret));
+        self
+    }
+}
+
+impl Prompt for Unattended {
+    fn confirm(&mut self, msg: &str) -> Result<bool, String> {
+        match self.confirm_all {
+            true => {
+                println!("{} yes (--i-have-read-the-runbook)", msg);
+                Ok(true)
+            }
+            false => Terminal.confirm(msg),
+        }
+    }
+
+    fn password(&mut self, kind: SecretKind, prompt: &str) -> Result<Secret, String> {
+        match self.secrets.iter().find(|(given, _)| *given == kind) {
+            Some((_, secret)) => {
+                println!("{}: (from a file descriptor)", prompt);
+                Ok(secret.clone())
+            }
+            None => Terminal.password(kind, prompt),
+        }
+    }
+}
+
+pub fn is_valid_fd(val: String) -> Result<(), String> {
+    match val.parse::<RawFd>() {
+        Ok(fd) if fd > 2 => Ok(()),
+        _ => Err(format!(
+            "invalid file descriptor (expected a number above 2): {}",
+            val
+        )),
+    }
+}
+
+// Reads a secret from the first line of the file descriptor `fd`, e.g. one
+// that the shell opened on a named pipe with `3<pipe`. The descriptor is
+// closed afterwards, so this must be called before the process opens any
+// file of its own: otherwise a descriptor that the shell didn't open could be
+// one of ours by now.
+pub fn read_secret_fd(fd: RawFd) -> Result<Secret, String> {
+    // NOTE(ww): The standard descriptors aren't ours to close, and stdin in
+    // particular is what the interactive prompts read from.
+    if fd <= 2 {
+        return Err(format!(
+            "refusing to read a secret from standard file descriptor {}",
+            fd
+        ));
+    }
+
+    // A descriptor that isn't open at all is a mistake on the command line.
+    // SAFETY: F_GETFD only reads the descriptor's flags.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!("file descriptor {} isn't open", fd));
+    }
+
+    // SAFETY: Nothing else in the process owns `fd` (see above), so the File
+    // can take ownership of it and close it when dropped.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+
+    // NOTE(ww): Reading a byte at a time, rather than through a BufReader,
+    // means that the only copy of the secret is one that we wipe.
+    let mut buf = [0u8; MAX_SECRET_LEN];
+    let mut len = 0;
+    let result = loop {
+        if len == buf.len() {
+            break Err(format!("the secret on file descriptor {} is too long", fd));
+        }
+
+        match file.read(&mut buf[len..len + 1]) {
+            Ok(0) => break Ok(()),
+            Ok(_) if buf[len] == b'\n' => break Ok(()),
+            Ok(_) => len += 1,
+            Err(e) => {
+                break Err(format!(
+                    "couldn't read a secret from file descriptor {}: {}",
+                    fd, e
+                ))
+            }
+        }
+    };
+
+    let secret = match (result, &buf[..len]) {
+        (Err(e), _) => Err(e),
+        (Ok(()), []) => Err(format!("nothing to read on file descriptor {}", fd)),
+        (Ok(()), [secret @ .., b'\r']) | (Ok(()), secret) => {
+            match String::from_utf8(secret.to_vec()) {
+                Ok(secret) => Ok(Secret::new(secret)),
+                Err(e) => {
+                    e.into_bytes().zeroize();
+                    Err(format!("the secret on file descriptor {} isn't UTF-8", fd))
+                }
+            }
+        }
+    };
+    buf.zeroize();
+
+    secret
+}
+
+// Whether stdin is a terminal, i.e. whether somebody could be answering the
+// prompts by hand.
+pub fn stdin_is_tty() -> bool {
+    // SAFETY: isatty has no preconditions.
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
@@ -0,0 +1,168 @@
+// Secrets (passwords and PINs) held by the provisioner, and keeping them out
+// of places that can outlive the process: swap, core dumps, and logs.
+
+use lazy_static::lazy_static;
+use zeroize::Zeroize;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Mutex;
+
+lazy_static! {
+    // How many live Secrets lie on each locked page, by the page's address.
+    // mlock doesn't nest, so a page can only be unlocked once the last Secret
+    // on it is dropped: two short Secrets can easily share one.
+    static ref LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+}
+
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+// A password or PIN. Its buffer is locked into memory (where the OS lets us)
+// and wiped when dropped, and it never shows up in Debug output. Use expose()
+// to get at it, as close to where it's needed as possible.
+// NOTE(ww): There's deliberately no Display impl.
+// NOTE: Only the Secret's own buffer is looked after. The copies that made it
+// (e.g. dialoguer's, while reading a password at the terminal) are neither
+// locked nor wiped.
+pub struct Secret {
+    inner: String,
+}
+
+impl Secret {
+    pub fn new(inner: String) -> Secret {
+        let secret = Secret { inner };
+
+        // NOTE(ww): mlock can fail, e.g. under a small RLIMIT_MEMLOCK. The
+        // secret is still wiped on drop, so that's not worth failing over.
+        secret.lock(true);
+        secret
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.inner
+    }
+
+    // Locks (or unlocks) the pages under the String's allocation, keeping
+    // count of the Secrets on each in LOCKED_PAGES.
+    fn lock(&self, lock: bool) {
+        let len = self.inner.capacity();
+        if len == 0 {
+            return;
+        }
+
+        let page_size = page_size();
+        let start = self.inner.as_ptr() as usize & !(page_size - 1);
+        let end = self.inner.as_ptr() as usize + len;
+
+        let mut pages = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+        for page in (start..end).step_by(page_size) {
+            let count = pages.entry(page).or_insert(0);
+            let first_or_last = match lock {
+                true => {
+                    *count += 1;
+                    *count == 1
+                }
+                false => {
+                    *count -= 1;
+                    *count == 0
+                }
+            };
+            if !first_or_last {
+                continue;
+            }
+
+            let addr = page as *const libc::c_void;
+            // SAFETY: The page holds (part of) the String's own allocation.
+            unsafe {
+                match lock {
+                    true => libc::mlock(addr, page_size),
+                    false => {
+                        pages.remove(&page);
+                        libc::munlock(addr, page_size)
+                    }
+                };
+            }
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Secret {
+        Secret::new(secret.into())
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Secret {
+        Secret::from(self.expose())
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+
+        // zeroize only wipes the String's contents, and not its spare capacity,
+        // which can still hold e.g. characters that were deleted at a prompt.
+        // SAFETY: Only zeroes are written, and only into the allocation.
+        let bytes = unsafe { self.inner.as_mut_vec() };
+        for byte in bytes.spare_capacity_mut() {
+            unsafe { ptr::write_volatile(byte.as_mut_ptr(), 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+
+        self.lock(false);
+    }
+}
+
+// Keeps the process from dumping core (and, on Linux, from being attached to
+// or read through /proc by other processes of the same user), so that the
+// secrets in its memory can't end up on disk.
+pub fn disable_core_dumps() -> Result<(), String> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // SAFETY: limit is a valid rlimit.
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(format!(
+            "couldn't disable core dumps: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: PR_SET_DUMPABLE takes a single integer argument.
+        if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+            return Err(format!(
+                "couldn't make the process undumpable: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
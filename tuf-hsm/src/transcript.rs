@@ -0,0 +1,127 @@
+// A machine-readable transcript of a ceremony, kept alongside the ceremony
+// products as {products_dir}/{serial}/{serial}_transcript.jsonl.
+//
+// Where the journal only says which stages began and ended, the transcript
+// says what each one did, so that whoever reviews the products afterwards
+// doesn't have to reconstruct it from a recording. Each event is one JSON
+// object on its own line, e.g.:
+//
+//   {"time":1600000000,"event":"key-generated","role":"root","object_id":"3","algorithm":"p256"}
+//
+// NOTE: Events only ever carry public information. Nothing that could hold a
+// password, PIN or private key (i.e. a Secret) is ever part of one.
+
+use ring::digest;
+use serde::Serialize;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+// The suffix of the transcript file. The ultimate path will be of the form
+// XXXXXXXXXX_transcript.jsonl, where XXXXXXXXXX is the serial number of the HSM.
+pub const TRANSCRIPT_FILE_SUFFIX: &str = "transcript.jsonl";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    // The HSM that's about to be provisioned, e.g. "yubihsm" or a PKCS#11
+    // profile name like "nitrokey".
+    DeviceDiscovered {
+        vendor: String,
+        serial_number: String,
+    },
+    // The operator confirmed the factory reset, and the HSM was wiped.
+    ResetConfirmed,
+    // One of the HSM's factory credentials was replaced: "auth-key" (with the
+    // new key's object ID) on a YubiHSM, "so-pin" or "user-pin" on a PKCS#11
+    // token.
+    CredentialChanged {
+        credential: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        object_id: Option<String>,
+    },
+    // A role's keypair was generated on the HSM.
+    KeyGenerated {
+        role: String,
+        object_id: String,
+        algorithm: String,
+    },
+    // The HSM attested to a role's keypair.
+    AttestationProduced {
+        role: String,
+        object_id: String,
+    },
+    // A ceremony product was written, by filename (not path).
+    FileWritten {
+        file: String,
+        size: usize,
+        sha256: String,
+    },
+}
+
+impl Event {
+    // The event for writing `contents` to `path`.
+    pub fn file_written(path: &Path, contents: &[u8]) -> Event {
+        Event::FileWritten {
+            file: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            size: contents.len(),
+            sha256: hex(digest::digest(&digest::SHA256, contents).as_ref()),
+        }
+    }
+}
+
+// A lowercase hex encoding, as sha256sum prints.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A single line of the transcript.
+#[derive(Serialize)]
+struct Line<'a> {
+    time: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub fn path(output_dir: &Path, serial_number: &str) -> PathBuf {
+    output_dir.join(format!("{}_{}", serial_number, TRANSCRIPT_FILE_SUFFIX))
+}
+
+pub struct Transcript {
+    file: File,
+}
+
+impl Transcript {
+    // Opens the transcript at `path` for appending, creating it if need be.
+    // A resumed run carries on with the same transcript.
+    pub fn open(path: &Path) -> Result<Transcript, Error> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(Transcript { file }),
+            Err(e) => Err(Error::Io(format!(
+                "couldn't open transcript {:?}: {}",
+                path, e
+            ))),
+        }
+    }
+
+    pub fn record(&mut self, event: &Event) -> Result<(), Error> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        // NOTE: This unwrap is safe, since an Event is only ever strings and numbers.
+        let line = serde_json::to_string(&Line { time, event }).unwrap();
+
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| Error::Io(format!("couldn't write to the transcript: {}", e)))
+    }
+}
@@ -0,0 +1,162 @@
+// Parsing for the Yubico-specific X.509 extensions that a YubiHSM 2 places
+// in its attestation certificates. See:
+// https://developers.yubico.com/YubiHSM2/Concepts/Attestation.html
+
+use x509_parser::certificate::X509Certificate;
+use yubihsm::capability::Capability;
+use yubihsm::domain::Domain;
+use yubihsm::object::{Id, Origin};
+
+use std::fmt;
+
+const FIRMWARE_VERSION_OID: &str = "1.3.6.1.4.1.41482.4.1";
+const SERIAL_NUMBER_OID: &str = "1.3.6.1.4.1.41482.4.2";
+const ORIGIN_OID: &str = "1.3.6.1.4.1.41482.4.3";
+const DOMAINS_OID: &str = "1.3.6.1.4.1.41482.4.4";
+const CAPABILITIES_OID: &str = "1.3.6.1.4.1.41482.4.5";
+const OBJECT_ID_OID: &str = "1.3.6.1.4.1.41482.4.6";
+const LABEL_OID: &str = "1.3.6.1.4.1.41482.4.9";
+
+const DER_INTEGER: u8 = 0x02;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_UTF8_STRING: u8 = 0x0c;
+
+// The attributes of the object that an attestation certificate vouches for.
+#[derive(Debug)]
+pub struct Attestation {
+    pub firmware_version: String,
+    pub serial_number: u32,
+    pub origin: Origin,
+    pub domains: Domain,
+    pub capabilities: Capability,
+    pub object_id: Id,
+    pub label: String,
+}
+
+impl fmt::Display for Attestation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "firmware {}, serial {}, origin {:?}, domains {:?}, capabilities {:?}, object ID {}, label {:?}",
+            self.firmware_version,
+            self.serial_number,
+            self.origin,
+            self.domains,
+            self.capabilities,
+            self.object_id,
+            self.label
+        )
+    }
+}
+
+// Unwraps a single DER TLV with the given tag, returning its contents.
+// Everything that a YubiHSM puts in these extensions is short, so we only
+// handle the short length form.
+fn der_contents(tag: u8, der: &[u8]) -> Result<&[u8], String> {
+    match der {
+        [t, len, contents @ ..] if *t == tag && *len < 0x80 && *len as usize == contents.len() => {
+            Ok(contents)
+        }
+        _ => Err(format!("expected a DER value with tag {:#04x}", tag)),
+    }
+}
+
+// A small, non-negative DER INTEGER.
+fn der_uint(der: &[u8]) -> Result<u64, String> {
+    let contents = der_contents(DER_INTEGER, der)?;
+    if contents.is_empty() || contents[0] & 0x80 != 0 || contents.len() > 9 {
+        return Err(String::from("expected a small, non-negative INTEGER"));
+    }
+
+    Ok(contents
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte)))
+}
+
+// A BIT STRING with no unused bits, read as a big-endian unsigned integer.
+fn der_bits(der: &[u8]) -> Result<u64, String> {
+    match der_contents(DER_BIT_STRING, der)? {
+        [0, bits @ ..] if !bits.is_empty() && bits.len() <= 8 => Ok(bits
+            .iter()
+            .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte))),
+        _ => Err(String::from("expected a whole-byte BIT STRING")),
+    }
+}
+
+fn extension<'a>(cert: &'a X509Certificate, oid: &str) -> Result<&'a [u8], String> {
+    match cert
+        .iter_extensions()
+        .find(|ext| ext.oid.to_id_string() == oid)
+    {
+        Some(ext) => Ok(ext.value),
+        None => Err(format!("missing extension {}", oid)),
+    }
+}
+
+// Reads the device serial number extension, which appears in both the HSM's
+// own attestation certificate and each of its key attestations.
+pub fn serial_number(cert: &X509Certificate) -> Result<u32, String> {
+    let serial = der_uint(extension(cert, SERIAL_NUMBER_OID)?)
+        .map_err(|e| format!("bad serial number: {}", e))?;
+
+    match serial <= u64::from(u32::MAX) {
+        true => Ok(serial as u32),
+        false => Err(format!("bad serial number: {} is too large", serial)),
+    }
+}
+
+pub fn parse(cert: &X509Certificate) -> Result<Attestation, String> {
+    let firmware_version =
+        match der_contents(DER_OCTET_STRING, extension(cert, FIRMWARE_VERSION_OID)?)? {
+            [major, minor, patch] => format!("{}.{}.{}", major, minor, patch),
+            _ => return Err(String::from("bad firmware version")),
+        };
+
+    let origin = match der_bits(extension(cert, ORIGIN_OID)?) {
+        Ok(origin) if origin <= 0xff => match Origin::from_u8(origin as u8) {
+            Ok(origin) => origin,
+            Err(e) => return Err(format!("bad origin: {}", e)),
+        },
+        Ok(origin) => return Err(format!("bad origin: {:#x}", origin)),
+        Err(e) => return Err(format!("bad origin: {}", e)),
+    };
+
+    let domains = match der_bits(extension(cert, DOMAINS_OID)?) {
+        Ok(domains) if domains <= 0xffff => match Domain::from_bits(domains as u16) {
+            Some(domains) => domains,
+            None => return Err(format!("bad domains: {:#x}", domains)),
+        },
+        Ok(domains) => return Err(format!("bad domains: {:#x}", domains)),
+        Err(e) => return Err(format!("bad domains: {}", e)),
+    };
+
+    let capabilities = match der_bits(extension(cert, CAPABILITIES_OID)?) {
+        Ok(capabilities) => match Capability::from_bits(capabilities) {
+            Some(capabilities) => capabilities,
+            None => return Err(format!("bad capabilities: {:#x}", capabilities)),
+        },
+        Err(e) => return Err(format!("bad capabilities: {}", e)),
+    };
+
+    let object_id = match der_uint(extension(cert, OBJECT_ID_OID)?) {
+        Ok(object_id) if object_id <= u64::from(Id::MAX) => object_id as Id,
+        Ok(object_id) => return Err(format!("bad object ID: {}", object_id)),
+        Err(e) => return Err(format!("bad object ID: {}", e)),
+    };
+
+    let label = match der_contents(DER_UTF8_STRING, extension(cert, LABEL_OID)?) {
+        Ok(label) => String::from_utf8_lossy(label).into_owned(),
+        Err(e) => return Err(format!("bad label: {}", e)),
+    };
+
+    Ok(Attestation {
+        firmware_version,
+        serial_number: serial_number(cert)?,
+        origin,
+        domains,
+        capabilities,
+        object_id,
+        label,
+    })
+}
@@ -0,0 +1,177 @@
+// The Backend for the YubiHSM 2.
+
+use yubihsm::capability::Capability;
+use yubihsm::object::Id;
+use yubihsm::Credentials;
+
+use std::thread;
+use std::time::Duration;
+
+use super::hsm::{Device, Hsm};
+use super::role::Role;
+use super::HSM_USB_TIMEOUT;
+use super::{attest, new_auth_key, new_keypair, open_hsm, perform_factory_reset, public_key};
+use crate::backend::{Backend, Object};
+use crate::error::Error;
+use crate::journal::Journal;
+use crate::prompt::{Prompt, SecretKind};
+use crate::secret::Secret;
+
+pub struct YubiHsm<D: Device> {
+    device: D,
+    serial_number: String,
+
+    // How long to give the HSM to come back online after a factory reset.
+    reset_delay: Duration,
+
+    // The session opened under our own authentication key, once there is one.
+    client: Option<D::Client>,
+}
+
+impl<D: Device> YubiHsm<D> {
+    pub fn new(device: D, serial_number: String, reset_delay: Duration) -> Self {
+        YubiHsm {
+            device,
+            serial_number,
+            reset_delay,
+            client: None,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn client(&self) -> Result<&D::Client, Error> {
+        match &self.client {
+            Some(client) => Ok(client),
+            None => Err(Error::Other(String::from("not logged in to the HSM"))),
+        }
+    }
+
+    // Opens a session under the given authentication key, which the other
+    // operations then use.
+    pub fn log_in(&mut self, key_id: Id, password: &Secret) -> Result<(), Error> {
+        let credentials = Credentials::from_password(key_id, password.expose().as_bytes());
+        self.client = Some(open_hsm(&self.device, credentials)?);
+        Ok(())
+    }
+
+    // The HSM's own attestation certificate, which its attestations chain to.
+    pub fn attestation_cert(&self) -> Result<Vec<u8>, Error> {
+        match self.client()?.get_opaque(0) {
+            Ok(cert) => Ok(cert),
+            Err(e) => Err(Error::Attestation(format!(
+                "couldn't get the HSM's attestation cert: {}",
+                e
+            ))),
+        }
+    }
+}
+
+impl<D: Device> Backend for YubiHsm<D> {
+    type Config = D::Config;
+    type Role = Role;
+
+    fn discover(config: &D::Config) -> Result<Self, Error> {
+        let (device, serial_number) = D::discover(config)?;
+
+        Ok(YubiHsm::new(
+            device,
+            serial_number,
+            Duration::from_secs(HSM_USB_TIMEOUT),
+        ))
+    }
+
+    fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+
+    fn firmware(&mut self) -> Result<String, Error> {
+        match self.client()?.device_info() {
+            Ok(info) => Ok(format!(
+                "{}.{}.{}",
+                info.major_version, info.minor_version, info.build_version
+            )),
+            Err(e) => Err(Error::Device(format!("couldn't get device info: {}", e))),
+        }
+    }
+
+    fn reset(&mut self, prompt: &mut dyn Prompt, journal: &mut Journal) -> Result<(), Error> {
+        self.client = None;
+        perform_factory_reset(&self.device, prompt, journal)?;
+
+        println!(
+            "Success! Giving the HSM {} seconds to come back online...",
+            self.reset_delay.as_secs()
+        );
+        thread::sleep(self.reset_delay);
+
+        Ok(())
+    }
+
+    // Replaces the default authentication key with one that can only generate,
+    // attest to, and sign with the roles' keys.
+    fn set_credentials(
+        &mut self,
+        roles: &[Role],
+        prompt: &mut dyn Prompt,
+        journal: &mut Journal,
+    ) -> Result<(), Error> {
+        let signing_capabilities = roles.iter().fold(Capability::empty(), |caps, role| {
+            caps | role.key_type.signing_capability()
+        });
+        let auth_key_id = new_auth_key(&self.device, prompt, signing_capabilities, journal)?;
+        println!("Success!");
+
+        let password =
+            prompt.password(SecretKind::AuthKeyPassword, "Authentication key password")?;
+        self.log_in(auth_key_id, &password)
+    }
+
+    fn generate_key(&mut self, role: &Role) -> Result<(), Error> {
+        new_keypair(role, self.client()?)
+    }
+
+    fn public_key(&mut self, role: &Role) -> Result<Vec<u8>, Error> {
+        public_key(role, self.client()?)
+    }
+
+    fn attest(&mut self, role: &Role) -> Result<Option<Vec<u8>>, Error> {
+        attest(role, self.client()?).map(|cert| Some(cert.into_vec()))
+    }
+
+    fn list_objects(&mut self) -> Result<Vec<Object>, Error> {
+        let client = self.client()?;
+        let entries = match client.list_objects(&[]) {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Err(Error::Device(format!(
+                    "couldn't list the HSM's objects: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut objects = vec![];
+        for entry in entries {
+            let info = match client.get_object_info(entry.object_id, entry.object_type) {
+                Ok(info) => info,
+                Err(e) => {
+                    return Err(Error::Device(format!(
+                        "couldn't get info for object {}: {}",
+                        entry.object_id, e
+                    )))
+                }
+            };
+
+            objects.push(Object {
+                id: entry.object_id.to_string(),
+                kind: entry.object_type.to_string(),
+                label: info.label.to_string(),
+            });
+        }
+
+        Ok(objects)
+    }
+}
@@ -0,0 +1,359 @@
+// The command line for provisioning YubiHSMs, as run by yubihsm-provision
+// and `tuf-hsm provision --vendor yubihsm`.
+
+use clap::{App, AppSettings, Arg, ArgMatches};
+use yubihsm::connector::Connector;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::backend::YubiHsm;
+use super::devices::{self, Connection, Transport};
+use super::plan::{self, Body};
+use super::role::{self, Role};
+use super::verify::{load_certs, verify_products, yubico_ca_certs};
+use super::{KeyType, Options, BIG_SCARY_BANNER};
+use crate::backend::Backend;
+use crate::error::Error;
+use crate::prompt::{self, Prompt, SecretKind, Terminal, Unattended};
+use crate::{airgap, big_scary_banner, cli, Vendor, CEREMONY_PRODUCTS_DIR};
+
+pub fn list() -> Result<(), Error> {
+    let devices = devices::detect_hsms()?;
+    if devices.is_empty() {
+        return Err(Error::DeviceNotFound(String::from("no YubiHSMs detected")));
+    }
+
+    for device in devices.iter() {
+        let firmware =
+            match devices::device_info(&Connector::usb(&devices::usb_config(device.serial_number)))
+            {
+                Ok(info) => info.firmware,
+                Err(e) => format!("unknown ({})", e),
+            };
+
+        println!(
+            "{}\tserial number {}\tfirmware {}",
+            device.product_name, device.serial_number, firmware
+        );
+    }
+
+    Ok(())
+}
+
+// The arguments that say which keys to expect, which both provisioning and
+// verification take: either --type and --role, or a body from a ceremony plan.
+fn role_args<'a, 'b>() -> [Arg<'a, 'b>; 4] {
+    [
+        Arg::with_name("type")
+            .help("sets the key type")
+            .short("t")
+            .long("type")
+            .multiple(false)
+            .takes_value(true)
+            .possible_values(KeyType::NAMES)
+            .required_unless("plan")
+            .conflicts_with("plan"),
+        Arg::with_name("role")
+            .help(
+                "a role to generate a key for, as NAME:ID:LABEL[:TYPE] \
+                 (default: root:3:tuf-root and targets:4:tuf-targets)",
+            )
+            .long("role")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .conflicts_with("plan"),
+        Arg::with_name("plan")
+            .help("a TOML ceremony plan to take the key type and roles from")
+            .long("plan")
+            .multiple(false)
+            .takes_value(true)
+            .requires("body"),
+        Arg::with_name("body")
+            .help("the signing body ID in the ceremony plan, e.g. YubiHSM2-1")
+            .long("body")
+            .multiple(false)
+            .takes_value(true)
+            .requires("plan"),
+    ]
+}
+
+fn plan_body(matches: &ArgMatches) -> Result<Option<Body>, Error> {
+    match (matches.value_of("plan"), matches.value_of("body")) {
+        (Some(path), Some(body_id)) => {
+            let body = plan::load_body(Path::new(path), body_id).map_err(Error::Plan)?;
+            println!(
+                "Following the plan for {}: a {} with serial number {}",
+                body.id,
+                plan::VENDOR,
+                body.serial
+            );
+            Ok(Some(body))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn roles(matches: &ArgMatches, body: Option<&Body>) -> Result<Vec<Role>, String> {
+    if let Some(body) = body {
+        return Ok(body.roles.clone());
+    }
+
+    // NOTE: This unwrap is safe due to the flag restrictions in possible_values.
+    let key_type = KeyType::from_name(matches.value_of("type").unwrap()).unwrap();
+
+    let roles = match matches.values_of("role") {
+        Some(specs) => specs
+            .map(|spec| role::parse(spec, key_type))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => role::default_roles(key_type),
+    };
+
+    role::check_roles(&roles)?;
+    Ok(roles)
+}
+
+pub fn verify(matches: &ArgMatches) -> Result<(), Error> {
+    let body = plan_body(matches)?;
+    let roles = roles(matches, body.as_ref())?;
+
+    // NOTE: This unwrap is safe, since the argument is required.
+    let products_dir = Path::new(matches.value_of("products").unwrap());
+    if let Some(body) = &body {
+        body.check_serial(
+            &products_dir
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default(),
+        )
+        .map_err(Error::Plan)?;
+    }
+
+    let mut trust_anchors = yubico_ca_certs()?;
+    for ca in matches.values_of("ca").into_iter().flatten() {
+        let contents = match fs::read(ca) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Err(Error::Io(format!(
+                    "couldn't read CA certificate {}: {}",
+                    ca, e
+                )))
+            }
+        };
+
+        match load_certs(&contents)? {
+            certs if certs.is_empty() => {
+                return Err(Error::Other(format!("no certificates in {}", ca)))
+            }
+            certs => trust_anchors.extend(certs),
+        }
+    }
+
+    if trust_anchors.is_empty() {
+        return Err(Error::Attestation(String::from(
+            "this build embeds no Yubico CA certificates; supply them with --ca",
+        )));
+    }
+
+    let report = verify_products(products_dir, &roles, &trust_anchors)?;
+    println!("{}", report);
+
+    match report.passed() {
+        true => Ok(()),
+        false => Err(Error::Attestation(String::from(
+            "attestation verification failed",
+        ))),
+    }
+}
+
+// Builds the prompt: the Terminal, unless secrets or answers were supplied up
+// front for an unattended rehearsal.
+fn prompt(matches: &ArgMatches) -> Result<Box<dyn Prompt>, String> {
+    let confirm_all = matches.is_present("i-have-read-the-runbook");
+    let password_fd = matches.value_of("password-fd");
+    if !confirm_all && password_fd.is_none() {
+        return Ok(Box::new(Terminal));
+    }
+
+    if prompt::stdin_is_tty() && !matches.is_present("force-unattended") {
+        return Err(String::from(
+            "refusing to run unattended with a terminal on stdin; drop --password-fd and \
+             --i-have-read-the-runbook, or pass --force-unattended",
+        ));
+    }
+
+    let mut unattended = Unattended::new(confirm_all);
+    // NOTE: This unwrap is safe, since clap has already validated --password-fd.
+    if let Some(fd) = password_fd {
+        unattended = unattended.with_secret(
+            SecretKind::AuthKeyPassword,
+            prompt::read_secret_fd(fd.parse().unwrap())?,
+        );
+    }
+
+    Ok(Box::new(unattended))
+}
+
+// Provisioning, with its arguments but without any subcommands.
+pub fn provision_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .about("Resets a YubiHSM 2 and generates (and attests) its TUF keys")
+        .args(&role_args())
+        .arg(cli::allow_online_arg())
+        .arg(
+            Arg::with_name("der")
+                .help("also write each public key as a DER-encoded SubjectPublicKeyInfo")
+                .long("der")
+                .multiple(false),
+        )
+        .arg(cli::resume_arg())
+        .arg(
+            Arg::with_name("serial")
+                .help("the serial number of the YubiHSM to provision, if several are attached")
+                .long("serial")
+                .multiple(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connector")
+                .help("how to reach the YubiHSM: usb, or http://host:port for a yubihsm-connector")
+                .long("connector")
+                .multiple(false)
+                .takes_value(true)
+                .default_value("usb")
+                .validator(|spec| Transport::parse(&spec).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("password-fd")
+                .help(
+                    "read the authentication key password from this file descriptor \
+                     (e.g. 3, with 3<named-pipe) instead of prompting",
+                )
+                .long("password-fd")
+                .multiple(false)
+                .takes_value(true)
+                .validator(prompt::is_valid_fd),
+        )
+        .arg(cli::runbook_arg())
+        .arg(
+            Arg::with_name("force-unattended")
+                .help("allow --password-fd and --i-have-read-the-runbook with a terminal on stdin")
+                .long("force-unattended")
+                .multiple(false),
+        )
+}
+
+pub fn doctor_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    cli::doctor_app(name).arg(
+        Arg::with_name("module")
+            .help("a PKCS#11 module to check, e.g. OpenSC's for the Nitrokey HSMs")
+            .long("module")
+            .multiple(false)
+            .takes_value(true),
+    )
+}
+
+pub fn list_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name).about("lists the attached YubiHSMs' product names, serial numbers and firmware")
+}
+
+pub fn verify_app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .about("verifies the attestations in a YubiHSM's ceremony products")
+        .arg(
+            Arg::with_name("products")
+                .help("the HSM's products directory, e.g. ceremony-products/XXXXXXXXXX")
+                .required(true),
+        )
+        .args(&role_args())
+        .arg(
+            Arg::with_name("ca")
+                .help("an additional trusted Yubico CA certificate (PEM or DER)")
+                .long("ca")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+}
+
+// Provisioning, plus the list-devices, doctor, verify and verify-manifest
+// subcommands.
+pub fn app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    provision_app(name)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(list_app("list-devices"))
+        .subcommand(doctor_app("doctor"))
+        .subcommand(verify_app("verify"))
+        .subcommand(cli::verify_manifest_app("verify-manifest"))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Error> {
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        return verify(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify-manifest") {
+        return cli::verify_manifest(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("doctor") {
+        return doctor(matches);
+    }
+
+    if matches.subcommand_matches("list-devices").is_some() {
+        return list();
+    }
+
+    provision(matches)
+}
+
+pub fn doctor(matches: &ArgMatches) -> Result<(), Error> {
+    let module = matches
+        .value_of("module")
+        .map(|module| Ok(PathBuf::from(module)));
+    cli::doctor(matches, Vendor::YubiHsm, module)
+}
+
+pub fn provision(matches: &ArgMatches) -> Result<(), Error> {
+    // NOTE: Any secret file descriptors have to be read before anything else
+    // opens a file; see prompt::read_secret_fd.
+    let mut prompt = prompt(matches)?;
+
+    let body = plan_body(matches)?;
+    let roles = roles(matches, body.as_ref())?;
+
+    // Refuse to go anywhere near the HSM on a machine that looks online.
+    let airgap_override = airgap::preflight(matches.value_of("allow-online"))?;
+
+    big_scary_banner(&mut *prompt, BIG_SCARY_BANNER)?;
+
+    // Step 0: Find the YubiHSM (the attached one, the one picked with --serial,
+    // or the one behind a yubihsm-connector) and build a connector for it.
+    // We use this connector through the other steps, to avoid rediscovery.
+    // NOTE: These unwraps are safe, since the argument has a default and clap
+    // has already validated it.
+    let mut hsm = YubiHsm::<Connector>::discover(&Connection {
+        transport: Transport::parse(matches.value_of("connector").unwrap()).unwrap(),
+        serial: matches.value_of("serial").map(String::from),
+    })?;
+
+    // Refuse to touch an HSM that isn't the one that the plan is for.
+    if let Some(body) = &body {
+        body.check_serial(hsm.serial_number())
+            .map_err(Error::Plan)?;
+    }
+
+    let options = Options {
+        roles,
+        products_dir: match body {
+            Some(body) => body.products_dir,
+            None => PathBuf::from(CEREMONY_PRODUCTS_DIR),
+        },
+        write_der: matches.is_present("der"),
+        resume: matches.is_present("resume"),
+        airgap_override,
+    };
+
+    super::provision(&mut hsm, &mut *prompt, &options)
+}
@@ -0,0 +1,242 @@
+// Picking a YubiHSM out of the ones attached to the machine, and describing
+// them for list-devices.
+
+use uuid::Uuid;
+use yubihsm::connector::usb::{Devices, UsbTimeout};
+use yubihsm::connector::Connector;
+use yubihsm::device::SerialNumber;
+use yubihsm::{HttpConfig, UsbConfig};
+
+use super::HSM_USB_TIMEOUT;
+use crate::error::Error;
+
+// The Device Info command, which (unlike most commands) the YubiHSM answers
+// without an authenticated session. See:
+// https://developers.yubico.com/YubiHSM2/Commands/Device_Info.html
+const DEVICE_INFO_COMMAND: u8 = 0x06;
+const RESPONSE_FLAG: u8 = 0x80;
+const ERROR_RESPONSE: u8 = 0x7f;
+
+// NOTE(ww): YubiHSM serial numbers are displayed with leading zeros, but
+// often written down or typed without them.
+fn same_serial(a: &str, b: &str) -> bool {
+    a.trim().trim_start_matches('0') == b.trim().trim_start_matches('0')
+}
+
+// Picks one serial number from the detected ones: the one matching `wanted`
+// if given, or the only one otherwise. Anything ambiguous is an error.
+pub fn select_serial(detected: &[String], wanted: Option<&str>) -> Result<String, Error> {
+    if detected.is_empty() {
+        return Err(Error::DeviceNotFound(String::from("no YubiHSMs detected")));
+    }
+
+    let candidates: Vec<&String> = match wanted {
+        Some(wanted) => detected
+            .iter()
+            .filter(|serial| same_serial(serial, wanted))
+            .collect(),
+        None => detected.iter().collect(),
+    };
+
+    match (candidates.as_slice(), wanted) {
+        ([serial], _) => Ok((*serial).clone()),
+        ([], Some(wanted)) => Err(Error::DeviceNotFound(format!(
+            "no YubiHSM with serial number {} detected (found {})",
+            wanted,
+            detected.join(", ")
+        ))),
+        (_, Some(wanted)) => Err(Error::MultipleDevices(format!(
+            "more than one YubiHSM with serial number {} detected; refusing to continue",
+            wanted
+        ))),
+        (_, None) => Err(Error::MultipleDevices(format!(
+            "more than one YubiHSM detected ({}); pass --serial to pick one",
+            detected.join(", ")
+        ))),
+    }
+}
+
+// What the HSM says about itself in response to Device Info.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    // The firmware version, e.g. "2.0.2".
+    pub firmware: String,
+
+    // The serial number, 0-prefixed to 10 digits like the USB serial number.
+    pub serial_number: String,
+}
+
+// Parses a raw Device Info response.
+pub fn parse_device_info(response: &[u8]) -> Result<DeviceInfo, String> {
+    match response {
+        [ERROR_RESPONSE, _, _, code, ..] => {
+            Err(format!("HSM returned error {:#04x} for device info", code))
+        }
+        [code, _, _, major, minor, build, s0, s1, s2, s3, ..]
+            if *code == DEVICE_INFO_COMMAND | RESPONSE_FLAG =>
+        {
+            Ok(DeviceInfo {
+                firmware: format!("{}.{}.{}", major, minor, build),
+                serial_number: format!("{:010}", u32::from_be_bytes([*s0, *s1, *s2, *s3])),
+            })
+        }
+        _ => Err(String::from("malformed device info response")),
+    }
+}
+
+// Asks the HSM behind `connector` to describe itself.
+pub fn device_info(connector: &Connector) -> Result<DeviceInfo, String> {
+    let response = match connector.send_message(Uuid::nil(), vec![DEVICE_INFO_COMMAND, 0, 0].into())
+    {
+        Ok(response) => Vec::<u8>::from(response),
+        Err(e) => return Err(format!("couldn't get device info: {}", e)),
+    };
+
+    parse_device_info(&response)
+}
+
+// Refuses to continue unless the HSM behind `connector` reports the serial
+// number that we expect, e.g. the one it was discovered or picked by.
+pub fn check_serial(connector: &Connector, expected: &str) -> Result<DeviceInfo, String> {
+    let info = device_info(connector)?;
+
+    match same_serial(&info.serial_number, expected) {
+        true => Ok(info),
+        false => Err(format!(
+            "expected a YubiHSM with serial number {}, but the connected one reports {}; aborting",
+            expected, info.serial_number
+        )),
+    }
+}
+
+// How to reach the HSM: directly over USB, or through a yubihsm-connector
+// process (or anything else speaking its HTTP protocol).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transport {
+    Usb,
+    Http { addr: String, port: u16 },
+}
+
+// yubihsm-connector's default port.
+pub const DEFAULT_HTTP_PORT: u16 = 12345;
+
+impl Transport {
+    // Parses "usb" or "http://host[:port]".
+    pub fn parse(spec: &str) -> Result<Transport, String> {
+        if spec == "usb" {
+            return Ok(Transport::Usb);
+        }
+
+        let bad = || format!("bad connector {:?}: expected usb or http://host:port", spec);
+
+        let authority = match spec.strip_prefix("http://") {
+            Some(authority) => authority.trim_end_matches('/'),
+            None => return Err(bad()),
+        };
+
+        let (addr, port) = match authority.rsplit_once(':') {
+            Some((addr, port)) => match port.parse() {
+                Ok(port) => (addr, port),
+                Err(_) => return Err(bad()),
+            },
+            None => (authority, DEFAULT_HTTP_PORT),
+        };
+
+        if addr.is_empty() || addr.contains('/') {
+            return Err(bad());
+        }
+
+        Ok(Transport::Http {
+            addr: addr.into(),
+            port,
+        })
+    }
+}
+
+// How to find the YubiHSM to provision.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Connection {
+    pub transport: Transport,
+
+    // The HSM's serial number, if several are attached.
+    pub serial: Option<String>,
+}
+
+pub fn detect_hsms() -> Result<Devices, String> {
+    match Devices::detect(UsbTimeout::from_secs(HSM_USB_TIMEOUT)) {
+        Ok(ds) => Ok(ds),
+        Err(e) => Err(format!("HSM detection error: {}", e)),
+    }
+}
+
+pub fn usb_config(serial: SerialNumber) -> UsbConfig {
+    UsbConfig {
+        serial: Some(serial),
+        timeout_ms: HSM_USB_TIMEOUT * 1000,
+    }
+}
+
+fn find_hsm(wanted: Option<&str>) -> Result<UsbConfig, Error> {
+    let devices = detect_hsms()?;
+    let serials: Vec<String> = devices
+        .iter()
+        .map(|device| device.serial_number.to_string())
+        .collect();
+
+    let serial = select_serial(&serials, wanted)?;
+
+    // NOTE: This unwrap is safe, since select_serial only returns detected serials.
+    let device = devices
+        .iter()
+        .find(|device| device.serial_number.to_string() == serial)
+        .unwrap();
+
+    println!(
+        "Discovered a {} with serial number {}",
+        device.product_name, device.serial_number
+    );
+
+    Ok(usb_config(device.serial_number))
+}
+
+// Connects to the YubiHSM over the given transport, returning the connector
+// and the HSM's serial number. Either way, the HSM has to confirm its serial
+// number via Device Info before we go any further.
+pub fn connect(transport: &Transport, wanted: Option<&str>) -> Result<(Connector, String), Error> {
+    let (connector, serial_number) = match transport {
+        Transport::Usb => {
+            let usb_config = find_hsm(wanted)?;
+            match usb_config.serial {
+                Some(serial) => (Connector::usb(&usb_config), serial.to_string()),
+                None => {
+                    return Err(Error::from(String::from(
+                        "no serial number for USB config?",
+                    )))
+                }
+            }
+        }
+        Transport::Http { addr, port } => {
+            println!("Connecting to yubihsm-connector at {}:{}", addr, port);
+            let connector = Connector::http(&HttpConfig {
+                addr: addr.clone(),
+                port: *port,
+                timeout_ms: HSM_USB_TIMEOUT * 1000,
+            });
+
+            let info = device_info(&connector)?;
+            println!(
+                "Discovered a YubiHSM with serial number {}",
+                info.serial_number
+            );
+            (connector, wanted.unwrap_or(&info.serial_number).to_string())
+        }
+    };
+
+    let info = check_serial(&connector, &serial_number)?;
+    println!(
+        "HSM {} confirmed its serial number (firmware {})",
+        info.serial_number, info.firmware
+    );
+
+    Ok((connector, info.serial_number))
+}
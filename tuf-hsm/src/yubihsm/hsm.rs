@@ -0,0 +1,160 @@
+use yubihsm::asymmetric::{self, PublicKey};
+use yubihsm::attestation::Certificate;
+use yubihsm::authentication::{self, key::Key};
+use yubihsm::capability::Capability;
+use yubihsm::client::{Client, Error};
+use yubihsm::connector::Connector;
+use yubihsm::device;
+use yubihsm::domain::Domain;
+use yubihsm::object::{self, Id, Label, Type};
+use yubihsm::Credentials;
+
+use super::devices::{self, Connection};
+
+// The subset of YubiHSM operations that provisioning relies on.
+// This is implemented for yubihsm::Client, and exists so that the
+// provisioning flow can be driven against something other than
+// a physical YubiHSM (e.g., the yubihsm crate's MockHsm) in tests.
+pub trait Hsm {
+    fn reset_device(&self) -> Result<(), Error>;
+
+    #[allow(clippy::too_many_arguments)]
+    fn put_authentication_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: authentication::Algorithm,
+        authentication_key: Key,
+    ) -> Result<Id, Error>;
+
+    fn delete_object(&self, object_id: Id, object_type: Type) -> Result<(), Error>;
+
+    fn generate_asymmetric_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Id, Error>;
+
+    fn get_public_key(&self, key_id: Id) -> Result<PublicKey, Error>;
+
+    fn sign_attestation_certificate(
+        &self,
+        key_id: Id,
+        attestation_key_id: Option<Id>,
+    ) -> Result<Certificate, Error>;
+
+    fn get_opaque(&self, object_id: Id) -> Result<Vec<u8>, Error>;
+
+    fn list_objects(&self, filters: &[object::Filter]) -> Result<Vec<object::Entry>, Error>;
+
+    fn get_object_info(&self, object_id: Id, object_type: Type) -> Result<object::Info, Error>;
+
+    fn device_info(&self) -> Result<device::Info, Error>;
+}
+
+impl Hsm for Client {
+    fn reset_device(&self) -> Result<(), Error> {
+        Client::reset_device(self)
+    }
+
+    fn put_authentication_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: authentication::Algorithm,
+        authentication_key: Key,
+    ) -> Result<Id, Error> {
+        Client::put_authentication_key(
+            self,
+            key_id,
+            label,
+            domains,
+            capabilities,
+            delegated_capabilities,
+            algorithm,
+            authentication_key,
+        )
+    }
+
+    fn delete_object(&self, object_id: Id, object_type: Type) -> Result<(), Error> {
+        Client::delete_object(self, object_id, object_type)
+    }
+
+    fn generate_asymmetric_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Id, Error> {
+        Client::generate_asymmetric_key(self, key_id, label, domains, capabilities, algorithm)
+    }
+
+    fn get_public_key(&self, key_id: Id) -> Result<PublicKey, Error> {
+        Client::get_public_key(self, key_id)
+    }
+
+    fn sign_attestation_certificate(
+        &self,
+        key_id: Id,
+        attestation_key_id: Option<Id>,
+    ) -> Result<Certificate, Error> {
+        Client::sign_attestation_certificate(self, key_id, attestation_key_id)
+    }
+
+    fn get_opaque(&self, object_id: Id) -> Result<Vec<u8>, Error> {
+        Client::get_opaque(self, object_id)
+    }
+
+    fn list_objects(&self, filters: &[object::Filter]) -> Result<Vec<object::Entry>, Error> {
+        Client::list_objects(self, filters)
+    }
+
+    fn get_object_info(&self, object_id: Id, object_type: Type) -> Result<object::Info, Error> {
+        Client::get_object_info(self, object_id, object_type)
+    }
+
+    fn device_info(&self) -> Result<device::Info, Error> {
+        Client::device_info(self)
+    }
+}
+
+// Something we can open authenticated sessions against.
+// For real provisioning this is a yubihsm::Connector (usually a USB one),
+// since each provisioning stage opens its own session with the HSM.
+pub trait Device {
+    type Client: Hsm;
+
+    // How to find the device.
+    type Config;
+
+    // Finds the device, returning it and the HSM's serial number.
+    fn discover(config: &Self::Config) -> Result<(Self, String), crate::error::Error>
+    where
+        Self: Sized;
+
+    fn open(&self, credentials: Credentials) -> Result<Self::Client, Error>;
+}
+
+impl Device for Connector {
+    type Client = Client;
+    type Config = Connection;
+
+    fn discover(config: &Connection) -> Result<(Connector, String), crate::error::Error> {
+        devices::connect(&config.transport, config.serial.as_deref())
+    }
+
+    fn open(&self, credentials: Credentials) -> Result<Client, Error> {
+        Client::open(self.clone(), credentials, true)
+    }
+}
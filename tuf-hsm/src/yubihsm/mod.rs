@@ -0,0 +1,878 @@
+use yubihsm::asymmetric;
+use yubihsm::attestation::Certificate;
+use yubihsm::authentication::key::Key;
+use yubihsm::authentication::{Algorithm, DEFAULT_AUTHENTICATION_KEY_ID};
+use yubihsm::capability::Capability;
+use yubihsm::client;
+use yubihsm::device;
+use yubihsm::domain::Domain;
+use yubihsm::ecdsa::curve;
+use yubihsm::object::{Id, Label, Type};
+use yubihsm::Credentials;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub mod attestation;
+pub mod backend;
+pub mod cli;
+pub mod devices;
+pub mod hsm;
+pub mod plan;
+pub mod pubkey;
+pub mod resume;
+pub mod role;
+pub mod verify;
+
+use crate::backend::Backend;
+use crate::error::Error;
+use crate::journal::{self, Journal, Recovery, Stage};
+use crate::manifest;
+use crate::prompt::{Prompt, SecretKind};
+use crate::secret::Secret;
+use crate::transcript::{self, Event, Transcript};
+use crate::{airgap, confirm};
+use backend::YubiHsm;
+use hsm::{Device, Hsm};
+use role::Role;
+
+// The object IDs of the default TUF keypairs. See role::default_roles.
+pub const TUF_ROOT_KEY_ID: Id = 3;
+pub const TUF_TARGETS_KEY_ID: Id = 4;
+
+// The labels given to the default TUF keypairs.
+pub const TUF_ROOT_KEY_LABEL: &str = "tuf-root";
+pub const TUF_TARGETS_KEY_LABEL: &str = "tuf-targets";
+
+// The object ID of the authentication key that replaces the default one.
+pub const TUF_AUTH_KEY_ID: Id = 2;
+
+// The suffix for the file that we'll write the YubiHSM's internal attestation
+// certificate to. The ultimate path will be of the form XXXXXXXXXX_cert.der,
+// where XXXXXXXXXX is the 0-padded serial number of the HSM.
+pub const YUBIHSM_ATTESTATION_CERT_SUFFIX: &str = "cert.der";
+
+pub const HSM_USB_TIMEOUT: u64 = 10;
+
+pub const BIG_SCARY_BANNER: &str = r#"
+!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
+!!!                    DANGER!                    !!!
+!!!                                               !!!
+!!!   This program will reset and reprovision     !!!
+!!!   your YubiHSM 2 for TUF purposes.            !!!
+!!!                                               !!!
+!!!   Make sure to read the runbook before        !!!
+!!!   using this program. Failure to do so        !!!
+!!!   will cause PERMANENT key loss.              !!!
+!!!                                               !!!
+!!!   Hit "y" (case insensitive) to continue.     !!!
+!!!                                               !!!
+!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
+"#;
+
+const RESUME_MESSAGE: &str = r#"
+#####################################################
+###                                               ###
+###   The HSM's default "auth key" doesn't work.  ###
+###                                               ###
+###   If an earlier run was interrupted after     ###
+###   creating the new "auth key", we can pick    ###
+###   up where it left off, using that key's      ###
+###   password.                                   ###
+###                                               ###
+###   Hit "y" (case insensitive) to continue.     ###
+###                                               ###
+#####################################################
+"#;
+
+const NEW_AUTH_KEY_MESSAGE: &str = r#"
+#####################################################
+###                                               ###
+###   We're going to create a new "auth key"      ###
+###   on your YubiHSM.                            ###
+###                                               ###
+###   This "auth key" will                        ###
+###   have a password that you MUST remember      ###
+###   OR store securely and will protect the      ###
+###   TUF keys that are going to be created.      ###
+###                                               ###
+###   Hit "y" (case insensitive) to continue.     ###
+###                                               ###
+#####################################################
+"#;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+    P256,
+    P384,
+    Ed25519,
+}
+
+impl KeyType {
+    // The names accepted by --type.
+    pub const NAMES: &'static [&'static str] = &["p256", "p384", "ed25519"];
+
+    pub fn from_name(name: &str) -> Option<KeyType> {
+        match name {
+            "p256" => Some(KeyType::P256),
+            "p384" => Some(KeyType::P384),
+            "ed25519" => Some(KeyType::Ed25519),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyType::P256 => "p256",
+            KeyType::P384 => "p384",
+            KeyType::Ed25519 => "ed25519",
+        }
+    }
+
+    // The algorithm that keys of this type are generated with.
+    pub fn algorithm(self) -> asymmetric::Algorithm {
+        match self {
+            KeyType::P256 => asymmetric::Algorithm::EcP256,
+            KeyType::P384 => asymmetric::Algorithm::EcP384,
+            KeyType::Ed25519 => asymmetric::Algorithm::Ed25519,
+        }
+    }
+
+    // The only capability that keys of this type are generated with,
+    // and therefore the only one that our auth key needs to delegate.
+    pub fn signing_capability(self) -> Capability {
+        match self {
+            KeyType::P256 | KeyType::P384 => Capability::SIGN_ECDSA,
+            KeyType::Ed25519 => Capability::SIGN_EDDSA,
+        }
+    }
+}
+
+pub struct Options {
+    // The roles to generate keys for; usually role::default_roles.
+    pub roles: Vec<Role>,
+
+    // The parent directory for ceremony products; usually CEREMONY_PRODUCTS_DIR.
+    pub products_dir: PathBuf,
+
+    // Whether to also write each public key as a DER-encoded SubjectPublicKeyInfo.
+    // The PEM-encoded form is always written.
+    pub write_der: bool,
+
+    // Whether to pick up a run that the journal says was interrupted.
+    pub resume: bool,
+
+    // The operator's override of a failed air-gap preflight, if any, to be
+    // recorded with the products.
+    pub airgap_override: Option<airgap::Override>,
+}
+
+fn file_presence_checks(
+    output_dir: &Path,
+    serial_number: &str,
+    roles: &[Role],
+) -> Result<(), Error> {
+    for role in roles {
+        let filename = output_dir.join(format!(
+            "{}_{}",
+            serial_number,
+            role.file_suffix(role::ATTESTATION_FILE_SUFFIX)
+        ));
+        if filename.exists() {
+            return Err(Error::OutputExists(format!(
+                "Attestation file already exists: {:?}; aborting",
+                filename
+            )));
+        }
+    }
+
+    for role in roles {
+        for suffix in &[
+            role::PUBKEY_FILE_SUFFIX,
+            role::PEM_FILE_SUFFIX,
+            role::DER_FILE_SUFFIX,
+        ] {
+            let filename =
+                output_dir.join(format!("{}_{}", serial_number, role.file_suffix(suffix)));
+            if filename.exists() {
+                return Err(Error::OutputExists(format!(
+                    "Public key file already exists: {:?}; aborting",
+                    filename
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the HSM itself turned down the credentials that a session was opened
+// with (a wrong password, or an authentication key that isn't there), rather
+// than the session failing on the way to it.
+fn is_auth_failure(e: &client::Error) -> bool {
+    match e.kind() {
+        client::ErrorKind::AuthenticationError => true,
+        client::ErrorKind::DeviceError => matches!(
+            e.device_error(),
+            Some(device::ErrorKind::AuthenticationFailed) | Some(device::ErrorKind::ObjectNotFound)
+        ),
+        _ => false,
+    }
+}
+
+// A failure to open a session that isn't the HSM turning the credentials down.
+fn connection_error(e: client::Error) -> Error {
+    Error::Device(format!(
+        "unable to open a client connection with the HSM: {}",
+        e
+    ))
+}
+
+fn open_hsm_default_creds<D: Device>(device: &D) -> Result<D::Client, Error> {
+    // NOTE(ww): We assume here that the YubiHSM being provisioned still
+    // has its default authentication key. If this isn't the case,
+    // the user can physically perform a reset by pressing the metal contact
+    // of the HSM for 10 seconds while inserting and then continue with
+    // provisioning.
+
+    let credentials = Credentials::default();
+    match device.open(credentials) {
+        Ok(c) => Ok(c),
+        Err(e) if is_auth_failure(&e) => Err(Error::AuthFailure(format!(
+            "the HSM rejected its default authentication key: {}; try a physical reset",
+            e
+        ))),
+        Err(e) => Err(connection_error(e)),
+    }
+}
+
+fn open_hsm<D: Device>(device: &D, credentials: Credentials) -> Result<D::Client, Error> {
+    match device.open(credentials) {
+        Ok(c) => Ok(c),
+        Err(e) if is_auth_failure(&e) => Err(Error::AuthFailure(format!(
+            "the HSM rejected the authentication key: {}",
+            e
+        ))),
+        Err(e) => Err(connection_error(e)),
+    }
+}
+
+pub fn perform_factory_reset<D: Device>(
+    device: &D,
+    prompt: &mut dyn Prompt,
+    journal: &mut Journal,
+) -> Result<(), Error> {
+    let client = open_hsm_default_creds(device)?;
+
+    println!("We've successfully authenticated with the HSM!");
+    confirm(
+        prompt,
+        "Continue with factory reset? This step is IRREVERSIBLE!",
+    )?;
+    journal.record(Stage::Reset, || match client.reset_device() {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::Device(format!(
+            "reset failed: {}; try a physical reset",
+            e
+        ))),
+    })
+}
+
+pub fn new_auth_key<D: Device>(
+    device: &D,
+    prompt: &mut dyn Prompt,
+    signing_capabilities: Capability,
+    journal: &mut Journal,
+) -> Result<Id, Error> {
+    let client = open_hsm_default_creds(device)?;
+
+    println!("{}", NEW_AUTH_KEY_MESSAGE);
+    confirm(prompt, "Continue?")?;
+
+    let password = prompt.password(SecretKind::AuthKeyPassword, "Authentication key password")?;
+    let confirm_password = prompt.password(
+        SecretKind::AuthKeyPassword,
+        "Confirm your authentication key password",
+    )?;
+
+    if password != confirm_password {
+        return Err(Error::CredentialMismatch(String::from(
+            "supplied passwords don't match!",
+        )));
+    }
+
+    // These are the permissions that our new auth key will be given.
+    // In detail:
+    //   * GET_OPAQUE: Allows sessions under this key to retrieve opaque
+    //     HSM-stored data. In particular, this allows us to retrieve the opaque
+    //     built-in attestation key for attestation signing.
+    //   * GENERATE_ASYMMETRIC_KEY: Allows sessions under this key to generate
+    //     asymmetric keypairs, which we'll need to generate our keys.
+    //   * SIGN_ECDSA and/or SIGN_EDDSA, depending on the key types: Allows
+    //     sessions under this key to create digital signatures with available keys.
+    //   * SIGN_ATTESTATION_CERTIFICATE: Allows sessions under this key to
+    //     generate x509 certificates that attest to the HSM's possession
+    //     of a private key.
+    //   * DELETE_AUTHENTICATION_KEY: Allows sessions under this key to
+    //     delete authentication keys, which we'll use to delete the
+    //     factory default authentication key.
+    let auth_key_caps = Capability::GET_OPAQUE
+        | Capability::GENERATE_ASYMMETRIC_KEY
+        | signing_capabilities
+        | Capability::SIGN_ATTESTATION_CERTIFICATE
+        | Capability::DELETE_AUTHENTICATION_KEY;
+
+    let key_id = journal.record(Stage::AuthKey, || {
+        replace_auth_key(
+            device,
+            &client,
+            &password,
+            auth_key_caps,
+            signing_capabilities,
+        )
+    })?;
+
+    println!(
+        "Success! Provisioned a new authentication key as object {} and deleted the default key",
+        key_id
+    );
+
+    Ok(key_id)
+}
+
+// Puts our new authentication key on the HSM and, once it works, deletes the
+// default one.
+fn replace_auth_key<D: Device>(
+    device: &D,
+    client: &D::Client,
+    password: &Secret,
+    auth_key_caps: Capability,
+    signing_capabilities: Capability,
+) -> Result<Id, Error> {
+    let key_id = match client.put_authentication_key(
+        // This is the object ID of the authentication key being created.
+        // Since we're performing this operation right after a factory reset,
+        // ID #2 should be available for use. ID #1 is currently in use as the
+        // default authentication key.
+        TUF_AUTH_KEY_ID,
+        // This is the label associated with our authentication key.
+        // NOTE: This unwrap is safe, since the "tuf-authkey" literal is under 40 bytes.
+        Label::from_bytes(b"tuf-authkey").unwrap(),
+        // This is the set of domains associated with our authentication key.
+        // We don't make use of the YubiHSM's domain feature, so we always set this
+        // to DOM1.
+        Domain::DOM1,
+        // The set of capabilities specified above.
+        auth_key_caps,
+        // The set of delegated capabilities, i.e. the capabilities needed by
+        // the keys that we create under this authentication key. We'll only be
+        // using those keys to sign, so the signing capabilities for our key types
+        // are the only delegated capabilities required.
+        signing_capabilities,
+        // The authentication key's algorithm. This is the only available option.
+        Algorithm::YubicoAes,
+        // The password-derived key used to protect this authentication key.
+        // NOTE: The YubiHSM family uses PBKDF2 with a static salt for key
+        // derivation, so a long, random password should be used.
+        Key::derive_from_password(password.expose().as_bytes()),
+    ) {
+        Ok(id) => id,
+        Err(e) => {
+            return Err(Error::CredentialChange(format!(
+                "failed to insert new auth key: {}; reprovision",
+                e
+            )))
+        }
+    };
+
+    let credentials = Credentials::from_password(key_id, password.expose().as_bytes());
+    let client = open_hsm(device, credentials)?;
+
+    // Remove the original, default authentication key.
+    if let Err(e) = client.delete_object(DEFAULT_AUTHENTICATION_KEY_ID, Type::AuthenticationKey) {
+        return Err(Error::CredentialChange(format!(
+            "failed to delete default auth key: {}; reprovision",
+            e
+        )));
+    }
+
+    Ok(key_id)
+}
+
+// Generates the keypair for a single role.
+pub fn new_keypair<H: Hsm>(role: &Role, client: &H) -> Result<(), Error> {
+    let label = match Label::from_bytes(role.label.as_bytes()) {
+        Ok(label) => label,
+        Err(e) => {
+            return Err(Error::KeyGeneration(format!(
+                "key label invalid: {}; reprovision",
+                e
+            )))
+        }
+    };
+
+    match client.generate_asymmetric_key(
+        role.key_id,
+        label,
+        Domain::DOM1,
+        role.key_type.signing_capability(),
+        role.key_type.algorithm(),
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::KeyGeneration(format!(
+            "failed to create keypair: {}; rerun to resume",
+            e
+        ))),
+    }
+}
+
+// Returns the public key of a role's keypair: an uncompressed SEC1 point for
+// EC keys, or the 32 raw bytes for Ed25519.
+pub fn public_key<H: Hsm>(role: &Role, client: &H) -> Result<Vec<u8>, Error> {
+    let pubkey = match client.get_public_key(role.key_id) {
+        Ok(pubkey) => pubkey,
+        Err(e) => {
+            return Err(Error::KeyGeneration(format!(
+                "failed to retrieve public key for {} ({}): {}; rerun to resume",
+                role.label, role.key_id, e
+            )))
+        }
+    };
+
+    // NOTE: get_public_key returns the public key as raw bytes, meaning
+    // that it isn't in a format that most libraries can consume.
+    // We ask it nicely to convert itself into a common format, which also
+    // checks that the HSM handed back a key of the right algorithm and size.
+    // Unlike EC points, the raw bytes of an Ed25519 public key are already
+    // its standard (RFC 8032) encoding.
+    let pubkey = match role.key_type {
+        KeyType::P256 => pubkey
+            .ecdsa::<curve::NistP256>()
+            .map(|point| point.as_bytes().to_vec()),
+        KeyType::P384 => pubkey
+            .ecdsa::<curve::NistP384>()
+            .map(|point| point.as_bytes().to_vec()),
+        KeyType::Ed25519 => pubkey.ed25519().map(|key| key.as_bytes().to_vec()),
+    };
+
+    match pubkey {
+        Some(pubkey) => Ok(pubkey),
+        None => Err(Error::KeyGeneration(format!(
+            "HSM returned a malformed {:?} public key for {} ({}); reprovision",
+            role.key_type, role.label, role.key_id
+        ))),
+    }
+}
+
+// Signs an attestation certificate for a role's keypair.
+pub fn attest<H: Hsm>(role: &Role, client: &H) -> Result<Certificate, Error> {
+    // NOTE: The None parameter here indicates that we're using the default
+    // attestation key (object ID 0) to generate our attestation certificate.
+    // The default attestation key is a natural choice, since it's signed
+    // by an intermediate CA which in turn is signed by the well-known,
+    // public Yubico CA. Yubico publishes the intermediate's public cert here:
+    // https://developers.yubico.com/YubiHSM2/Concepts/E45DA5F361B091B30D8F2C6FA040DB6FEF57918E.pem
+    match client.sign_attestation_certificate(role.key_id, None) {
+        Ok(cert) => Ok(cert),
+        Err(e) => Err(Error::Attestation(format!(
+            "failed to create attestation certificate for {} ({}): {}; rerun to resume",
+            role.label, role.key_id, e
+        ))),
+    }
+}
+
+// Generates the keypair for a single role, returning its raw public key and
+// attestation certificate.
+pub fn new_keypair_with_attestation<H: Hsm>(
+    role: &Role,
+    client: &H,
+) -> Result<(Vec<u8>, Certificate), Error> {
+    new_keypair(role, client)?;
+    Ok((public_key(role, client)?, attest(role, client)?))
+}
+
+// Generates (or, for keys that already exist, re-exports and re-attests) the
+// key for each role, returning every product to write, by filename suffix.
+fn role_products<D: Device>(
+    hsm: &mut YubiHsm<D>,
+    roles: &[Role],
+    existing: &[Role],
+    write_der: bool,
+    journal: &mut Journal,
+    transcript: &mut Transcript,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut products = vec![(
+        YUBIHSM_ATTESTATION_CERT_SUFFIX.to_string(),
+        hsm.attestation_cert()?,
+    )];
+    for role in roles {
+        let pubkey = match existing.contains(role) {
+            true => {
+                println!(
+                    "Re-attesting the existing {} key ({})",
+                    role.name, role.label
+                );
+                hsm.public_key(role)?
+            }
+            false => {
+                println!("Generating the {} key ({})", role.name, role.label);
+                let pubkey = journal.record(Stage::Keygen(role.name.clone()), || {
+                    hsm.generate_key(role)?;
+                    hsm.public_key(role)
+                })?;
+                transcript.record(&Event::KeyGenerated {
+                    role: role.name.clone(),
+                    object_id: role.key_id.to_string(),
+                    algorithm: role.key_type.name().into(),
+                })?;
+                pubkey
+            }
+        };
+        let attestation =
+            journal.record(Stage::Attestation(role.name.clone()), || hsm.attest(role))?;
+        if attestation.is_some() {
+            transcript.record(&Event::AttestationProduced {
+                role: role.name.clone(),
+                object_id: role.key_id.to_string(),
+            })?;
+        }
+
+        // Encode each public key as a SubjectPublicKeyInfo, so that nobody has to
+        // convert the raw keys by hand later.
+        let spki = pubkey::spki_der(role.key_type, &pubkey);
+
+        if let Some(attestation) = attestation {
+            products.push((role.file_suffix(role::ATTESTATION_FILE_SUFFIX), attestation));
+        }
+        products.push((role.file_suffix(role::PUBKEY_FILE_SUFFIX), pubkey));
+        products.push((
+            role.file_suffix(role::PEM_FILE_SUFFIX),
+            pubkey::spki_pem(&spki).into_bytes(),
+        ));
+
+        if write_der {
+            products.push((role.file_suffix(role::DER_FILE_SUFFIX), spki));
+        }
+    }
+
+    Ok(products)
+}
+
+// Writes products to {output_dir}/{serial_number}_{suffix}. When resuming,
+// products that an earlier run already wrote are kept: attestations are
+// signed afresh each time, so they may differ, but anything derived from a
+// public key must match what's on disk exactly.
+fn write_products(
+    output_dir: &Path,
+    serial_number: &str,
+    products: Vec<(String, Vec<u8>)>,
+    resuming: bool,
+    journal: &mut Journal,
+    transcript: &mut Transcript,
+) -> Result<(), Error> {
+    for tup in products {
+        let filename = output_dir.join(format!("{}_{}", serial_number, tup.0));
+
+        if resuming && filename.exists() {
+            let is_attestation = tup.0 == YUBIHSM_ATTESTATION_CERT_SUFFIX
+                || tup.0.ends_with(role::ATTESTATION_FILE_SUFFIX);
+
+            match fs::read(&filename) {
+                Ok(contents) if contents == tup.1 || is_attestation => continue,
+                Ok(_) => {
+                    return Err(Error::ManualIntervention(format!(
+                        "{:?} doesn't match the key on the HSM; aborting",
+                        filename
+                    )))
+                }
+                Err(e) => return Err(Error::Io(format!("couldn't read {:?}: {}", filename, e))),
+            }
+        }
+
+        journal.record(Stage::Write(tup.0.clone()), || {
+            let mut file = match File::create(&filename) {
+                Ok(file) => file,
+                Err(e) => {
+                    return Err(Error::Io(format!(
+                        "attestation file creation failed: {}: {}",
+                        tup.0, e
+                    )))
+                }
+            };
+
+            match file.write_all(&tup.1).and_then(|_| file.sync_all()) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(Error::Io(format!(
+                    "attestation file I/O failed: {}: {}",
+                    tup.0, e
+                ))),
+            }
+        })?;
+        transcript.record(&Event::file_written(&filename, &tup.1))?;
+    }
+
+    Ok(())
+}
+
+// Picks up an interrupted run on an HSM whose default authentication key is
+// already gone, after showing the user what's there and asking first.
+fn resume<D: Device>(
+    hsm: &mut YubiHsm<D>,
+    prompt: &mut dyn Prompt,
+    options: &Options,
+    output_dir: &Path,
+    recovery: Option<&Recovery>,
+    journal: &mut Journal,
+    transcript: &mut Transcript,
+) -> Result<(), Error> {
+    println!("{}", RESUME_MESSAGE);
+    confirm(prompt, "Try to resume with the new authentication key?")?;
+
+    let password = prompt.password(SecretKind::AuthKeyPassword, "Authentication key password")?;
+    hsm.log_in(TUF_AUTH_KEY_ID, &password)?;
+
+    let serial_number = hsm.serial_number().to_string();
+    let state = resume::detect_state(hsm.client()?, &options.roles, output_dir, &serial_number)?;
+
+    // NOTE(ww): A key that the journal says we generated, but that isn't on
+    // the HSM anymore, means that somebody else has been at the HSM.
+    if let Some(recovery) = recovery {
+        for role in &options.roles {
+            if recovery.completed(&Stage::Keygen(role.name.clone()))
+                && !state.generated.contains(role)
+            {
+                return Err(Error::ManualIntervention(format!(
+                    "the journal says that the {} key was generated, but it isn't on the HSM; \
+                     manual intervention required",
+                    role.name
+                )));
+            }
+        }
+    }
+
+    println!("{}", state);
+    confirm(prompt, "Continue provisioning from here?")?;
+
+    let products = role_products(
+        hsm,
+        &options.roles,
+        &state.generated,
+        options.write_der,
+        journal,
+        transcript,
+    )?;
+    write_products(
+        output_dir,
+        &serial_number,
+        products,
+        true,
+        journal,
+        transcript,
+    )?;
+    crate::backend::show_objects(hsm)
+}
+
+// Decides, from the journal left by earlier runs, whether this run can go
+// ahead. Without --resume, an earlier run that died anywhere (even before
+// its first stage) is an error; with it, there has to be something to resume.
+fn check_recovery(
+    recovery: Option<&Recovery>,
+    resuming: bool,
+    journal_path: &Path,
+) -> Result<(), Error> {
+    match (recovery, resuming) {
+        (None, true) => Err(Error::Journal(format!(
+            "there's no journal at {:?}, so there's nothing to resume",
+            journal_path
+        ))),
+        (Some(recovery), true) if recovery.finished() => Err(Error::Journal(format!(
+            "the journal at {:?} shows that this HSM was already provisioned; nothing to resume",
+            journal_path
+        ))),
+        (Some(recovery), false) if !recovery.interrupted.is_empty() => {
+            Err(Error::Journal(format!(
+                "the journal at {:?} shows that an earlier run was interrupted during {}; \
+             rerun with --resume",
+                journal_path,
+                recovery.interrupted_stage().unwrap()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Runs every provisioning stage against the given device, writing the
+// ceremony products (and a journal of the stages) to
+// {products_dir}/{serial_number}/. If the default authentication key no
+// longer works, offers to resume an earlier run instead.
+pub fn provision<D: Device>(
+    hsm: &mut YubiHsm<D>,
+    prompt: &mut dyn Prompt,
+    options: &Options,
+) -> Result<(), Error> {
+    let serial_number = &hsm.serial_number().to_string();
+
+    // Refuse bad or colliding roles before anything is generated.
+    role::check_roles(&options.roles)?;
+
+    let output_dir = options.products_dir.join(serial_number);
+    if let Err(e) = fs::create_dir_all(&output_dir) {
+        return Err(Error::Io(format!(
+            "Couldn't create output directory: {}",
+            e
+        )));
+    }
+
+    let journal_path = journal::path(&output_dir, serial_number);
+    let recovery = journal::read(&journal_path)?;
+    check_recovery(recovery.as_ref(), options.resume, &journal_path)?;
+
+    // A product that was being written when the last run died may be
+    // truncated, so it has to be written again.
+    if let Some(Stage::Write(suffix)) = recovery.as_ref().and_then(|r| r.interrupted_stage()) {
+        let filename = output_dir.join(format!("{}_{}", serial_number, suffix));
+        if filename.exists() {
+            println!("Removing {:?}, which may be incomplete", filename);
+            if let Err(e) = fs::remove_file(&filename) {
+                return Err(Error::Io(format!("couldn't remove {:?}: {}", filename, e)));
+            }
+        }
+    }
+
+    if let Some(airgap_override) = &options.airgap_override {
+        airgap::record_override(&output_dir, serial_number, airgap_override)?;
+    }
+
+    let mut journal = Journal::open(&journal_path)?;
+    let mut transcript = Transcript::open(&transcript::path(&output_dir, serial_number))?;
+    transcript.record(&Event::DeviceDiscovered {
+        vendor: String::from("yubihsm"),
+        serial_number: serial_number.clone(),
+    })?;
+
+    journal.begin(&Stage::Provision)?;
+    provision_stages(
+        hsm,
+        prompt,
+        options,
+        &output_dir,
+        recovery.as_ref(),
+        &mut journal,
+        &mut transcript,
+    )?;
+    journal.end(&Stage::Provision)?;
+
+    write_manifest(hsm, &output_dir, &options.roles)
+}
+
+// Writes the manifest of everything that the run left in output_dir.
+fn write_manifest<D: Device>(
+    hsm: &mut YubiHsm<D>,
+    output_dir: &Path,
+    roles: &[Role],
+) -> Result<(), Error> {
+    let device = manifest::Device {
+        vendor: String::from("yubihsm"),
+        serial_number: hsm.serial_number().to_string(),
+        firmware: hsm.firmware()?,
+    };
+    let roles = roles
+        .iter()
+        .map(|role| manifest::Role {
+            name: role.name.clone(),
+            object_id: role.key_id.to_string(),
+            label: role.label.clone(),
+            key_type: role.key_type.name().into(),
+        })
+        .collect();
+
+    manifest::write(output_dir, device, roles).map(|_| ())
+}
+
+fn provision_stages<D: Device>(
+    hsm: &mut YubiHsm<D>,
+    prompt: &mut dyn Prompt,
+    options: &Options,
+    output_dir: &Path,
+    recovery: Option<&Recovery>,
+    journal: &mut Journal,
+    transcript: &mut Transcript,
+) -> Result<(), Error> {
+    let serial_number = hsm.serial_number().to_string();
+
+    // Stage 0: Work out whether this is a fresh run or an interrupted one. Only
+    // the HSM rejecting its default authentication key, after a run that we
+    // know about, means the latter.
+    match hsm.device().open(Credentials::default()) {
+        Ok(_) => {}
+        Err(e) if is_auth_failure(&e) && (options.resume || recovery.is_some()) => {
+            println!(
+                "Couldn't authenticate with the default authentication key: {}",
+                e
+            );
+            return resume(
+                hsm, prompt, options, output_dir, recovery, journal, transcript,
+            );
+        }
+        Err(e) if is_auth_failure(&e) => {
+            return Err(Error::AuthFailure(format!(
+                "the HSM rejected its default authentication key, and there's no journal of \
+                 an earlier run to resume: {}; try a physical reset",
+                e
+            )))
+        }
+        Err(e) => return Err(connection_error(e)),
+    }
+
+    // NOTE(ww): The default key working after an earlier run replaced it means
+    // that the HSM has been reset (or swapped) since. We can't tell which, so
+    // we don't guess.
+    if recovery.is_some_and(|recovery| recovery.completed(&Stage::AuthKey)) {
+        return Err(Error::ManualIntervention(format!(
+            "the journal says that this HSM's default authentication key was replaced, \
+             but it still works; the HSM has been reset or swapped since. \
+             Manual intervention required: move {:?} aside and start over",
+            output_dir
+        )));
+    }
+
+    file_presence_checks(output_dir, &serial_number, &options.roles)?;
+
+    // Step 1: Reset the device to a factory state.
+    hsm.reset(prompt, journal)?;
+    transcript.record(&Event::ResetConfirmed)?;
+
+    // Stage 2: Create a new authentication key, remove the default one, and
+    // log in with the new one, as long as the user supplies the correct password.
+    hsm.set_credentials(&options.roles, prompt, journal)?;
+    transcript.record(&Event::CredentialChanged {
+        credential: String::from("auth-key"),
+        object_id: Some(TUF_AUTH_KEY_ID.to_string()),
+    })?;
+
+    // Stage 3: Using the new authentication key, generate a keypair suitable
+    // for signing operations for each role. Generate an x509 attestation cert
+    // for each keypair, and extract the HSM's attestation certificate for
+    // verifying each attestation later.
+    println!("We're creating our TUF keys and attestation certificates now.");
+    let products = role_products(
+        hsm,
+        &options.roles,
+        &[],
+        options.write_der,
+        journal,
+        transcript,
+    )?;
+
+    // Write our public keys and attestation data to disk.
+    write_products(
+        output_dir,
+        &serial_number,
+        products,
+        false,
+        journal,
+        transcript,
+    )?;
+    crate::backend::show_objects(hsm)
+}
@@ -0,0 +1,191 @@
+// Ceremony plans: a TOML file that describes every HSM ("signing body") in a
+// ceremony, so that operators pick a body by ID instead of transcribing key
+// types and roles by hand. See ceremony-plan.toml at the top of the runbook.
+//
+// A plan is a list of bodies:
+//
+//   [[body]]
+//   id = "YubiHSM2-1"
+//   vendor = "yubihsm"
+//   serial = "0013200461"
+//   type = "p256"
+//   output_dir = "ceremony-products"  # optional
+//
+//   [[body.role]]                     # optional; defaults to root and targets
+//   name = "root"
+//   id = 3
+//   label = "tuf-root"
+//   type = "p384"                     # optional; defaults to the body's type
+
+use serde::Deserialize;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::role::{self, Role};
+use super::KeyType;
+use crate::CEREMONY_PRODUCTS_DIR;
+
+// The vendor that this program provisions, as spelled in plans.
+pub const VENDOR: &str = "yubihsm";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPlan {
+    body: Vec<RawBody>,
+}
+
+// NOTE(ww): Plans are shared with nitrohsm-provision, so key types and roles
+// are kept loosely typed until we know that a body is ours.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBody {
+    id: String,
+    vendor: String,
+    serial: String,
+    #[serde(rename = "type")]
+    key_type: String,
+    output_dir: Option<PathBuf>,
+    role: Option<Vec<RawRole>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRole {
+    name: String,
+    id: i64,
+    label: String,
+    #[serde(rename = "type")]
+    key_type: Option<String>,
+}
+
+// A single signing body from a plan.
+#[derive(Debug, PartialEq)]
+pub struct Body {
+    // The signing body's ID, e.g. "YubiHSM2-1".
+    pub id: String,
+
+    // The HSM's expected serial number.
+    pub serial: String,
+
+    // The body's key type, which its roles default to.
+    pub key_type: KeyType,
+
+    // The roles to generate keys for on this body.
+    pub roles: Vec<Role>,
+
+    // The parent directory for this body's ceremony products.
+    pub products_dir: PathBuf,
+}
+
+impl Body {
+    // Refuses to continue unless the detected HSM is the one that the plan expects.
+    // NOTE(ww): YubiHSM serial numbers are often written down without their
+    // leading zeros, so we ignore those.
+    pub fn check_serial(&self, serial_number: &str) -> Result<(), String> {
+        let expected = self.serial.trim().trim_start_matches('0');
+        match expected == serial_number.trim().trim_start_matches('0') {
+            true => Ok(()),
+            false => Err(format!(
+                "the plan expects {} to have serial number {}, but this HSM's is {}; aborting",
+                self.id, self.serial, serial_number
+            )),
+        }
+    }
+}
+
+fn key_type(body_id: &str, name: &str) -> Result<KeyType, String> {
+    match KeyType::from_name(name) {
+        Some(key_type) => Ok(key_type),
+        None => Err(format!(
+            "body {}: key type {:?} must be one of {}",
+            body_id,
+            name,
+            KeyType::NAMES.join(", ")
+        )),
+    }
+}
+
+fn parse_role(body_id: &str, default_key_type: KeyType, raw: &RawRole) -> Result<Role, String> {
+    let key_id = match raw.id {
+        key_id if key_id >= 0 && key_id <= i64::from(u16::MAX) => key_id as u16,
+        _ => {
+            return Err(format!(
+                "body {}: role {} has a bad object ID: {}",
+                body_id, raw.name, raw.id
+            ))
+        }
+    };
+
+    let key_type = match &raw.key_type {
+        Some(name) => key_type(body_id, name)?,
+        None => default_key_type,
+    };
+
+    Ok(Role::new(&raw.name, key_id, &raw.label, key_type))
+}
+
+// Loads the signing body with the given ID from the plan at `path`.
+pub fn load_body(path: &Path, body_id: &str) -> Result<Body, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(format!("couldn't read plan {}: {}", path.display(), e)),
+    };
+
+    parse_body(&contents, body_id)
+}
+
+// Like load_body, but from the plan's contents.
+pub fn parse_body(plan: &str, body_id: &str) -> Result<Body, String> {
+    let plan: RawPlan = match toml::from_str(plan) {
+        Ok(plan) => plan,
+        Err(e) => return Err(format!("malformed plan: {}", e)),
+    };
+
+    let mut ids = HashSet::new();
+    for body in &plan.body {
+        if !ids.insert(&body.id) {
+            return Err(format!(
+                "body {} is listed more than once in the plan",
+                body.id
+            ));
+        }
+    }
+
+    let raw = match plan.body.iter().find(|body| body.id == body_id) {
+        Some(raw) => raw,
+        None => return Err(format!("no body {} in the plan", body_id)),
+    };
+
+    if raw.vendor != VENDOR {
+        return Err(format!(
+            "the plan says that {} is a {} HSM, not a {}; aborting",
+            raw.id, raw.vendor, VENDOR
+        ));
+    }
+
+    let key_type = key_type(&raw.id, &raw.key_type)?;
+    let roles = match &raw.role {
+        Some(roles) => roles
+            .iter()
+            .map(|role| parse_role(&raw.id, key_type, role))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => role::default_roles(key_type),
+    };
+
+    if let Err(e) = role::check_roles(&roles) {
+        return Err(format!("body {}: {}", raw.id, e));
+    }
+
+    Ok(Body {
+        id: raw.id.clone(),
+        serial: raw.serial.clone(),
+        key_type,
+        roles,
+        products_dir: raw
+            .output_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(CEREMONY_PRODUCTS_DIR)),
+    })
+}
@@ -0,0 +1,76 @@
+// Helpers for turning the raw public keys that the YubiHSM hands us into
+// SubjectPublicKeyInfo (RFC 5480 and RFC 8410) public keys, in both DER and PEM.
+//
+// NOTE(ww): We only ever need to encode a few fixed structures here, so we
+// build the DER by hand rather than pulling in an ASN.1 library.
+
+use super::KeyType;
+
+// id-ecPublicKey (1.2.840.10045.2.1), DER-encoded.
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+// secp256r1 (1.2.840.10045.3.1.7), DER-encoded.
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+// secp384r1 (1.3.132.0.34), DER-encoded.
+const P384_OID: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+// id-Ed25519 (1.3.101.112), DER-encoded.
+const ED25519_OID: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+const DER_BIT_STRING: u8 = 0x03;
+const DER_SEQUENCE: u8 = 0x30;
+
+impl KeyType {
+    // The contents of the SPKI's AlgorithmIdentifier. EC keys carry their curve
+    // as a parameter, while Ed25519 keys have no parameters at all (RFC 8410 3).
+    fn algorithm_identifier(self) -> Vec<u8> {
+        match self {
+            KeyType::P256 => [EC_PUBLIC_KEY_OID, P256_OID].concat(),
+            KeyType::P384 => [EC_PUBLIC_KEY_OID, P384_OID].concat(),
+            KeyType::Ed25519 => ED25519_OID.to_vec(),
+        }
+    }
+}
+
+fn der_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag];
+
+    if contents.len() < 0x80 {
+        tlv.push(contents.len() as u8);
+    } else {
+        let len = contents.len().to_be_bytes();
+        let len = &len[len.iter().position(|b| *b != 0).unwrap()..];
+        tlv.push(0x80 | len.len() as u8);
+        tlv.extend_from_slice(len);
+    }
+
+    tlv.extend_from_slice(contents);
+    tlv
+}
+
+// Wraps a raw public key (an uncompressed EC point, or the 32 bytes of an
+// Ed25519 key) in a DER-encoded SubjectPublicKeyInfo.
+pub fn spki_der(key_type: KeyType, pubkey: &[u8]) -> Vec<u8> {
+    let algorithm = der_tlv(DER_SEQUENCE, &key_type.algorithm_identifier());
+
+    // A BIT STRING's first content byte is the number of unused bits.
+    let subject_public_key = der_tlv(DER_BIT_STRING, &[&[0x00], pubkey].concat());
+
+    der_tlv(DER_SEQUENCE, &[algorithm, subject_public_key].concat())
+}
+
+// PEM-encodes a DER-encoded SubjectPublicKeyInfo.
+pub fn spki_pem(der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+
+    let mut pem = String::from("-----BEGIN PUBLIC KEY-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        // NOTE: base64 output is always ASCII, so this can't fail.
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str("-----END PUBLIC KEY-----\n");
+
+    pem
+}
@@ -0,0 +1,209 @@
+// Picking up a provisioning run that died partway through.
+//
+// Once new_auth_key has deleted the default authentication key, the HSM can
+// no longer be reset without the new key's password (or a physical reset).
+// If provisioning stops after that point, e.g. because the machine lost power,
+// we can still log in with the new key, see which of the role keys already
+// exist, and continue from there instead of starting over.
+
+use yubihsm::object::{Origin, Type};
+use yubihsm::Algorithm;
+
+use std::fmt;
+use std::path::Path;
+
+use super::hsm::Hsm;
+use super::role::{self, Role};
+use super::{TUF_AUTH_KEY_ID, YUBIHSM_ATTESTATION_CERT_SUFFIX};
+use crate::error::Error;
+
+// What a partially provisioned HSM (and its products directory) looks like.
+#[derive(Debug, PartialEq)]
+pub struct DeviceState {
+    // The IDs of the authentication keys on the HSM.
+    pub auth_key_ids: Vec<u16>,
+
+    // The roles whose keys were already generated.
+    pub generated: Vec<Role>,
+
+    // The roles whose keys still need to be generated.
+    pub missing: Vec<Role>,
+
+    // The products that were already written, by filename suffix.
+    pub written: Vec<String>,
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = |roles: &[Role]| match roles.is_empty() {
+            true => String::from("none"),
+            false => roles
+                .iter()
+                .map(|role| format!("{} (object {})", role.name, role.key_id))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+
+        writeln!(
+            f,
+            "Authentication keys: {}",
+            self.auth_key_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        writeln!(f, "Keys already generated: {}", names(&self.generated))?;
+        writeln!(f, "Keys still to generate: {}", names(&self.missing))?;
+        write!(
+            f,
+            "Products already written: {}",
+            match self.written.is_empty() {
+                true => String::from("none"),
+                false => self.written.join(", "),
+            }
+        )
+    }
+}
+
+// Every product that provisioning can write for these roles, by filename suffix.
+fn product_suffixes(roles: &[Role]) -> Vec<(Option<&Role>, String)> {
+    let mut suffixes = vec![(None, YUBIHSM_ATTESTATION_CERT_SUFFIX.to_string())];
+    for role in roles {
+        for suffix in &[
+            role::ATTESTATION_FILE_SUFFIX,
+            role::PUBKEY_FILE_SUFFIX,
+            role::PEM_FILE_SUFFIX,
+            role::DER_FILE_SUFFIX,
+        ] {
+            suffixes.push((Some(role), role.file_suffix(suffix)));
+        }
+    }
+
+    suffixes
+}
+
+// Works out how far an earlier run got, using a session under our own
+// authentication key. Anything that provisioning wouldn't have left behind
+// is an error, since resuming on top of it could hide a tampered HSM.
+pub fn detect_state<H: Hsm>(
+    client: &H,
+    roles: &[Role],
+    output_dir: &Path,
+    serial_number: &str,
+) -> Result<DeviceState, Error> {
+    let objects = match client.list_objects(&[]) {
+        Ok(objects) => objects,
+        Err(e) => {
+            return Err(Error::Device(format!(
+                "couldn't list the HSM's objects: {}",
+                e
+            )))
+        }
+    };
+
+    let mut auth_key_ids = vec![];
+    let mut generated = vec![];
+    for object in &objects {
+        match object.object_type {
+            Type::AuthenticationKey => auth_key_ids.push(object.object_id),
+            Type::AsymmetricKey => {
+                let role = match roles.iter().find(|role| role.key_id == object.object_id) {
+                    Some(role) => role,
+                    None => {
+                        return Err(Error::ManualIntervention(format!(
+                            "found an unexpected key with object ID {}; refusing to resume",
+                            object.object_id
+                        )))
+                    }
+                };
+
+                check_generated_key(client, role)?;
+                generated.push(role.clone());
+            }
+            other => {
+                return Err(Error::ManualIntervention(format!(
+                    "found an unexpected {:?} object with ID {}; refusing to resume",
+                    other, object.object_id
+                )))
+            }
+        }
+    }
+
+    if auth_key_ids != [TUF_AUTH_KEY_ID] {
+        return Err(Error::ManualIntervention(format!(
+            "expected only authentication key {}, but found {:?}; refusing to resume",
+            TUF_AUTH_KEY_ID, auth_key_ids
+        )));
+    }
+
+    let mut written = vec![];
+    for (role, suffix) in product_suffixes(roles) {
+        if !output_dir
+            .join(format!("{}_{}", serial_number, suffix))
+            .exists()
+        {
+            continue;
+        }
+
+        // NOTE(ww): Products for a key that isn't on the HSM must be left
+        // over from some other run, and can't be trusted.
+        if let Some(role) = role {
+            if !generated.contains(role) {
+                return Err(Error::ManualIntervention(format!(
+                    "{}_{} exists, but the {} key isn't on the HSM; refusing to resume",
+                    serial_number, suffix, role.name
+                )));
+            }
+        }
+
+        written.push(suffix);
+    }
+
+    let missing = roles
+        .iter()
+        .filter(|role| !generated.contains(role))
+        .cloned()
+        .collect();
+
+    Ok(DeviceState {
+        auth_key_ids,
+        generated,
+        missing,
+        written,
+    })
+}
+
+// Checks that an existing key is the one that provisioning would have
+// generated for this role.
+fn check_generated_key<H: Hsm>(client: &H, role: &Role) -> Result<(), Error> {
+    let info = match client.get_object_info(role.key_id, Type::AsymmetricKey) {
+        Ok(info) => info,
+        Err(e) => {
+            return Err(Error::Device(format!(
+                "couldn't get info for object {}: {}",
+                role.key_id, e
+            )))
+        }
+    };
+
+    let label_matches = info.label.to_string() == role.label;
+    let as_expected = label_matches
+        && info.algorithm == Algorithm::Asymmetric(role.key_type.algorithm())
+        && info.origin == Origin::Generated
+        && info.capabilities == role.key_type.signing_capability();
+
+    match as_expected {
+        true => Ok(()),
+        false => Err(Error::ManualIntervention(format!(
+            "object {} isn't the {} key that we'd have generated \
+             (label {:?}, {:?}, {:?}, {:?}); refusing to resume",
+            role.key_id,
+            role.name,
+            info.label.to_string(),
+            info.algorithm,
+            info.origin,
+            info.capabilities
+        ))),
+    }
+}
@@ -0,0 +1,166 @@
+// The TUF roles that get a keypair on the HSM. By default these are just root
+// and targets, but PEP 458 rotations and delegated roles need more, so the
+// list can be given on the command line instead (see parse).
+
+use yubihsm::object::{Id, Label};
+
+use std::collections::HashSet;
+
+use super::{
+    KeyType, TUF_ROOT_KEY_ID, TUF_ROOT_KEY_LABEL, TUF_TARGETS_KEY_ID, TUF_TARGETS_KEY_LABEL,
+};
+
+// The suffixes for each role's products. The ultimate paths will be of the
+// form XXXXXXXXXX_{role}_{suffix}, where XXXXXXXXXX is the 0-padded serial
+// number of the HSM.
+pub const ATTESTATION_FILE_SUFFIX: &str = "attestation.der";
+pub const PUBKEY_FILE_SUFFIX: &str = "pubkey.pub";
+pub const PEM_FILE_SUFFIX: &str = "pubkey.pem";
+pub const DER_FILE_SUFFIX: &str = "pubkey.der";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Role {
+    // The TUF role's name, e.g. "root". Used to name the role's products.
+    pub name: String,
+
+    // The object ID of the role's asymmetric key on the HSM.
+    pub key_id: Id,
+
+    // The label of the role's asymmetric key on the HSM.
+    pub label: String,
+
+    // The kind of key to generate for this role.
+    pub key_type: KeyType,
+}
+
+impl Role {
+    pub fn new(name: &str, key_id: Id, label: &str, key_type: KeyType) -> Role {
+        Role {
+            name: name.into(),
+            key_id,
+            label: label.into(),
+            key_type,
+        }
+    }
+
+    // The filename suffix (after the serial number) for one of this role's products.
+    pub fn file_suffix(&self, suffix: &str) -> String {
+        format!("{}_{}", self.name, suffix)
+    }
+}
+
+// The roles that we provision when none are given.
+pub fn default_roles(key_type: KeyType) -> Vec<Role> {
+    vec![
+        Role::new("root", TUF_ROOT_KEY_ID, TUF_ROOT_KEY_LABEL, key_type),
+        Role::new(
+            "targets",
+            TUF_TARGETS_KEY_ID,
+            TUF_TARGETS_KEY_LABEL,
+            key_type,
+        ),
+    ]
+}
+
+// Parses a role given as NAME:ID:LABEL[:TYPE], e.g. "snapshot:5:tuf-snapshot:p256".
+// Roles without an explicit TYPE get `default_key_type`.
+pub fn parse(spec: &str, default_key_type: KeyType) -> Result<Role, String> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (name, key_id, label, key_type) = match parts.as_slice() {
+        [name, key_id, label] => (name, key_id, label, None),
+        [name, key_id, label, key_type] => (name, key_id, label, Some(key_type)),
+        _ => {
+            return Err(format!(
+                "bad role {:?}: expected NAME:ID:LABEL[:TYPE]",
+                spec
+            ))
+        }
+    };
+
+    let key_id = match key_id.parse::<Id>() {
+        Ok(key_id) => key_id,
+        Err(_) => {
+            return Err(format!(
+                "bad role {:?}: invalid object ID {:?}",
+                spec, key_id
+            ))
+        }
+    };
+
+    let key_type = match key_type {
+        Some(name) => match KeyType::from_name(name) {
+            Some(key_type) => key_type,
+            None => {
+                return Err(format!(
+                    "bad role {:?}: key type must be one of {}",
+                    spec,
+                    KeyType::NAMES.join(", ")
+                ))
+            }
+        },
+        None => default_key_type,
+    };
+
+    Ok(Role::new(name, key_id, label, key_type))
+}
+
+// Checks that a list of roles can be provisioned together: every role needs a
+// usable name and label, and no two roles can share a name, object ID, or label.
+// This runs before anything touches the HSM.
+pub fn check_roles(roles: &[Role]) -> Result<(), String> {
+    if roles.is_empty() {
+        return Err(String::from("no roles to provision"));
+    }
+
+    let mut names = HashSet::new();
+    let mut key_ids = HashSet::new();
+    let mut labels = HashSet::new();
+
+    for role in roles {
+        // NOTE(ww): Role names end up in filenames, so keep them boring.
+        if role.name.is_empty()
+            || !role
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "bad role name {:?}: use letters, digits, '-' and '_'",
+                role.name
+            ));
+        }
+
+        // Object ID 0 is the HSM's own attestation key.
+        if role.key_id == 0 {
+            return Err(format!("role {} can't use object ID 0", role.name));
+        }
+
+        if role.label.is_empty() {
+            return Err(format!("role {} needs a label", role.name));
+        }
+
+        if let Err(e) = Label::from_bytes(role.label.as_bytes()) {
+            return Err(format!("role {} has a bad label: {}", role.name, e));
+        }
+
+        if !names.insert(&role.name) {
+            return Err(format!("role {} is listed more than once", role.name));
+        }
+
+        if !key_ids.insert(role.key_id) {
+            return Err(format!(
+                "object ID {} is used by more than one role",
+                role.key_id
+            ));
+        }
+
+        if !labels.insert(&role.label) {
+            return Err(format!(
+                "label {:?} is used by more than one role",
+                role.label
+            ));
+        }
+    }
+
+    Ok(())
+}
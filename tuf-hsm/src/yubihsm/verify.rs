@@ -0,0 +1,454 @@
+// Offline verification of the products that `provision` writes for a YubiHSM:
+// the device's attestation certificate must chain to Yubico's CAs, and each
+// key attestation must be signed by the device's attestation certificate.
+
+use ring::digest;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::pem::Pem;
+use x509_parser::prelude::FromDer;
+use yubihsm::capability::Capability;
+use yubihsm::object::{Id, Origin};
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use super::attestation::{self, Attestation};
+use super::pubkey;
+use super::role::{self, Role};
+use super::YUBIHSM_ATTESTATION_CERT_SUFFIX;
+
+// Yubico's YubiHSM 2 root and intermediate CA certificates, as a PEM bundle.
+const YUBICO_CA_BUNDLE: &[u8] = include_bytes!("../../../assets/yubihsm2-ca-certs.pem");
+
+// The SHA-256 fingerprints (lowercase hex, of the DER) of exactly the
+// certificates in YUBICO_CA_BUNDLE. Any certificate in the bundle that isn't
+// listed here is refused, so a bad edit to the bundle can't quietly add a
+// trust anchor.
+// NOTE: Each fingerprint has to be checked against Yubico's published value
+// when its certificate is added to the bundle.
+pub const YUBICO_CA_FINGERPRINTS: &[&str] = &[];
+
+// The chain from a device certificate to Yubico's root is short; anything
+// longer than this means that the bundle is malformed.
+const MAX_CHAIN_DEPTH: usize = 4;
+
+pub struct Check {
+    // What was checked, e.g. "XXXXXXXXXX_cert.der chains to a Yubico root CA".
+    pub description: String,
+
+    // Why the check failed, if it did.
+    pub result: Result<(), String>,
+}
+
+pub struct Checksum {
+    pub filename: String,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+pub struct Report {
+    pub serial_number: String,
+    pub checks: Vec<Check>,
+
+    // The parsed contents of each key attestation, by filename.
+    pub attestations: Vec<(String, Attestation)>,
+
+    pub checksums: Vec<Checksum>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Verification of attestations for YubiHSM 2 {}:",
+            self.serial_number
+        )?;
+        for check in &self.checks {
+            match &check.result {
+                Ok(()) => writeln!(f, "  PASS: {}", check.description)?,
+                Err(e) => writeln!(f, "  FAIL: {}: {}", check.description, e)?,
+            }
+        }
+
+        writeln!(f, "Attestations:")?;
+        for (filename, attestation) in &self.attestations {
+            writeln!(f, "  {}: {}", filename, attestation)?;
+        }
+
+        writeln!(f, "Checksums:")?;
+        for checksum in &self.checksums {
+            writeln!(f, "  {}", checksum.filename)?;
+            writeln!(f, "    SHA1:   {}", checksum.sha1)?;
+            writeln!(f, "    SHA256: {}", checksum.sha256)?;
+        }
+
+        match self.passed() {
+            true => write!(f, "Result: PASS"),
+            false => write!(f, "Result: FAIL"),
+        }
+    }
+}
+
+// Parses a PEM bundle (ignoring anything outside of its certificate blocks)
+// or, failing that, a single DER-encoded certificate.
+pub fn load_certs(contents: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut certs = vec![];
+    for pem in Pem::iter_from_buffer(contents) {
+        match pem {
+            Ok(pem) if pem.label == "CERTIFICATE" => certs.push(pem.contents),
+            Ok(_) => continue,
+            Err(e) => return Err(format!("malformed PEM certificate: {:?}", e)),
+        }
+    }
+
+    if certs.is_empty() && X509Certificate::from_der(contents).is_ok() {
+        certs.push(contents.to_vec());
+    }
+
+    Ok(certs)
+}
+
+// The Yubico CA certificates embedded at build time, if any. A build with any
+// that aren't pinned in YUBICO_CA_FINGERPRINTS can't verify anything; a build
+// without any can only verify against CAs supplied with --ca.
+pub fn yubico_ca_certs() -> Result<Vec<Vec<u8>>, String> {
+    let certs = load_certs(YUBICO_CA_BUNDLE)?;
+    for cert in &certs {
+        let fingerprint = hex(digest::digest(&digest::SHA256, cert).as_ref());
+        if !YUBICO_CA_FINGERPRINTS.contains(&fingerprint.as_str()) {
+            return Err(format!(
+                "the embedded Yubico CA certificate with SHA-256 fingerprint {} isn't pinned",
+                fingerprint
+            ));
+        }
+    }
+
+    Ok(certs)
+}
+
+fn parse_cert<'a>(name: &str, der: &'a [u8]) -> Result<X509Certificate<'a>, String> {
+    match X509Certificate::from_der(der) {
+        Ok((_, cert)) => Ok(cert),
+        Err(e) => Err(format!("couldn't parse {} as X.509: {}", name, e)),
+    }
+}
+
+fn is_self_signed(cert: &X509Certificate) -> bool {
+    cert.subject() == cert.issuer() && cert.verify_signature(None).is_ok()
+}
+
+// Checks that `issuer` is a CA that signed `cert`.
+fn check_issued_by(cert: &X509Certificate, issuer: &X509Certificate) -> Result<(), String> {
+    if cert.issuer() != issuer.subject() {
+        return Err(format!(
+            "issued by \"{}\", not \"{}\"",
+            cert.issuer(),
+            issuer.subject()
+        ));
+    }
+
+    if !issuer.is_ca() {
+        return Err(format!("\"{}\" is not a CA", issuer.subject()));
+    }
+
+    match cert.verify_signature(Some(issuer.public_key())) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!(
+            "signature by \"{}\" doesn't verify: {}",
+            issuer.subject(),
+            e
+        )),
+    }
+}
+
+// Walks from `cert` up through `anchors` until we reach a self-signed root.
+//
+// NOTE(ww): We deliberately don't check validity periods here: the ceremony
+// machine is a Raspberry Pi without a real-time clock, and Yubico's
+// attestation certificates are all valid until 2071 anyway.
+fn check_chains_to_root(cert: &X509Certificate, anchors: &[X509Certificate]) -> Result<(), String> {
+    let mut current = cert;
+    for _ in 0..MAX_CHAIN_DEPTH {
+        let issuer = match anchors
+            .iter()
+            .find(|anchor| check_issued_by(current, anchor).is_ok())
+        {
+            Some(issuer) => issuer,
+            None => {
+                return Err(format!(
+                    "no trusted Yubico CA issued \"{}\"",
+                    current.subject()
+                ))
+            }
+        };
+
+        if is_self_signed(issuer) {
+            return Ok(());
+        }
+
+        current = issuer;
+    }
+
+    Err(String::from("certificate chain is too long"))
+}
+
+// Checks that an attestation certifies exactly the raw public key that we
+// wrote to disk, as a key of the role's type.
+fn check_certified_key(
+    attestation: &X509Certificate,
+    role: &Role,
+    pubkey: &[u8],
+) -> Result<(), String> {
+    let spki = attestation.public_key();
+
+    if spki.subject_public_key.data != pubkey {
+        return Err(String::from("subject public key differs"));
+    }
+
+    match spki.raw == pubkey::spki_der(role.key_type, pubkey).as_slice() {
+        true => Ok(()),
+        false => Err(format!("certified key isn't a {:?} key", role.key_type)),
+    }
+}
+
+// Checks that an attestation describes the key that we meant to generate:
+// a signing-only key, generated on this HSM, with the expected ID and label.
+fn check_attested_key(
+    attestation: &Attestation,
+    signing_capability: Capability,
+    serial_number: u32,
+    key_id: Id,
+    label: &str,
+) -> Result<(), String> {
+    let mut problems = vec![];
+
+    if attestation.serial_number != serial_number {
+        problems.push(format!(
+            "serial number is {}, not {}",
+            attestation.serial_number, serial_number
+        ));
+    }
+
+    if attestation.origin != Origin::Generated {
+        problems.push(format!("origin is {:?}, not Generated", attestation.origin));
+    }
+
+    if attestation.capabilities != signing_capability {
+        problems.push(format!(
+            "capabilities are {:?}, not {:?}",
+            attestation.capabilities, signing_capability
+        ));
+    }
+
+    if attestation.object_id != key_id {
+        problems.push(format!(
+            "object ID is {}, not {}",
+            attestation.object_id, key_id
+        ));
+    }
+
+    if attestation.label != label {
+        problems.push(format!("label is {:?}, not {:?}", attestation.label, label));
+    }
+
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems.join("; ")),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn checksum(filename: &str, contents: &[u8]) -> Checksum {
+    Checksum {
+        filename: filename.into(),
+        sha1: hex(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, contents).as_ref()),
+        sha256: hex(digest::digest(&digest::SHA256, contents).as_ref()),
+    }
+}
+
+fn read_product(products_dir: &Path, filename: &str) -> Result<Vec<u8>, String> {
+    match fs::read(products_dir.join(filename)) {
+        Ok(contents) => Ok(contents),
+        Err(e) => Err(format!("couldn't read {}: {}", filename, e)),
+    }
+}
+
+// Verifies the products in `products_dir` (i.e. {CEREMONY_PRODUCTS_DIR}/XXXXXXXXXX),
+// expecting a key for each of `roles` and using `trust_anchors` as the Yubico CAs.
+// An error is returned only if the products can't be read at all; failed checks
+// are recorded in the report.
+pub fn verify_products(
+    products_dir: &Path,
+    roles: &[Role],
+    trust_anchors: &[Vec<u8>],
+) -> Result<Report, String> {
+    let serial_number = match products_dir.file_name().and_then(|name| name.to_str()) {
+        Some(serial_number) => serial_number.to_string(),
+        None => {
+            return Err(format!(
+                "can't determine a serial number from {}",
+                products_dir.display()
+            ))
+        }
+    };
+
+    // NOTE(ww): The directory name is the 0-padded serial number, while the
+    // attestations carry it as an integer.
+    let serial: u32 = match serial_number.parse() {
+        Ok(serial) => serial,
+        Err(_) => {
+            return Err(format!(
+                "{} doesn't look like a YubiHSM serial number",
+                serial_number
+            ))
+        }
+    };
+
+    let cert_filename = format!("{}_{}", serial_number, YUBIHSM_ATTESTATION_CERT_SUFFIX);
+    let cert_der = read_product(products_dir, &cert_filename)?;
+
+    let mut checksums = vec![];
+    let mut keys = vec![];
+    for role in roles {
+        let pubkey_filename = format!(
+            "{}_{}",
+            serial_number,
+            role.file_suffix(role::PUBKEY_FILE_SUFFIX)
+        );
+        let pubkey = read_product(products_dir, &pubkey_filename)?;
+        checksums.push(checksum(&pubkey_filename, &pubkey));
+
+        let pem_filename = format!(
+            "{}_{}",
+            serial_number,
+            role.file_suffix(role::PEM_FILE_SUFFIX)
+        );
+        checksums.push(checksum(
+            &pem_filename,
+            &read_product(products_dir, &pem_filename)?,
+        ));
+
+        keys.push((
+            format!(
+                "{}_{}",
+                serial_number,
+                role.file_suffix(role::ATTESTATION_FILE_SUFFIX)
+            ),
+            pubkey_filename,
+            pubkey,
+            role,
+        ));
+    }
+
+    checksums.push(checksum(&cert_filename, &cert_der));
+
+    let mut attestation_ders = vec![];
+    for (filename, ..) in &keys {
+        let der = read_product(products_dir, filename)?;
+        checksums.push(checksum(filename, &der));
+        attestation_ders.push(der);
+    }
+
+    let mut anchors = vec![];
+    for (idx, der) in trust_anchors.iter().enumerate() {
+        anchors.push(parse_cert(&format!("Yubico CA #{}", idx), der)?);
+    }
+
+    let mut checks = vec![];
+
+    // First, make sure that the Yubico intermediates chain to a Yubico root.
+    checks.push(Check {
+        description: String::from("Yubico intermediate CAs chain to a Yubico root CA"),
+        result: match anchors.iter().filter(|a| !is_self_signed(a)).count() {
+            0 => Err(String::from("no Yubico intermediate CA certificates")),
+            _ => anchors
+                .iter()
+                .filter(|anchor| !is_self_signed(anchor))
+                .try_for_each(|anchor| check_chains_to_root(anchor, &anchors)),
+        },
+    });
+
+    // Next, the HSM's own attestation certificate against the Yubico CAs.
+    let cert = parse_cert(&cert_filename, &cert_der);
+    checks.push(Check {
+        description: format!("{} chains to a Yubico root CA", cert_filename),
+        result: cert
+            .as_ref()
+            .map_err(String::clone)
+            .and_then(|cert| check_chains_to_root(cert, &anchors)),
+    });
+
+    checks.push(Check {
+        description: format!("{} belongs to HSM {}", cert_filename, serial),
+        result: cert
+            .as_ref()
+            .map_err(String::clone)
+            .and_then(attestation::serial_number)
+            .and_then(|cert_serial| match cert_serial == serial {
+                true => Ok(()),
+                false => Err(format!("serial number is {}", cert_serial)),
+            }),
+    });
+
+    // Finally, each key's attestation: it must be signed by the HSM's attestation
+    // certificate, and must vouch for exactly the key that we wrote to disk.
+    let mut attestations = vec![];
+    for ((filename, pubkey_filename, pubkey, role), der) in keys.iter().zip(&attestation_ders) {
+        let attestation = parse_cert(filename, der);
+
+        checks.push(Check {
+            description: format!("{} is signed by {}", filename, cert_filename),
+            result: attestation.as_ref().map_err(String::clone).and_then(
+                |attestation| match &cert {
+                    Ok(cert) => check_issued_by(attestation, cert),
+                    Err(e) => Err(e.clone()),
+                },
+            ),
+        });
+
+        checks.push(Check {
+            description: format!("{} certifies the key in {}", filename, pubkey_filename),
+            result: attestation
+                .as_ref()
+                .map_err(String::clone)
+                .and_then(|attestation| check_certified_key(attestation, role, pubkey)),
+        });
+
+        let signing_capability = role.key_type.signing_capability();
+
+        let parsed = attestation
+            .as_ref()
+            .map_err(String::clone)
+            .and_then(attestation::parse);
+        checks.push(Check {
+            description: format!(
+                "{} attests to a generated {:?} key with ID {} and label {:?} on HSM {}",
+                filename, signing_capability, role.key_id, role.label, serial
+            ),
+            result: parsed.as_ref().map_err(String::clone).and_then(|parsed| {
+                check_attested_key(parsed, signing_capability, serial, role.key_id, &role.label)
+            }),
+        });
+
+        if let Ok(parsed) = parsed {
+            attestations.push((filename.clone(), parsed));
+        }
+    }
+
+    Ok(Report {
+        serial_number,
+        checks,
+        attestations,
+        checksums,
+    })
+}
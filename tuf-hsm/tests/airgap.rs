@@ -0,0 +1,117 @@
+use tuf_hsm::airgap::check;
+
+use std::fs;
+use std::path::Path;
+
+const ROUTE_HEADER: &str =
+    "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n";
+
+// The lo route that every IPv6-enabled kernel has, and which doesn't count.
+const IPV6_LO_ROUTE: &str =
+    "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+     00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n";
+
+fn write(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn interface(sys: &Path, name: &str, iface_type: &str, flags: &str, operstate: &str) {
+    write(sys, &format!("class/net/{}/type", name), iface_type);
+    write(sys, &format!("class/net/{}/flags", name), flags);
+    write(sys, &format!("class/net/{}/operstate", name), operstate);
+}
+
+fn radio(sys: &Path, entry: &str, radio_type: &str, name: &str, soft: &str, hard: &str) {
+    write(sys, &format!("class/rfkill/{}/type", entry), radio_type);
+    write(sys, &format!("class/rfkill/{}/name", entry), name);
+    write(sys, &format!("class/rfkill/{}/soft", entry), soft);
+    write(sys, &format!("class/rfkill/{}/hard", entry), hard);
+}
+
+#[test]
+fn passes_an_air_gapped_machine() {
+    let sys = tempfile::tempdir().unwrap();
+    let proc = tempfile::tempdir().unwrap();
+
+    interface(sys.path(), "lo", "772\n", "0x9\n", "unknown\n");
+    interface(sys.path(), "eth0", "1\n", "0x1002\n", "down\n");
+    radio(sys.path(), "rfkill0", "wlan\n", "phy0\n", "1\n", "0\n");
+    radio(sys.path(), "rfkill1", "bluetooth\n", "hci0\n", "0\n", "1\n");
+    write(proc.path(), "net/route", ROUTE_HEADER);
+    write(proc.path(), "net/ipv6_route", IPV6_LO_ROUTE);
+
+    assert_eq!(check(sys.path(), proc.path()), Vec::<String>::new());
+}
+
+#[test]
+fn reports_everything_that_it_finds() {
+    let sys = tempfile::tempdir().unwrap();
+    let proc = tempfile::tempdir().unwrap();
+
+    interface(sys.path(), "lo", "772\n", "0x9\n", "unknown\n");
+    interface(sys.path(), "eth0", "1\n", "0x1003\n", "up\n");
+    interface(sys.path(), "wlan0", "1\n", "0x1003\n", "dormant\n");
+    radio(sys.path(), "rfkill0", "wlan\n", "phy0\n", "0\n", "0\n");
+    radio(sys.path(), "rfkill1", "nfc\n", "nfc0\n", "0\n", "0\n");
+    write(
+        proc.path(),
+        "net/route",
+        &format!(
+            "{}eth0\t00000000\t010200C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+             eth0\t000200C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n",
+            ROUTE_HEADER
+        ),
+    );
+    write(
+        proc.path(),
+        "net/ipv6_route",
+        &format!(
+            "{}00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+             fe800000000000000000000000000001 00000400 00000001 00000000 00000003     eth0\n",
+            IPV6_LO_ROUTE
+        ),
+    );
+
+    let findings = check(sys.path(), proc.path());
+    assert_eq!(
+        findings,
+        [
+            "network interface eth0 is up (operstate up)",
+            "network interface wlan0 is up (operstate dormant)",
+            "wlan radio phy0 (rfkill0) isn't blocked by rfkill",
+            "default route via 192.0.2.1 on eth0",
+            "default IPv6 route on eth0",
+        ]
+    );
+}
+
+#[test]
+fn whatever_cant_be_checked_counts() {
+    let sys = tempfile::tempdir().unwrap();
+    let proc = tempfile::tempdir().unwrap();
+
+    interface(sys.path(), "eth0", "1\n", "garbage\n", "up\n");
+
+    let findings = check(sys.path(), proc.path());
+    assert_eq!(findings.len(), 2);
+    assert_eq!(
+        findings[0],
+        "couldn't tell whether network interface eth0 is up"
+    );
+    assert!(findings[1].starts_with("couldn't read the routes in"));
+}
+
+// Only Linux can be checked, so the preflight refuses everywhere else.
+#[cfg(not(target_os = "linux"))]
+#[test]
+fn refuses_unsupported_platforms() {
+    let err = tuf_hsm::airgap::preflight(None).unwrap_err();
+    assert!(err.contains("unsupported platform"), "{}", err);
+
+    let airgap_override = tuf_hsm::airgap::preflight(Some("checked by hand"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(airgap_override.findings.len(), 1);
+}
@@ -0,0 +1,155 @@
+use tuf_hsm::doctor::{run, table, Check, Environment, Status};
+use tuf_hsm::Vendor;
+
+use std::fs;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+const YUBIHSM_RULES: &str = include_str!("../../assets/99-yubihsm.rules");
+
+fn write(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn environment(root: &Path) -> Environment {
+    Environment {
+        sys: root.join("sys"),
+        dev: root.join("dev"),
+        udev_rules_dirs: vec![root.join("etc/udev/rules.d"), root.join("lib/udev/rules.d")],
+        pcscd_sockets: vec![root.join("run/pcscd/pcscd.comm")],
+        pkcs11_module: None,
+        output_dirs: vec![],
+    }
+}
+
+fn find<'a>(checks: &'a [Check], name: &str) -> &'a Check {
+    checks
+        .iter()
+        .find(|check| check.name == name)
+        .unwrap_or_else(|| panic!("no {} check in {:?}", name, checks))
+}
+
+#[test]
+fn passes_a_ready_machine() {
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path();
+
+    write(root, "sys/bus/usb/devices/1-1/idVendor", "1050\n");
+    write(root, "sys/bus/usb/devices/1-1/idProduct", "0030\n");
+    write(root, "sys/bus/usb/devices/1-1/busnum", "1\n");
+    write(root, "sys/bus/usb/devices/1-1/devnum", "4\n");
+    write(root, "sys/bus/usb/devices/1-2/idVendor", "046d\n");
+    write(root, "sys/bus/usb/devices/1-2/idProduct", "c31c\n");
+    write(root, "dev/bus/usb/001/004", "");
+    write(root, "lib/udev/rules.d/99-yubihsm.rules", YUBIHSM_RULES);
+    fs::create_dir_all(root.join("run/pcscd")).unwrap();
+    let _pcscd = UnixListener::bind(root.join("run/pcscd/pcscd.comm")).unwrap();
+
+    let checks = run(
+        Vendor::YubiHsm,
+        &Environment {
+            output_dirs: vec![root.join("ceremony-products")],
+            ..environment(root)
+        },
+    );
+    for name in &["YubiHSM 2 USB permissions", "YubiHSM 2 udev rule"] {
+        assert_eq!(find(&checks, name).status, Status::Pass, "{:?}", checks);
+    }
+    assert!(find(&checks, "YubiHSM 2 udev rule")
+        .detail
+        .contains("99-yubihsm.rules"));
+    assert_eq!(find(&checks, "pcscd").status, Status::Skip);
+    assert_eq!(find(&checks, "PKCS#11 module").status, Status::Skip);
+
+    let nitrokey_checks = run(Vendor::Nitrokey, &environment(root));
+    assert_eq!(find(&nitrokey_checks, "pcscd").status, Status::Pass);
+    for name in &["YubiHSM 2 USB permissions", "YubiHSM 2 udev rule"] {
+        assert_eq!(find(&nitrokey_checks, name).status, Status::Skip);
+    }
+
+    // A missing output directory is checked where it would be created.
+    let products = root.join("ceremony-products");
+    let writable = find(&checks, &format!("{} writable", products.display()));
+    assert_eq!(writable.status, Status::Pass);
+    assert!(writable.detail.contains("doesn't exist yet"));
+    find(&checks, &format!("{} filesystem", products.display()));
+    let free_space = find(&checks, &format!("{} free space", products.display()));
+    assert!(free_space.detail.contains("MiB free"));
+}
+
+#[test]
+fn fails_an_unprepared_machine() {
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path();
+
+    // The device is attached, but there's no node for it (and no rule).
+    write(root, "sys/bus/usb/devices/1-1/idVendor", "1050\n");
+    write(root, "sys/bus/usb/devices/1-1/idProduct", "0030\n");
+    write(root, "sys/bus/usb/devices/1-1/busnum", "1\n");
+    write(root, "sys/bus/usb/devices/1-1/devnum", "4\n");
+    write(
+        root,
+        "etc/udev/rules.d/50-other.rules",
+        "SUBSYSTEM==\"usb\", MODE=\"0600\"\n",
+    );
+    write(root, "not-a-module.so", "");
+
+    let env = Environment {
+        pkcs11_module: Some(Ok(root.join("not-a-module.so"))),
+        ..environment(root)
+    };
+    let statuses = |checks: &[Check]| checks.iter().map(|check| check.status).collect::<Vec<_>>();
+
+    // A YubiHSM doesn't need pcscd, but a module given with --module is checked.
+    let checks = run(Vendor::YubiHsm, &env);
+    assert_eq!(
+        statuses(&checks),
+        [Status::Fail, Status::Fail, Status::Skip, Status::Fail]
+    );
+    assert!(checks[0].detail.contains("bus/usb/001/004"));
+    assert!(checks[1].detail.contains("install assets/99-yubihsm.rules"));
+    assert!(checks[3].detail.contains("couldn't load"));
+
+    let checks = run(Vendor::Nitrokey, &env);
+    assert_eq!(
+        statuses(&checks),
+        [Status::Skip, Status::Skip, Status::Fail, Status::Fail]
+    );
+    assert!(checks[2].detail.contains("is pcscd installed and running?"));
+    assert!(checks[3].detail.contains("couldn't load"));
+
+    // Nothing attached means nothing to check.
+    let checks = run(
+        Vendor::YubiHsm,
+        &environment(root.join("nowhere").as_path()),
+    );
+    assert_eq!(checks[0].status, Status::Fail);
+    fs::create_dir_all(root.join("empty/sys/bus/usb/devices")).unwrap();
+    let checks = run(Vendor::YubiHsm, &environment(root.join("empty").as_path()));
+    assert_eq!(checks[0].status, Status::Skip);
+}
+
+#[test]
+fn formats_a_table() {
+    let checks = [
+        Check {
+            name: "pcscd".into(),
+            status: Status::Pass,
+            detail: "listening".into(),
+        },
+        Check {
+            name: "PKCS#11 module".into(),
+            status: Status::Fail,
+            detail: "not found; tried:\n  /usr/lib/opensc-pkcs11.so".into(),
+        },
+    ];
+    assert_eq!(
+        table(&checks),
+        "CHECK           RESULT  DETAILS\n\
+         pcscd           PASS    listening\n\
+         PKCS#11 module  FAIL    not found; tried:\n\
+         \x20                         /usr/lib/opensc-pkcs11.so\n"
+    );
+}
@@ -0,0 +1,53 @@
+use tuf_hsm::error::Error;
+
+fn every_error() -> Vec<Error> {
+    vec![
+        Error::UserAbort(String::from("abort")),
+        Error::DeviceNotFound(String::from("not found")),
+        Error::MultipleDevices(String::from("multiple")),
+        Error::AuthFailure(String::from("auth")),
+        Error::DeviceLocked(String::from("locked")),
+        Error::KeyGeneration(String::from("keygen")),
+        Error::Attestation(String::from("attestation")),
+        Error::OutputExists(String::from("exists")),
+        Error::Io(String::from("io")),
+        Error::Manifest(String::from("manifest")),
+        Error::Device(String::from("device")),
+        Error::CredentialMismatch(String::from("mismatch")),
+        Error::CredentialChange(String::from("change")),
+        Error::Journal(String::from("journal")),
+        Error::ManualIntervention(String::from("intervention")),
+        Error::Plan(String::from("plan")),
+        Error::Other(String::from("other")),
+    ]
+}
+
+#[test]
+fn exit_codes_are_stable() {
+    // These are documented in the README; changing them breaks runbooks.
+    assert_eq!(
+        every_error()
+            .iter()
+            .map(|e| e.exit_code())
+            .collect::<Vec<_>>(),
+        vec![10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 1]
+    );
+}
+
+#[test]
+fn every_failure_class_has_a_hint() {
+    for e in every_error() {
+        match e {
+            Error::Other(_) => assert_eq!(e.hint(), None),
+            _ => assert!(e.hint().is_some(), "no hint for {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn strings_are_other_errors() {
+    let e = Error::from(String::from("bad argument"));
+    assert_eq!(e, Error::Other(String::from("bad argument")));
+    assert_eq!(e.to_string(), "bad argument");
+    assert_eq!(e.exit_code(), 1);
+}
@@ -0,0 +1,129 @@
+use tuf_hsm::error::Error;
+use tuf_hsm::journal::{self, parse, Journal, Recovery, Stage};
+
+use std::fs;
+
+#[test]
+fn round_trips_stages() {
+    for stage in &[
+        Stage::Provision,
+        Stage::Reset,
+        Stage::AuthKey,
+        Stage::Keygen("root".into()),
+        Stage::Attestation("targets".into()),
+        Stage::Write("root_pubkey.pub".into()),
+    ] {
+        assert_eq!(stage.to_string().parse::<Stage>().unwrap(), *stage);
+    }
+
+    assert!("keygen".parse::<Stage>().is_err());
+    assert!("reset root".parse::<Stage>().is_err());
+}
+
+#[test]
+fn parses_finished_and_interrupted_runs() {
+    let finished = parse(
+        "1 begin provision\n2 begin reset\n3 end reset\n4 begin keygen root\n\
+         5 end keygen root\n6 end provision\n",
+    )
+    .unwrap();
+    assert!(finished.finished());
+    assert_eq!(finished.interrupted_stage(), None);
+    assert!(finished.completed(&Stage::Keygen("root".into())));
+
+    let interrupted =
+        parse("1 begin provision\n2 begin reset\n3 end reset\n4 begin keygen root\n").unwrap();
+    assert!(!interrupted.finished());
+    assert_eq!(
+        interrupted.interrupted,
+        [Stage::Provision, Stage::Keygen("root".into())]
+    );
+    assert_eq!(
+        interrupted.interrupted_stage(),
+        Some(&Stage::Keygen("root".into()))
+    );
+    assert!(interrupted.completed(&Stage::Reset));
+
+    assert_eq!(parse("").unwrap(), Recovery::default());
+}
+
+#[test]
+fn a_run_that_only_began_is_interrupted() {
+    // e.g. a run that died between journaling its start and resetting the HSM.
+    let recovery = parse("1 begin provision\n").unwrap();
+    assert!(!recovery.finished());
+    assert_eq!(recovery.interrupted, [Stage::Provision]);
+    assert_eq!(recovery.interrupted_stage(), Some(&Stage::Provision));
+    assert!(recovery.completed.is_empty());
+}
+
+#[test]
+fn a_new_run_supersedes_the_last() {
+    let recovery = parse(
+        "1 begin provision\n2 begin auth-key\n3 begin provision\n4 begin write cert.der\n\
+         5 end write cert.der\n",
+    )
+    .unwrap();
+    assert_eq!(recovery.interrupted, [Stage::Provision]);
+    assert_eq!(recovery.completed, [Stage::Write("cert.der".into())]);
+}
+
+#[test]
+fn rejects_malformed_journals() {
+    for (contents, expected) in &[
+        (
+            "begin reset\n",
+            "malformed journal line 1: expected TIME EVENT STAGE",
+        ),
+        (
+            "1 begin provision\nx begin reset\n",
+            "malformed journal line 2",
+        ),
+        ("1 begin frobnicate\n", "unknown stage \"frobnicate\""),
+        ("1 finish reset\n", "unknown event"),
+        (
+            "1 begin reset\n2 end auth-key\n",
+            "end of a stage that wasn't begun",
+        ),
+    ] {
+        let err = parse(contents).unwrap_err();
+        assert!(err.contains(expected), "unexpected error: {}", err);
+    }
+}
+
+#[test]
+fn appends_to_the_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = journal::path(dir.path(), "0123456789");
+    assert!(path.ends_with("0123456789_journal.log"));
+    assert_eq!(journal::read(&path).unwrap(), None);
+
+    let mut journal = Journal::open(&path).unwrap();
+    journal.begin(&Stage::Provision).unwrap();
+    journal
+        .record(Stage::Reset, || Ok::<(), Error>(()))
+        .unwrap();
+    assert_eq!(
+        journal
+            .record(Stage::AuthKey, || Err::<(), _>(String::from("nope")))
+            .unwrap_err(),
+        Error::Other(String::from("nope"))
+    );
+
+    // A failed stage is left begun, exactly like an interrupted one.
+    let recovery = journal::read(&path).unwrap().unwrap();
+    assert_eq!(recovery.completed, [Stage::Reset]);
+    assert_eq!(recovery.interrupted, [Stage::Provision, Stage::AuthKey]);
+
+    // A journal that can't be made sense of is a journal error, not just any.
+    fs::write(dir.path().join("garbled_journal.log"), "begin provision\n").unwrap();
+    assert!(matches!(
+        journal::read(&dir.path().join("garbled_journal.log")),
+        Err(Error::Journal(ref msg)) if msg.starts_with("malformed journal line 1: ")
+    ));
+
+    // Reopening appends rather than truncating.
+    drop(journal);
+    Journal::open(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+}
@@ -0,0 +1,206 @@
+use tuf_hsm::error::Error;
+use tuf_hsm::manifest::{self, Device, Manifest, Product, Role};
+
+use std::fs;
+use std::path::Path;
+
+const SERIAL: &str = "0123456789";
+
+fn device() -> Device {
+    Device {
+        vendor: String::from("yubihsm"),
+        serial_number: String::from(SERIAL),
+        firmware: String::from("2.2.0"),
+    }
+}
+
+fn roles() -> Vec<Role> {
+    vec![Role {
+        name: String::from("root"),
+        object_id: String::from("3"),
+        label: String::from("tuf-root"),
+        key_type: String::from("p256"),
+    }]
+}
+
+// Writes some products, and a manifest of them.
+fn products(dir: &Path) -> Manifest {
+    fs::write(dir.join(format!("{}_root_pubkey.pub", SERIAL)), b"abc").unwrap();
+    fs::write(dir.join(format!("{}_journal.log", SERIAL)), b"").unwrap();
+    fs::write(dir.join(format!("{}_transcript.jsonl", SERIAL)), b"").unwrap();
+    manifest::write(dir, device(), roles()).unwrap()
+}
+
+fn failures(dir: &Path, manifest: &Manifest) -> Vec<String> {
+    manifest::verify(dir, manifest)
+        .unwrap()
+        .checks
+        .into_iter()
+        .filter_map(|check| match check.result {
+            Ok(()) => None,
+            Err(e) => Some(format!("{}: {}", check.description, e)),
+        })
+        .collect()
+}
+
+#[test]
+fn digests_products() {
+    assert_eq!(
+        Product::new("abc", b"abc"),
+        Product {
+            file: String::from("abc"),
+            size: 3,
+            sha256: String::from(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            ),
+            sha512: String::from(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            ),
+        }
+    );
+}
+
+#[test]
+fn lists_every_product_but_itself_and_the_logs() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = products(dir.path());
+
+    assert_eq!(
+        manifest
+            .products
+            .iter()
+            .map(|product| product.file.as_str())
+            .collect::<Vec<_>>(),
+        vec!["0123456789_root_pubkey.pub"]
+    );
+    assert_eq!(manifest.products[0].size, 3);
+
+    // Writing it again (e.g. after a resumed run) still leaves it out.
+    let again = manifest::write(dir.path(), device(), roles()).unwrap();
+    assert_eq!(again, manifest);
+    assert_eq!(
+        Manifest::read(&manifest::path(dir.path(), SERIAL)).unwrap(),
+        manifest
+    );
+}
+
+#[test]
+fn verifies_a_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    products(dir.path());
+
+    let copy = tempfile::tempdir().unwrap();
+    for entry in fs::read_dir(dir.path()).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), copy.path().join(entry.file_name())).unwrap();
+    }
+
+    let found = manifest::find(copy.path()).unwrap();
+    assert_eq!(found, manifest::path(copy.path(), SERIAL));
+
+    let report = manifest::verify(copy.path(), &Manifest::read(&found).unwrap()).unwrap();
+    assert!(report.passed());
+    assert_eq!(report.checks.len(), 1);
+    assert!(report
+        .to_string()
+        .starts_with("Verification of the manifest for yubihsm 0123456789 (firmware 2.2.0):\n"));
+}
+
+#[test]
+fn catches_altered_products() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = products(dir.path());
+    let pubkey = dir.path().join(format!("{}_root_pubkey.pub", SERIAL));
+
+    // Same size, different contents.
+    fs::write(&pubkey, b"abd").unwrap();
+    let failed = failures(dir.path(), &manifest);
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].starts_with("0123456789_root_pubkey.pub matches the manifest: SHA-256 is "));
+
+    fs::write(&pubkey, b"abcd").unwrap();
+    assert_eq!(
+        failures(dir.path(), &manifest),
+        vec!["0123456789_root_pubkey.pub matches the manifest: size is 4, but the manifest says 3"]
+    );
+
+    fs::remove_file(&pubkey).unwrap();
+    let failed = failures(dir.path(), &manifest);
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].starts_with(
+        "0123456789_root_pubkey.pub matches the manifest: couldn't read 0123456789_root_pubkey.pub"
+    ));
+}
+
+#[test]
+fn catches_unexpected_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = products(dir.path());
+
+    fs::write(dir.path().join("extra.pem"), b"extra").unwrap();
+    assert_eq!(
+        failures(dir.path(), &manifest),
+        vec!["extra.pem is listed in the manifest: unexpected file"]
+    );
+}
+
+#[test]
+fn ignores_appended_logs() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = products(dir.path());
+
+    for log in &["journal.log", "transcript.jsonl"] {
+        let path = dir.path().join(format!("{}_{}", SERIAL, log));
+        fs::write(&path, b"appended after the manifest\n").unwrap();
+    }
+    assert!(failures(dir.path(), &manifest).is_empty());
+}
+
+#[test]
+fn refuses_paths_outside_the_products() {
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("secret"), b"abc").unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let products_dir = dir.path().join(SERIAL);
+    fs::create_dir(&products_dir).unwrap();
+
+    let absolute = outside.path().join("secret");
+    let mut manifest = products(&products_dir);
+    for file in &[
+        String::from("../../secret"),
+        String::from(".."),
+        String::from("sub/../0123456789_root_pubkey.pub"),
+        absolute.to_string_lossy().into_owned(),
+    ] {
+        manifest.products = vec![Product::new(file, b"abc")];
+        let failed = failures(&products_dir, &manifest);
+        assert!(
+            failed[0].ends_with(&format!("{:?} isn't a plain filename", file)),
+            "{:?}",
+            failed
+        );
+    }
+}
+
+#[test]
+fn finds_exactly_one_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(matches!(
+        manifest::find(dir.path()),
+        Err(Error::Manifest(ref msg)) if msg.starts_with("no *_manifest.json in ")
+    ));
+
+    products(dir.path());
+    fs::write(dir.path().join("9876543210_manifest.json"), b"{}").unwrap();
+    assert!(matches!(
+        manifest::find(dir.path()),
+        Err(Error::Manifest(ref msg)) if msg.starts_with("more than one manifest in ")
+    ));
+
+    assert!(matches!(
+        Manifest::read(&dir.path().join("9876543210_manifest.json")),
+        Err(Error::Manifest(ref msg)) if msg.starts_with("malformed manifest ")
+    ));
+}
@@ -0,0 +1,49 @@
+// Tests for locating the PKCS#11 module. These don't load the module.
+
+use tuf_hsm::pkcs11::{find_pkcs11_module, OPENSC_PKCS11_SO_PATHS, PKCS11_MODULE_ENV};
+
+use std::env;
+use std::fs;
+
+#[test]
+fn explicit_module_takes_precedence() {
+    let dir = tempfile::tempdir().unwrap();
+    let module = dir.path().join("libfake-pkcs11.so");
+    fs::write(&module, b"").unwrap();
+
+    assert_eq!(find_pkcs11_module(Some(&module)).unwrap(), module);
+
+    let missing = dir.path().join("missing.so");
+    let err = find_pkcs11_module(Some(&missing)).unwrap_err();
+    assert!(err.contains("missing.so"));
+    assert!(err.contains("--module"));
+
+    // A directory isn't a module.
+    assert!(find_pkcs11_module(Some(dir.path())).is_err());
+}
+
+#[test]
+fn environment_overrides_search() {
+    let dir = tempfile::tempdir().unwrap();
+    let module = dir.path().join("libfake-pkcs11.so");
+    fs::write(&module, b"").unwrap();
+
+    env::set_var(PKCS11_MODULE_ENV, &module);
+    assert_eq!(find_pkcs11_module(None).unwrap(), module);
+
+    env::set_var(PKCS11_MODULE_ENV, dir.path().join("missing.so"));
+    let err = find_pkcs11_module(None).unwrap_err();
+    assert!(err.contains(PKCS11_MODULE_ENV));
+
+    env::remove_var(PKCS11_MODULE_ENV);
+
+    // With nothing set, we either find OpenSC or list everywhere we looked.
+    match find_pkcs11_module(None) {
+        Ok(module) => assert!(OPENSC_PKCS11_SO_PATHS.contains(&module.to_str().unwrap())),
+        Err(e) => {
+            for path in OPENSC_PKCS11_SO_PATHS {
+                assert!(e.contains(path), "{} not listed", path);
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Tests for resetting a YubiKey's PIV application, against a mock card that
+// keeps the PIN and PUK retry counters like a YubiKey does.
+
+use tuf_hsm::error::Error;
+use tuf_hsm::pkcs11::piv::{reset_card, Card};
+
+const RETRIES: u8 = 3;
+
+fn padded(pin: &[u8]) -> Vec<u8> {
+    let mut padded = pin.to_vec();
+    padded.resize(8, 0xff);
+    padded
+}
+
+struct MockCard {
+    serial: u32,
+    pin: Vec<u8>,
+    puk: Vec<u8>,
+    pin_retries: u8,
+    puk_retries: u8,
+    resets: usize,
+    refuse_reset: bool,
+}
+
+impl MockCard {
+    fn new(serial: u32) -> Self {
+        MockCard {
+            serial,
+            pin: padded(b"654321"),
+            puk: padded(b"87654321"),
+            pin_retries: RETRIES,
+            puk_retries: RETRIES,
+            resets: 0,
+            refuse_reset: false,
+        }
+    }
+
+    // Checks a PIN or PUK guess against its retry counter.
+    fn check(guess: &[u8], secret: &[u8], retries: &mut u8) -> u16 {
+        if *retries == 0 {
+            0x6983
+        } else if guess == secret {
+            *retries = RETRIES;
+            0x9000
+        } else {
+            *retries -= 1;
+            0x63c0 | *retries as u16
+        }
+    }
+}
+
+impl Card for MockCard {
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, String> {
+        let (data, sw) = match apdu {
+            [0x00, 0xa4, 0x04, 0x00, 0x05, 0xa0, 0x00, 0x00, 0x03, 0x08] => (vec![], 0x9000),
+            [0x00, 0xf8, 0x00, 0x00] => (self.serial.to_be_bytes().to_vec(), 0x9000),
+            [0x00, 0x20, 0x00, 0x80, 0x08, pin @ ..] => {
+                (vec![], Self::check(pin, &self.pin, &mut self.pin_retries))
+            }
+            [0x00, 0x24, 0x00, 0x81, 0x10, puks @ ..] => {
+                let sw = Self::check(&puks[..8], &self.puk, &mut self.puk_retries);
+                if sw == 0x9000 {
+                    self.puk = puks[8..].to_vec();
+                }
+                (vec![], sw)
+            }
+            [0x00, 0xfb, 0x00, 0x00] if self.refuse_reset => (vec![], 0x6985),
+            [0x00, 0xfb, 0x00, 0x00] if self.pin_retries == 0 && self.puk_retries == 0 => {
+                let serial = self.serial;
+                *self = MockCard {
+                    pin: padded(b"123456"),
+                    puk: padded(b"12345678"),
+                    resets: self.resets + 1,
+                    ..MockCard::new(serial)
+                };
+                (vec![], 0x9000)
+            }
+            [0x00, 0xfb, 0x00, 0x00] => (vec![], 0x6985),
+            _ => (vec![], 0x6d00),
+        };
+
+        Ok([data, sw.to_be_bytes().to_vec()].concat())
+    }
+}
+
+#[test]
+fn resets_and_blocks_the_puk() {
+    let mut card = MockCard::new(12345678);
+    card.pin_retries = 1;
+
+    // YKCS11 pads the token's serial number, like other PKCS#11 modules.
+    reset_card(&mut card, "12345678        ").unwrap();
+    assert_eq!(card.resets, 1);
+    assert_eq!(card.pin, padded(b"123456"));
+    assert_eq!(card.pin_retries, RETRIES);
+    assert_eq!(card.puk_retries, 0);
+}
+
+#[test]
+fn refuses_other_yubikeys() {
+    let mut card = MockCard::new(1234);
+    assert!(matches!(
+        reset_card(&mut card, "5678"),
+        Err(Error::DeviceNotFound(_))
+    ));
+
+    // Nothing was guessed.
+    assert_eq!(card.pin_retries, RETRIES);
+    assert_eq!(card.puk_retries, RETRIES);
+}
+
+#[test]
+fn reports_refused_resets() {
+    let mut card = MockCard::new(1234);
+    card.refuse_reset = true;
+    assert!(matches!(
+        reset_card(&mut card, "1234"),
+        Err(Error::Device(_))
+    ));
+    assert_eq!(card.resets, 0);
+}
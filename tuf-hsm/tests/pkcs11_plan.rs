@@ -0,0 +1,122 @@
+use tuf_hsm::pkcs11::plan::{load_body, parse_body};
+use tuf_hsm::pkcs11::profile::{NITROKEY_PROFILE, YKCS11_PROFILE};
+use tuf_hsm::pkcs11::role::{default_roles, Role};
+use tuf_hsm::pkcs11::KeyType;
+
+use std::path::{Path, PathBuf};
+
+const PLAN: &str = r#"
+[[body]]
+id = "YubiHSM2-1"
+vendor = "yubihsm"
+serial = "0013200461"
+type = "ed25519"
+
+[[body]]
+id = "Nitrokey HSM-4"
+vendor = "nitrokey"
+serial = "DENK0102947"
+type = "p384"
+
+[[body]]
+id = "Nitrokey HSM-5"
+vendor = "nitrokey"
+serial = "DENK0103189"
+type = "p256"
+output_dir = "/media/ceremony-products"
+
+[[body.role]]
+name = "root"
+id = 0x12
+label = "root"
+type = "p384"
+
+[[body.role]]
+name = "bins"
+id = 0x20
+label = "bins"
+
+[[body]]
+id = "YubiKey-7"
+vendor = "ykcs11"
+serial = "12345678"
+type = "p256"
+"#;
+
+#[test]
+fn loads_bodies() {
+    let body = parse_body(PLAN, "Nitrokey HSM-4").unwrap();
+    assert_eq!(body.profile, NITROKEY_PROFILE);
+    assert_eq!(body.key_type, KeyType::P384);
+    assert_eq!(body.roles, default_roles(KeyType::P384));
+    assert_eq!(body.products_dir, PathBuf::from("ceremony-products"));
+
+    let body = parse_body(PLAN, "Nitrokey HSM-5").unwrap();
+    assert_eq!(
+        body.roles,
+        [
+            Role::new("root", 0x12, "root", KeyType::P384),
+            Role::new("bins", 0x20, "bins", KeyType::P256),
+        ]
+    );
+    assert_eq!(body.products_dir, PathBuf::from("/media/ceremony-products"));
+
+    let body = parse_body(PLAN, "YubiKey-7").unwrap();
+    assert_eq!(body.profile, YKCS11_PROFILE);
+    assert_eq!(body.roles, default_roles(KeyType::P256));
+}
+
+#[test]
+fn checks_serial_numbers() {
+    let body = parse_body(PLAN, "Nitrokey HSM-4").unwrap();
+    body.check_serial("DENK0102947").unwrap();
+    body.check_serial("DENK0102947     ").unwrap();
+    assert_eq!(
+        body.check_serial("DENK0103189").unwrap_err(),
+        "the plan expects Nitrokey HSM-4 to have serial number DENK0102947, \
+         but this HSM's is DENK0103189; aborting"
+    );
+}
+
+#[test]
+fn refuses_other_vendors_and_bad_roles() {
+    // NOTE: YubiHSM bodies are refused before their key types are looked at.
+    assert_eq!(
+        parse_body(PLAN, "YubiHSM2-1").unwrap_err(),
+        "the plan says that YubiHSM2-1 is a yubihsm HSM, not a PKCS#11 token \
+         (nitrokey, ykcs11, softhsm2-test, generic); aborting"
+    );
+
+    assert_eq!(
+        parse_body(
+            "[[body]]\nid = \"a\"\nvendor = \"nitrokey\"\nserial = \"1\"\ntype = \"p256\"\n\
+             [[body.role]]\nname = \"root\"\nid = 0x100\nlabel = \"root\"\n",
+            "a"
+        )
+        .unwrap_err(),
+        "body a: role root has a bad key ID: 256"
+    );
+
+    // YKCS11 only takes key IDs 05 to 18.
+    assert_eq!(
+        parse_body(
+            "[[body]]\nid = \"a\"\nvendor = \"ykcs11\"\nserial = \"1\"\ntype = \"p256\"\n\
+             [[body.role]]\nname = \"root\"\nid = 0x20\nlabel = \"root\"\n",
+            "a"
+        )
+        .unwrap_err(),
+        "body a: role root: key ID 20 is outside of ykcs11 tokens' range (05 to 18)"
+    );
+}
+
+#[test]
+fn loads_the_runbook_plan() {
+    let plan = Path::new(env!("CARGO_MANIFEST_DIR")).join("../ceremony-plan.toml");
+    for (body_id, key_type) in &[
+        ("Nitrokey HSM-4", KeyType::P384),
+        ("Nitrokey HSM-5", KeyType::P256),
+        ("Nitrokey HSM-6", KeyType::P384),
+    ] {
+        assert_eq!(load_body(&plan, body_id).unwrap().key_type, *key_type);
+    }
+}
@@ -0,0 +1,110 @@
+use pkcs11::types;
+
+use tuf_hsm::pkcs11::piv::{DEFAULT_MANAGEMENT_KEY, DEFAULT_PIN};
+use tuf_hsm::pkcs11::profile::{
+    Manufacturer, Profile, Reset, GENERIC_PROFILE, NITROKEY_PROFILE, PROFILES,
+    SOFTHSM2_TEST_PROFILE, YKCS11_PROFILE,
+};
+use tuf_hsm::pkcs11::role::{default_roles, Role};
+use tuf_hsm::pkcs11::KeyType;
+
+#[test]
+fn looks_up_profiles_by_name() {
+    assert_eq!(Profile::NAMES.len(), PROFILES.len());
+    for name in Profile::NAMES {
+        assert_eq!(Profile::from_name(name).unwrap().name, *name);
+    }
+    assert_eq!(Profile::from_name("yubihsm"), None);
+}
+
+#[test]
+fn matches_manufacturers() {
+    assert!(Manufacturer::Exact("Nitrokey").matches("Nitrokey                        "));
+    assert!(!Manufacturer::Exact("Nitrokey").matches("Nitrokey GmbH"));
+    assert!(Manufacturer::Prefix("Yubico").matches("Yubico (www.yubico.com)"));
+    assert!(!Manufacturer::Prefix("Yubico").matches("SoftHSM project"));
+    assert!(Manufacturer::Any.matches(""));
+
+    assert!(!NITROKEY_PROFILE.manufacturer.matches("SoftHSM project"));
+    assert!(SOFTHSM2_TEST_PROFILE
+        .manufacturer
+        .matches("SoftHSM project"));
+    assert!(GENERIC_PROFILE.manufacturer.matches("Some Other Vendor"));
+    assert!(YKCS11_PROFILE
+        .manufacturer
+        .matches("Yubico (www.yubico.com)     "));
+}
+
+#[test]
+fn checks_pins_per_profile() {
+    NITROKEY_PROFILE.check_so_pin("0123456789abcdef").unwrap();
+    NITROKEY_PROFILE.check_user_pin("tuf123").unwrap();
+    assert_eq!(
+        NITROKEY_PROFILE.check_user_pin("tuf1234").unwrap_err(),
+        "invalid user PIN (expected 6 alphanum characters)"
+    );
+
+    YKCS11_PROFILE
+        .check_so_pin("010203040506070801020304050607080102030405060708")
+        .unwrap();
+    assert_eq!(
+        YKCS11_PROFILE.check_so_pin("0123456789abcdef").unwrap_err(),
+        "invalid SO PIN (expected a 48 hex digit management key)"
+    );
+    YKCS11_PROFILE.check_user_pin("12345678").unwrap();
+    assert!(YKCS11_PROFILE.check_user_pin("123456789").is_err());
+
+    for profile in &[SOFTHSM2_TEST_PROFILE, GENERIC_PROFILE] {
+        profile.check_so_pin("so-pin").unwrap();
+        profile.check_user_pin("1234").unwrap();
+        assert!(profile.check_user_pin("123").is_err());
+    }
+}
+
+#[test]
+fn checks_roles_per_profile() {
+    for profile in PROFILES {
+        for key_type in &[KeyType::P256, KeyType::P384] {
+            profile.check_roles(&default_roles(*key_type)).unwrap();
+        }
+    }
+
+    let roles = [Role::new("root", 0x00, "root", KeyType::P256)];
+    GENERIC_PROFILE.check_roles(&roles).unwrap();
+    assert_eq!(
+        NITROKEY_PROFILE.check_roles(&roles).unwrap_err(),
+        "role root: key ID 00 is outside of nitrokey tokens' range (01 to ff)"
+    );
+
+    // The PIV slots that YKCS11 maps IDs 01 to 04 onto aren't for us.
+    assert_eq!(
+        YKCS11_PROFILE
+            .check_roles(&[Role::new("root", 0x04, "root", KeyType::P256)])
+            .unwrap_err(),
+        "role root: key ID 04 is outside of ykcs11 tokens' range (05 to 18)"
+    );
+
+    let profile = Profile {
+        key_types: &[KeyType::P256],
+        ..GENERIC_PROFILE
+    };
+    assert_eq!(
+        profile
+            .check_roles(&[Role::new("root", 0x12, "root", KeyType::P384)])
+            .unwrap_err(),
+        "role root: generic tokens can't generate p384 keys"
+    );
+}
+
+#[test]
+fn ykcs11_resets_through_piv() {
+    assert_eq!(YKCS11_PROFILE.reset, Reset::PivApplet);
+    assert_eq!(YKCS11_PROFILE.generate_as, types::CKU_SO);
+    YKCS11_PROFILE.check_so_pin(DEFAULT_MANAGEMENT_KEY).unwrap();
+    YKCS11_PROFILE.check_user_pin(DEFAULT_PIN).unwrap();
+
+    for profile in &[NITROKEY_PROFILE, SOFTHSM2_TEST_PROFILE, GENERIC_PROFILE] {
+        assert_eq!(profile.reset, Reset::InitToken);
+        assert_eq!(profile.generate_as, types::CKU_USER);
+    }
+}
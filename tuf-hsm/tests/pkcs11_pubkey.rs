@@ -0,0 +1,72 @@
+// Checks our hand-rolled SubjectPublicKeyInfo encoding against keys
+// produced by `openssl ec -pubout`.
+
+use tuf_hsm::pkcs11::pubkey::{ec_point_from_attribute, spki_der, spki_pem};
+use tuf_hsm::pkcs11::KeyType;
+
+const P256_SPKI: &str = "3059301306072a8648ce3d020106082a8648ce3d030107034200041a5d3683d7b7e2b131d6ce6bec3a24fd8d7717a9823221848ef326562d2ed5473cac0386cc16b6ca93706f84cb820c10da19e5c26d90f87c52fdbfe56628ae20";
+
+const P256_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEGl02g9e34rEx1s5r7Dok/Y13F6mC
+MiGEjvMmVi0u1Uc8rAOGzBa2ypNwb4TLggwQ2hnlwm2Q+HxS/b/lZiiuIA==
+-----END PUBLIC KEY-----
+";
+
+const P384_SPKI: &str = "3076301006072a8648ce3d020106052b81040022036200043838fd691ab2f3a3fbf48c2feb5824c07bed35550d96673c33ebe5b90f5dce1f4b12ef6ab9b41ccb5773c8a6653d489508b085585d71ca4de4f68366992ebe2f6d3657f4e6ad0fc4ba739e4122246042e5377319c3fbbfe4861ea6735d9edecb";
+
+const P384_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MHYwEAYHKoZIzj0CAQYFK4EEACIDYgAEODj9aRqy86P79Iwv61gkwHvtNVUNlmc8
+M+vluQ9dzh9LEu9qubQcy1dzyKZlPUiVCLCFWF1xyk3k9oNmmS6+L202V/TmrQ/E
+unOeQSIkYELlN3MZw/u/5IYepnNdnt7L
+-----END PUBLIC KEY-----
+";
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+// The uncompressed point is always the tail of the SPKI.
+fn point(spki: &[u8], key_type: KeyType) -> Vec<u8> {
+    spki[spki.len() - key_type.point_len()..].to_vec()
+}
+
+#[test]
+fn encodes_spki() {
+    for (key_type, spki, pem) in &[
+        (KeyType::P256, P256_SPKI, P256_PEM),
+        (KeyType::P384, P384_SPKI, P384_PEM),
+    ] {
+        let spki = unhex(spki);
+        let der = spki_der(*key_type, &point(&spki, *key_type));
+        assert_eq!(der, spki);
+        assert_eq!(spki_pem(&der), *pem);
+    }
+}
+
+#[test]
+fn accepts_wrapped_and_bare_points() {
+    let point = point(&unhex(P256_SPKI), KeyType::P256);
+
+    let mut wrapped = vec![0x04, point.len() as u8];
+    wrapped.extend_from_slice(&point);
+
+    assert_eq!(
+        ec_point_from_attribute(KeyType::P256, &wrapped).unwrap(),
+        point
+    );
+    assert_eq!(
+        ec_point_from_attribute(KeyType::P256, &point).unwrap(),
+        point
+    );
+
+    // Right shape, wrong curve.
+    assert!(ec_point_from_attribute(KeyType::P384, &wrapped).is_err());
+
+    // Compressed points aren't something we know how to encode.
+    let mut compressed = point.clone();
+    compressed[0] = 0x02;
+    assert!(ec_point_from_attribute(KeyType::P256, &compressed).is_err());
+}
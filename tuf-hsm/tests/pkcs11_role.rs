@@ -0,0 +1,82 @@
+use tuf_hsm::pkcs11::role::{check_roles, default_roles, parse, Role};
+use tuf_hsm::pkcs11::{KeyType, TUF_ROOT_KEY_ID, TUF_TARGETS_KEY_ID};
+
+#[test]
+fn parses_roles() {
+    // IDs are hex, like pkcs11-tool's --id.
+    assert_eq!(
+        parse("snapshot:14:snapshot", KeyType::P256).unwrap(),
+        Role::new("snapshot", 0x14, "snapshot", KeyType::P256)
+    );
+    assert_eq!(
+        parse("timestamp:1f:timestamp:p384", KeyType::P256).unwrap(),
+        Role::new("timestamp", 0x1f, "timestamp", KeyType::P384)
+    );
+
+    for spec in &[
+        "snapshot",
+        "snapshot:14",
+        "snapshot:xy:snapshot",
+        "snapshot:100:snapshot",
+        "snapshot:14:snapshot:ed25519",
+        "snapshot:14:snapshot:p256:extra",
+    ] {
+        assert!(
+            parse(spec, KeyType::P256).is_err(),
+            "{} should be rejected",
+            spec
+        );
+    }
+}
+
+#[test]
+fn default_roles_match_earlier_ceremonies() {
+    let roles = default_roles(KeyType::P256);
+    check_roles(&roles).unwrap();
+    assert_eq!(
+        roles,
+        [
+            Role::new("root", TUF_ROOT_KEY_ID, "root", KeyType::P256),
+            Role::new("targets", TUF_TARGETS_KEY_ID, "targets", KeyType::P256),
+        ]
+    );
+    assert_eq!(roles[1].file_suffix("pubkey.pub"), "targets_pubkey.pub");
+}
+
+#[test]
+fn rejects_colliding_roles() {
+    for (roles, expected) in &[
+        (
+            vec![
+                Role::new("root", 0x12, "root", KeyType::P256),
+                Role::new("targets", 0x12, "targets", KeyType::P256),
+            ],
+            "key ID 12 is used by more than one role",
+        ),
+        (
+            vec![
+                Role::new("root", 0x12, "root", KeyType::P256),
+                Role::new("targets", 0x13, "root", KeyType::P256),
+            ],
+            "label \"root\" is used by more than one role",
+        ),
+        (
+            vec![
+                Role::new("root", 0x12, "root", KeyType::P256),
+                Role::new("root", 0x13, "root-2", KeyType::P256),
+            ],
+            "role root is listed more than once",
+        ),
+        (
+            vec![Role::new("root/../x", 0x12, "root", KeyType::P256)],
+            "bad role name \"root/../x\": use letters, digits, '-' and '_'",
+        ),
+        (
+            vec![Role::new("root", 0x12, "", KeyType::P256)],
+            "role root needs a label",
+        ),
+        (vec![], "no roles to provision"),
+    ] {
+        assert_eq!(check_roles(roles).unwrap_err(), *expected);
+    }
+}
@@ -0,0 +1,731 @@
+// End-to-end provisioning tests against a throwaway SoftHSM2 token.
+//
+// These need SoftHSM2 to be installed (e.g. `apt install softhsm2`). The module
+// is looked up in the usual distribution locations, or can be given explicitly
+// with SOFTHSM2_MODULE=/path/to/libsofthsm2.so. Without it, the SoftHSM2 tests
+// print a note and pass trivially, except in CI (where $CI is set): there, and
+// whenever SOFTHSM2_MODULE doesn't name a module, they fail.
+
+use lazy_static::lazy_static;
+use pkcs11::{types, Ctx};
+
+use tuf_hsm::backend::Backend;
+use tuf_hsm::error::Error;
+use tuf_hsm::journal::{self, Journal, Stage};
+use tuf_hsm::manifest::{self, Manifest};
+use tuf_hsm::pkcs11::backend::{Config, Pkcs11Token};
+use tuf_hsm::pkcs11::profile::{Profile, NITROKEY_PROFILE, SOFTHSM2_TEST_PROFILE};
+use tuf_hsm::pkcs11::role::{default_roles, Role};
+use tuf_hsm::pkcs11::token::Selection;
+use tuf_hsm::pkcs11::{
+    find_hsm, provision, token_in_deadly_state, KeyType, Options, TUF_ROOT_KEY_ID,
+    TUF_TARGETS_KEY_ID,
+};
+use tuf_hsm::prompt::{Prompt, SecretKind};
+use tuf_hsm::secret::Secret;
+use tuf_hsm::transcript;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// The Nitrokey's factory default SO PIN, as documented in the runbook.
+const SO_PIN: &str = "3537363231383830";
+const USER_PIN: &str = "648219";
+
+const NEW_SO_PIN: &str = "0123456789abcdef";
+const NEW_USER_PIN: &str = "tuf123";
+
+const SOFTHSM2_MODULE_PATHS: &[&str] = &[
+    "/usr/lib/softhsm/libsofthsm2.so",
+    "/usr/lib/x86_64-linux-gnu/softhsm/libsofthsm2.so",
+    "/usr/lib/aarch64-linux-gnu/softhsm/libsofthsm2.so",
+    "/usr/lib64/pkcs11/libsofthsm2.so",
+    "/usr/local/lib/softhsm/libsofthsm2.so",
+    "/opt/homebrew/lib/softhsm/libsofthsm2.so",
+];
+
+lazy_static! {
+    // SoftHSM2 reads its configuration (via SOFTHSM2_CONF) once per
+    // C_Initialize, and only one PKCS#11 context can be live per process.
+    static ref SOFTHSM2: Mutex<()> = Mutex::new(());
+}
+
+struct ScriptedPrompt {
+    confirmations: VecDeque<bool>,
+    passwords: VecDeque<String>,
+}
+
+impl ScriptedPrompt {
+    fn new(confirmations: &[bool], passwords: &[&str]) -> Self {
+        ScriptedPrompt {
+            confirmations: confirmations.iter().cloned().collect(),
+            passwords: passwords.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn assert_exhausted(&self) {
+        assert!(self.confirmations.is_empty(), "unused confirmations");
+        assert!(self.passwords.is_empty(), "unused passwords");
+    }
+}
+
+impl Prompt for ScriptedPrompt {
+    fn confirm(&mut self, msg: &str) -> Result<bool, String> {
+        Ok(self
+            .confirmations
+            .pop_front()
+            .unwrap_or_else(|| panic!("unexpected confirmation: {}", msg)))
+    }
+
+    fn password(&mut self, _kind: SecretKind, prompt: &str) -> Result<Secret, String> {
+        Ok(self
+            .passwords
+            .pop_front()
+            .map(Secret::new)
+            .unwrap_or_else(|| panic!("unexpected password prompt: {}", prompt)))
+    }
+}
+
+// A SoftHSM2 token store in a temporary directory, holding a single token
+// that looks like a factory-fresh Nitrokey: initialized with the default
+// SO PIN and a user PIN.
+struct SoftHsm2 {
+    module: PathBuf,
+    _tokens: tempfile::TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl SoftHsm2 {
+    fn new() -> Option<Self> {
+        let module = match env::var_os("SOFTHSM2_MODULE") {
+            Some(module) => {
+                let module = PathBuf::from(module);
+                assert!(
+                    module.is_file(),
+                    "SOFTHSM2_MODULE is set, but {:?} isn't a module",
+                    module
+                );
+                module
+            }
+            None => match SOFTHSM2_MODULE_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .find(|p| p.exists())
+            {
+                Some(module) => module,
+                None if env::var_os("CI").is_some() => {
+                    panic!("SoftHSM2 not found, but it's required in CI; set SOFTHSM2_MODULE")
+                }
+                None => {
+                    eprintln!("SoftHSM2 not found; set SOFTHSM2_MODULE to run this test");
+                    return None;
+                }
+            },
+        };
+
+        let lock = SOFTHSM2.lock().unwrap_or_else(|e| e.into_inner());
+
+        let tokens = tempfile::tempdir().unwrap();
+        let conf = tokens.path().join("softhsm2.conf");
+        fs::write(
+            &conf,
+            format!(
+                "directories.tokendir = {}\nobjectstore.backend = file\nlog.level = ERROR\n",
+                tokens.path().display()
+            ),
+        )
+        .unwrap();
+        env::set_var("SOFTHSM2_CONF", &conf);
+
+        let ctx = Ctx::new_and_initialize(&module).unwrap();
+        let slot = ctx.get_slot_list(true).unwrap()[0];
+        ctx.init_token(slot, Some(SO_PIN), "fresh").unwrap();
+
+        // NOTE: SoftHSM2 moves a token to a new slot upon initialization.
+        let slot = ctx
+            .get_slot_list(true)
+            .unwrap()
+            .into_iter()
+            .find(|s| ctx.get_token_info(*s).unwrap().flags & types::CKF_TOKEN_INITIALIZED != 0)
+            .unwrap();
+        let session = ctx
+            .open_session(
+                slot,
+                types::CKF_SERIAL_SESSION | types::CKF_RW_SESSION,
+                None,
+                None,
+            )
+            .unwrap();
+        ctx.login(session, types::CKU_SO, Some(SO_PIN)).unwrap();
+        ctx.init_pin(session, Some(USER_PIN)).unwrap();
+        ctx.close_session(session).unwrap();
+
+        Some(SoftHsm2 {
+            module,
+            _tokens: tokens,
+            _lock: lock,
+        })
+    }
+
+    // The token, as provision would find it.
+    fn token(&self) -> Pkcs11Token {
+        Pkcs11Token::discover(&Config {
+            module: self.module.clone(),
+            profile: SOFTHSM2_TEST_PROFILE,
+            serial: None,
+            slot: None,
+        })
+        .unwrap()
+    }
+}
+
+fn can_login(ctx: &Ctx, slot: types::CK_SLOT_ID, user: types::CK_USER_TYPE, pin: &str) -> bool {
+    let session = ctx
+        .open_session(
+            slot,
+            types::CKF_SERIAL_SESSION | types::CKF_RW_SESSION,
+            None,
+            None,
+        )
+        .unwrap();
+    let result = ctx.login(session, user, Some(pin)).is_ok();
+    ctx.close_session(session).unwrap();
+    result
+}
+
+// A journal for tests that reset the token outside of provision.
+fn scratch_journal() -> Journal {
+    let dir = tempfile::tempdir().unwrap().into_path();
+    Journal::open(&journal::path(&dir, "scratch")).unwrap()
+}
+
+// Resets the token and rotates its PINs, as provision does before generating
+// any keys, then closes the session so that can_login can log in afresh.
+fn factory_reset(
+    token: &mut Pkcs11Token,
+    so_pin: &str,
+    prompt: &mut dyn Prompt,
+) -> Result<(), Error> {
+    let mut journal = scratch_journal();
+    token.set_so_pin(Secret::from(so_pin));
+    token.reset(prompt, &mut journal)?;
+    token.set_credentials(&[], prompt, &mut journal)?;
+    token.close_session()
+}
+
+// Appends raw lines to a token's journal, as if an earlier run had written them.
+fn append_journal(output_dir: &Path, serial_number: &str, lines: &str) {
+    fs::create_dir_all(output_dir).unwrap();
+
+    let path = journal::path(output_dir, serial_number);
+    let mut contents = fs::read_to_string(&path).unwrap_or_default();
+    contents.push_str(lines);
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+fn reset_rotates_pins() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+
+    let mut token = softhsm.token();
+    let serial_number = token.serial_number().to_string();
+    assert!(!serial_number.is_empty());
+    assert!(!serial_number.ends_with(' '));
+
+    let mut prompt = ScriptedPrompt::new(
+        &[true],
+        &[NEW_SO_PIN, NEW_SO_PIN, NEW_USER_PIN, NEW_USER_PIN],
+    );
+    factory_reset(&mut token, SO_PIN, &mut prompt).unwrap();
+    prompt.assert_exhausted();
+
+    let info = token.ctx().get_token_info(token.slot()).unwrap();
+    assert_eq!(String::from(info.label), SOFTHSM2_TEST_PROFILE.token_label);
+    assert!(info.flags & types::CKF_USER_PIN_INITIALIZED != 0);
+    assert!(!token_in_deadly_state(&info));
+
+    assert!(can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_SO,
+        NEW_SO_PIN
+    ));
+    assert!(can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_USER,
+        NEW_USER_PIN
+    ));
+    assert!(!can_login(token.ctx(), token.slot(), types::CKU_SO, SO_PIN));
+    assert!(!can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_USER,
+        USER_PIN
+    ));
+}
+
+// Finds the single public key object with the given CKA_ID, returning its CKA_EC_POINT.
+fn find_public_key(ctx: &Ctx, slot: types::CK_SLOT_ID, key_id: u8) -> Vec<u8> {
+    let session = ctx
+        .open_session(slot, types::CKF_SERIAL_SESSION, None, None)
+        .unwrap();
+
+    let class = types::CKO_PUBLIC_KEY;
+    let key_id = [key_id];
+    let template = vec![
+        types::CK_ATTRIBUTE::new(types::CKA_CLASS).with_ck_ulong(&class),
+        types::CK_ATTRIBUTE::new(types::CKA_ID).with_bytes(&key_id),
+    ];
+    ctx.find_objects_init(session, &template).unwrap();
+    let objects = ctx.find_objects(session, 10).unwrap();
+    ctx.find_objects_final(session).unwrap();
+    assert_eq!(objects.len(), 1);
+
+    let mut template = vec![types::CK_ATTRIBUTE::new(types::CKA_EC_POINT)];
+    ctx.get_attribute_value(session, objects[0], &mut template)
+        .unwrap();
+    let ec_point = vec![0; template[0].ulValueLen as usize];
+    template[0].set_bytes(&ec_point);
+    ctx.get_attribute_value(session, objects[0], &mut template)
+        .unwrap();
+    let ec_point = template[0].get_bytes().unwrap();
+
+    ctx.close_session(session).unwrap();
+    ec_point
+}
+
+#[test]
+fn provision_generates_keys() {
+    for key_type in &[KeyType::P256, KeyType::P384] {
+        let softhsm = match SoftHsm2::new() {
+            Some(softhsm) => softhsm,
+            None => return,
+        };
+        let products = tempfile::tempdir().unwrap();
+
+        let mut token = softhsm.token();
+        let serial_number = token.serial_number().to_string();
+
+        let mut prompt = ScriptedPrompt::new(
+            &[true],
+            &[NEW_SO_PIN, NEW_SO_PIN, NEW_USER_PIN, NEW_USER_PIN],
+        );
+        let options = Options {
+            roles: default_roles(*key_type),
+            products_dir: products.path().into(),
+            resume: false,
+            airgap_override: None,
+        };
+        {
+            token.set_so_pin(Secret::from(SO_PIN));
+            provision(&mut token, &mut prompt, &options)
+        }
+        .unwrap();
+        prompt.assert_exhausted();
+
+        let output_dir = products.path().join(&serial_number);
+        for (role, key_id) in &[("root", TUF_ROOT_KEY_ID), ("targets", TUF_TARGETS_KEY_ID)] {
+            let ec_point = find_public_key(token.ctx(), token.slot(), *key_id);
+            let point = &ec_point[ec_point.len() - key_type.point_len()..];
+
+            let der = fs::read(output_dir.join(format!("{}_{}_pubkey.pub", serial_number, role)))
+                .unwrap();
+            assert!(der.ends_with(point));
+
+            let pem = fs::read_to_string(
+                output_dir.join(format!("{}_{}_pubkey.pem", serial_number, role)),
+            )
+            .unwrap();
+            assert!(pem.starts_with("-----BEGIN PUBLIC KEY-----\n"));
+        }
+
+        // The transcript has an event per stage, and none of the PINs.
+        let contents = fs::read_to_string(transcript::path(&output_dir, &serial_number)).unwrap();
+        for pin in &[SO_PIN, NEW_SO_PIN, NEW_USER_PIN] {
+            assert!(!contents.contains(pin));
+        }
+        let events: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| event["event"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "device-discovered",
+                "reset-confirmed",
+                "credential-changed",
+                "credential-changed",
+                "key-generated",
+                "key-generated",
+                "file-written",
+                "file-written",
+                "file-written",
+                "file-written",
+            ]
+        );
+        assert_eq!(events[0]["vendor"], "softhsm2-test");
+        assert_eq!(events[4]["object_id"], "12");
+        assert_eq!(events[4]["algorithm"], key_type.name());
+
+        // The manifest describes the token and its keys, and covers every
+        // other file.
+        let manifest = Manifest::read(&manifest::path(&output_dir, &serial_number)).unwrap();
+        assert_eq!(manifest.device.vendor, "softhsm2-test");
+        assert_eq!(manifest.device.serial_number, serial_number);
+        assert!(!manifest.device.firmware.is_empty());
+        assert_eq!(manifest.roles[0].object_id, "12");
+        assert_eq!(manifest.roles[0].key_type, key_type.name());
+        assert_eq!(manifest.products.len(), 6);
+        assert!(manifest::verify(&output_dir, &manifest).unwrap().passed());
+
+        // Running again must refuse before touching the token.
+        let mut prompt = ScriptedPrompt::new(&[], &[]);
+        let err = {
+            token.set_so_pin(Secret::from(NEW_SO_PIN));
+            provision(&mut token, &mut prompt, &options)
+        }
+        .unwrap_err();
+        assert!(matches!(err, Error::OutputExists(ref msg)
+            if msg.starts_with("Public key file already exists")));
+        assert!(can_login(
+            token.ctx(),
+            token.slot(),
+            types::CKU_USER,
+            NEW_USER_PIN
+        ));
+    }
+}
+
+#[test]
+fn provision_generates_extra_roles() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+    let products = tempfile::tempdir().unwrap();
+
+    let mut token = softhsm.token();
+    let serial_number = token.serial_number().to_string();
+
+    let mut prompt = ScriptedPrompt::new(
+        &[true],
+        &[NEW_SO_PIN, NEW_SO_PIN, NEW_USER_PIN, NEW_USER_PIN],
+    );
+    let options = Options {
+        roles: vec![
+            Role::new("root", TUF_ROOT_KEY_ID, "root", KeyType::P384),
+            Role::new("snapshot", 0x14, "snapshot", KeyType::P256),
+        ],
+        products_dir: products.path().into(),
+        resume: false,
+        airgap_override: None,
+    };
+    {
+        token.set_so_pin(Secret::from(SO_PIN));
+        provision(&mut token, &mut prompt, &options)
+    }
+    .unwrap();
+    prompt.assert_exhausted();
+
+    let output_dir = products.path().join(&serial_number);
+    let ec_point = find_public_key(token.ctx(), token.slot(), 0x14);
+    let point = &ec_point[ec_point.len() - KeyType::P256.point_len()..];
+    let der = fs::read(output_dir.join(format!("{}_snapshot_pubkey.pub", serial_number))).unwrap();
+    assert!(der.ends_with(point));
+
+    // Two files per role, the journal, the transcript and the manifest, and
+    // nothing for the roles we didn't ask for.
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 7);
+}
+
+#[test]
+fn reset_rejects_wrong_so_pin() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+
+    let mut token = softhsm.token();
+
+    let mut prompt = ScriptedPrompt::new(&[true], &[]);
+    let err = factory_reset(&mut token, "ffffffffffffffff", &mut prompt).unwrap_err();
+    prompt.assert_exhausted();
+    assert!(matches!(err, Error::AuthFailure(ref msg)
+        if msg.starts_with("failed to (re)initialize HSM")));
+
+    // The token was left alone.
+    assert!(can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_USER,
+        USER_PIN
+    ));
+}
+
+#[test]
+fn reset_rejects_mismatched_so_pin() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+
+    let mut token = softhsm.token();
+
+    let mut prompt = ScriptedPrompt::new(&[true], &[NEW_SO_PIN, "fedcba9876543210"]);
+    let err = factory_reset(&mut token, SO_PIN, &mut prompt).unwrap_err();
+    prompt.assert_exhausted();
+    assert_eq!(
+        err,
+        Error::CredentialMismatch(String::from("SO PIN does not match!"))
+    );
+
+    // The token was reinitialized, but the SO PIN wasn't changed.
+    assert!(can_login(token.ctx(), token.slot(), types::CKU_SO, SO_PIN));
+}
+
+#[test]
+fn declining_reset_leaves_token_untouched() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+
+    let mut token = softhsm.token();
+
+    let mut prompt = ScriptedPrompt::new(&[false], &[]);
+    let err = factory_reset(&mut token, SO_PIN, &mut prompt).unwrap_err();
+    prompt.assert_exhausted();
+    assert_eq!(
+        err,
+        Error::UserAbort(String::from("user interrupted provisioning"))
+    );
+
+    assert_eq!(
+        String::from(token.ctx().get_token_info(token.slot()).unwrap().label),
+        "fresh"
+    );
+    assert!(can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_USER,
+        USER_PIN
+    ));
+}
+
+#[test]
+fn nitrokey_profile_rejects_softhsm2() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+
+    // The spare, uninitialized SoftHSM2 slot makes this look like two tokens
+    // before the manufacturer is even considered.
+    match find_hsm(&softhsm.module, &NITROKEY_PROFILE, &Selection::default()) {
+        Ok(_) => panic!("SoftHSM2 accepted as a Nitrokey"),
+        Err(Error::MultipleDevices(msg)) => {
+            assert!(msg.starts_with("more than one HSM or token detected ("))
+        }
+        Err(e) => panic!("unexpected error: {:?}", e),
+    }
+
+    // Picking the initialized token by serial number gets past that.
+    let serial_number = softhsm.token().serial_number().to_string();
+    let selection = Selection {
+        serial: Some(&serial_number),
+        slot: None,
+    };
+    match find_hsm(&softhsm.module, &NITROKEY_PROFILE, &selection) {
+        Ok(_) => panic!("SoftHSM2 accepted as a Nitrokey"),
+        Err(e) => assert_eq!(
+            e,
+            Error::DeviceNotFound(String::from("unknown HSM: SoftHSM project"))
+        ),
+    }
+
+    // Even when that slot is ignored, the manufacturer doesn't match.
+    let profile = Profile {
+        manufacturer: NITROKEY_PROFILE.manufacturer,
+        ..SOFTHSM2_TEST_PROFILE
+    };
+    match find_hsm(&softhsm.module, &profile, &Selection::default()) {
+        Ok(_) => panic!("SoftHSM2 accepted as a Nitrokey"),
+        Err(e) => assert_eq!(
+            e,
+            Error::DeviceNotFound(String::from("unknown HSM: SoftHSM project"))
+        ),
+    }
+}
+
+#[test]
+fn deadly_states() {
+    let mut token = types::CK_TOKEN_INFO {
+        flags: types::CKF_TOKEN_INITIALIZED | types::CKF_USER_PIN_INITIALIZED,
+        ..Default::default()
+    };
+    assert!(!token_in_deadly_state(&token));
+
+    // Having used up a PIN attempt isn't deadly on its own.
+    token.flags |= types::CKF_USER_PIN_COUNT_LOW | types::CKF_SO_PIN_COUNT_LOW;
+    assert!(!token_in_deadly_state(&token));
+
+    for flag in &[
+        types::CKF_USER_PIN_FINAL_TRY,
+        types::CKF_USER_PIN_LOCKED,
+        types::CKF_SO_PIN_FINAL_TRY,
+        types::CKF_SO_PIN_LOCKED,
+        types::CKF_ERROR_STATE,
+    ] {
+        let mut deadly = token;
+        deadly.flags |= flag;
+        assert!(token_in_deadly_state(&deadly), "flag {:#x}", flag);
+    }
+}
+
+#[test]
+fn provision_resumes_interrupted_runs() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+    let products = tempfile::tempdir().unwrap();
+
+    let mut token = softhsm.token();
+    let serial_number = token.serial_number().to_string();
+
+    // An earlier run changed the SO PIN, then died writing the root public key.
+    let mut prompt = ScriptedPrompt::new(
+        &[true],
+        &[NEW_SO_PIN, NEW_SO_PIN, NEW_USER_PIN, NEW_USER_PIN],
+    );
+    factory_reset(&mut token, SO_PIN, &mut prompt).unwrap();
+
+    let output_dir = products.path().join(&serial_number);
+    append_journal(
+        &output_dir,
+        &serial_number,
+        "1600000000 begin provision\n1600000001 begin reset\n1600000002 end reset\n\
+         1600000003 begin so-pin\n1600000004 end so-pin\n\
+         1600000005 begin write root_pubkey.pub\n",
+    );
+    fs::write(
+        output_dir.join(format!("{}_root_pubkey.pub", serial_number)),
+        b"truncated",
+    )
+    .unwrap();
+
+    let options = Options {
+        roles: default_roles(KeyType::P256),
+        products_dir: products.path().into(),
+        airgap_override: None,
+        resume: false,
+    };
+
+    // Without --resume, nothing is touched.
+    let mut prompt = ScriptedPrompt::new(&[], &[]);
+    let err = {
+        token.set_so_pin(Secret::from(NEW_SO_PIN));
+        provision(&mut token, &mut prompt, &options)
+    }
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("interrupted during write root_pubkey.pub; rerun with --resume"));
+    assert!(can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_USER,
+        NEW_USER_PIN
+    ));
+
+    // With it, the user confirms the new SO PIN and everything is redone.
+    let mut prompt = ScriptedPrompt::new(
+        &[true, true],
+        &[NEW_SO_PIN, NEW_SO_PIN, NEW_USER_PIN, NEW_USER_PIN],
+    );
+    let options = Options {
+        resume: true,
+        ..options
+    };
+    {
+        token.set_so_pin(Secret::from(NEW_SO_PIN));
+        provision(&mut token, &mut prompt, &options)
+    }
+    .unwrap();
+    prompt.assert_exhausted();
+
+    let ec_point = find_public_key(token.ctx(), token.slot(), TUF_ROOT_KEY_ID);
+    let der = fs::read(output_dir.join(format!("{}_root_pubkey.pub", serial_number))).unwrap();
+    assert!(der.ends_with(&ec_point[ec_point.len() - KeyType::P256.point_len()..]));
+
+    let recovery = journal::read(&journal::path(&output_dir, &serial_number))
+        .unwrap()
+        .unwrap();
+    assert!(recovery.finished());
+}
+
+#[test]
+fn interrupted_so_pin_change_needs_manual_intervention() {
+    let softhsm = match SoftHsm2::new() {
+        Some(softhsm) => softhsm,
+        None => return,
+    };
+    let products = tempfile::tempdir().unwrap();
+
+    let mut token = softhsm.token();
+    let serial_number = token.serial_number().to_string();
+
+    let output_dir = products.path().join(&serial_number);
+    append_journal(
+        &output_dir,
+        &serial_number,
+        "1600000000 begin provision\n1600000001 begin so-pin\n",
+    );
+
+    let mut prompt = ScriptedPrompt::new(&[], &[]);
+    let options = Options {
+        roles: default_roles(KeyType::P256),
+        products_dir: products.path().into(),
+        airgap_override: None,
+        resume: true,
+    };
+    let err = {
+        token.set_so_pin(Secret::from(SO_PIN));
+        provision(&mut token, &mut prompt, &options)
+    }
+    .unwrap_err();
+    assert!(
+        matches!(err, Error::ManualIntervention(ref msg) if msg.ends_with("manual intervention required")),
+        "unexpected error: {:?}",
+        err
+    );
+    assert!(can_login(
+        token.ctx(),
+        token.slot(),
+        types::CKU_USER,
+        USER_PIN
+    ));
+
+    // Resuming a run that never happened makes no sense either.
+    let mut journal = Journal::open(&journal::path(&output_dir, &serial_number)).unwrap();
+    journal.end(&Stage::SoPin).unwrap();
+    journal.end(&Stage::Provision).unwrap();
+    let err = {
+        token.set_so_pin(Secret::from(SO_PIN));
+        provision(&mut token, &mut prompt, &options)
+    }
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("already provisioned; nothing to resume"));
+}
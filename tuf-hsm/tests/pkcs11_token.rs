@@ -0,0 +1,119 @@
+use pkcs11::types;
+
+use tuf_hsm::error::Error;
+use tuf_hsm::pkcs11::token::{describe, flag_names, pin_status, select_slot, Selection};
+
+fn tokens() -> Vec<(types::CK_SLOT_ID, String)> {
+    vec![
+        (0, String::from("DENK0102947     ")),
+        (1, String::from("0123456789abcdef")),
+    ]
+}
+
+#[test]
+fn selects_the_only_token() {
+    assert_eq!(
+        select_slot(&tokens()[..1], &Selection::default()).unwrap(),
+        0
+    );
+    assert_eq!(
+        select_slot(&[], &Selection::default()).unwrap_err(),
+        Error::DeviceNotFound(String::from("no HSMs detected"))
+    );
+    assert_eq!(
+        select_slot(&tokens(), &Selection::default()).unwrap_err(),
+        Error::MultipleDevices(String::from(
+            "more than one HSM or token detected (slot #0 (DENK0102947), \
+             slot #1 (0123456789abcdef)); pass --serial or --slot to pick one"
+        ))
+    );
+}
+
+#[test]
+fn selects_tokens_by_serial_and_slot() {
+    let by_serial = Selection {
+        serial: Some("DENK0102947"),
+        slot: None,
+    };
+    assert_eq!(select_slot(&tokens(), &by_serial).unwrap(), 0);
+
+    let by_slot = Selection {
+        serial: None,
+        slot: Some(1),
+    };
+    assert_eq!(select_slot(&tokens(), &by_slot).unwrap(), 1);
+
+    let both = Selection {
+        serial: Some("0123456789abcdef "),
+        slot: Some(1),
+    };
+    assert_eq!(select_slot(&tokens(), &both).unwrap(), 1);
+
+    // A serial number and slot that disagree match nothing.
+    let disagreeing = Selection {
+        serial: Some("DENK0102947"),
+        slot: Some(1),
+    };
+    assert_eq!(
+        select_slot(&tokens(), &disagreeing).unwrap_err(),
+        Error::DeviceNotFound(String::from(
+            "no matching token detected (found slot #0 (DENK0102947), slot #1 (0123456789abcdef))"
+        ))
+    );
+
+    let duplicated = vec![
+        (0, String::from("DENK0102947")),
+        (3, String::from("DENK0102947")),
+    ];
+    assert_eq!(
+        select_slot(&duplicated, &by_serial).unwrap_err(),
+        Error::MultipleDevices(String::from(
+            "more than one token matches (found slot #0 (DENK0102947), slot #3 (DENK0102947)); \
+             refusing to continue"
+        ))
+    );
+}
+
+#[test]
+fn describes_tokens() {
+    let flags = types::CKF_RNG
+        | types::CKF_LOGIN_REQUIRED
+        | types::CKF_TOKEN_INITIALIZED
+        | types::CKF_USER_PIN_FINAL_TRY
+        | types::CKF_SO_PIN_COUNT_LOW;
+
+    assert_eq!(
+        flag_names(flags),
+        ["rng", "login-required", "token-initialized"]
+    );
+    assert_eq!(pin_status(flags, false), "final try");
+    assert_eq!(pin_status(flags, true), "count low");
+    assert_eq!(
+        pin_status(
+            types::CKF_USER_PIN_LOCKED | types::CKF_USER_PIN_FINAL_TRY,
+            false
+        ),
+        "locked"
+    );
+    assert_eq!(pin_status(0, true), "ok");
+
+    let mut token = types::CK_TOKEN_INFO {
+        flags: types::CKF_TOKEN_INITIALIZED,
+        ..Default::default()
+    };
+    pad(&mut token.manufacturerID.0, "www.CardContact.de");
+    pad(&mut token.model.0, "PKCS#15 emulated");
+    pad(&mut token.serialNumber.0, "DENK0102947");
+    assert_eq!(
+        describe(4, &token),
+        "slot #4: www.CardContact.de PKCS#15 emulated with serial number DENK0102947\n  \
+         flags: token-initialized (0x400)\n  user PIN: ok, SO PIN: ok"
+    );
+}
+
+// Fills a PKCS#11 blank-padded field.
+fn pad(field: &mut [u8], value: &str) {
+    for (i, byte) in field.iter_mut().enumerate() {
+        *byte = *value.as_bytes().get(i).unwrap_or(&b' ');
+    }
+}
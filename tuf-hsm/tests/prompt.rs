@@ -0,0 +1,140 @@
+use tuf_hsm::pkcs11::current_so_pin;
+use tuf_hsm::pkcs11::profile::NITROKEY_PROFILE;
+use tuf_hsm::prompt::{is_valid_fd, read_secret_fd, Prompt, SecretKind, Unattended};
+
+use std::fs::{self, File};
+use std::os::unix::io::IntoRawFd;
+
+// Opens a file holding `contents`, handing back its raw descriptor.
+fn secret_fd(contents: &str) -> i32 {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("secret");
+    fs::write(&path, contents).unwrap();
+    File::open(&path).unwrap().into_raw_fd()
+}
+
+#[test]
+fn reads_secrets_from_descriptors() {
+    assert_eq!(
+        read_secret_fd(secret_fd("hunter2\nignored\n"))
+            .unwrap()
+            .expose(),
+        "hunter2"
+    );
+    assert_eq!(
+        read_secret_fd(secret_fd("crlf\r\n")).unwrap().expose(),
+        "crlf"
+    );
+    assert_eq!(
+        read_secret_fd(secret_fd("no newline")).unwrap().expose(),
+        "no newline"
+    );
+
+    let fd = secret_fd("");
+    assert_eq!(
+        read_secret_fd(fd).unwrap_err(),
+        format!("nothing to read on file descriptor {}", fd)
+    );
+}
+
+#[test]
+fn refuses_standard_descriptors() {
+    for fd in 0..=2 {
+        assert!(read_secret_fd(fd).is_err());
+        assert!(is_valid_fd(fd.to_string()).is_err());
+    }
+    assert!(is_valid_fd(String::from("3")).is_ok());
+    assert!(is_valid_fd(String::from("three")).is_err());
+}
+
+#[test]
+fn refuses_closed_descriptors() {
+    // NOTE: Nothing in the tests opens anywhere near this many files.
+    assert_eq!(
+        read_secret_fd(1000).unwrap_err(),
+        "file descriptor 1000 isn't open"
+    );
+}
+
+#[test]
+fn answers_prompts_unattended() {
+    let mut prompt = Unattended::new(true).with_secret(SecretKind::AuthKeyPassword, "pw".into());
+    assert!(prompt.confirm("Continue?").unwrap());
+    for msg in &[
+        "Authentication key password",
+        "Confirm your authentication key password",
+    ] {
+        assert_eq!(
+            prompt
+                .password(SecretKind::AuthKeyPassword, msg)
+                .unwrap()
+                .expose(),
+            "pw"
+        );
+    }
+}
+
+#[test]
+fn answers_pin_prompts_unattended() {
+    let mut prompt = Unattended::new(true)
+        .with_secret(SecretKind::CurrentSoPin, "3537363231383830".into())
+        .with_secret(SecretKind::NewSoPin, "0123456789abcdef".into())
+        .with_secret(SecretKind::NewUserPin, "tuf123".into());
+    assert!(prompt.confirm("Continue?").unwrap());
+
+    // Only the kind matters, not what the prompt says: the current SO PIN
+    // after an interrupted run is "the NEW one", but it's still the current one.
+    for (kind, msg, pin) in &[
+        (
+            SecretKind::CurrentSoPin,
+            "Enter your CURRENT Security Officer PIN (the NEW one that the interrupted run set)",
+            "3537363231383830",
+        ),
+        (
+            SecretKind::NewSoPin,
+            "Enter your NEW Security Officer PIN",
+            "0123456789abcdef",
+        ),
+        (
+            SecretKind::NewSoPin,
+            "Re-enter your NEW Security Officer PIN",
+            "0123456789abcdef",
+        ),
+        (SecretKind::NewUserPin, "Enter your NEW user PIN", "tuf123"),
+        (
+            SecretKind::NewUserPin,
+            "Re-enter your NEW user PIN",
+            "tuf123",
+        ),
+    ] {
+        assert_eq!(prompt.password(*kind, msg).unwrap().expose(), *pin);
+    }
+}
+
+#[test]
+fn prompts_for_the_current_so_pin() {
+    let mut prompt =
+        Unattended::new(false).with_secret(SecretKind::CurrentSoPin, "3537363231383830".into());
+    assert_eq!(
+        current_so_pin(&NITROKEY_PROFILE, &mut prompt, false)
+            .unwrap()
+            .expose(),
+        "3537363231383830"
+    );
+
+    // After an interrupted run changed it, the current SO PIN is still the
+    // one that --current-so-pin-fd supplies.
+    assert_eq!(
+        current_so_pin(&NITROKEY_PROFILE, &mut prompt, true)
+            .unwrap()
+            .expose(),
+        "3537363231383830"
+    );
+
+    // A malformed PIN is asked for again, but not forever.
+    let mut prompt = Unattended::new(false).with_secret(SecretKind::CurrentSoPin, "1234".into());
+    assert_eq!(
+        current_so_pin(&NITROKEY_PROFILE, &mut prompt, false).unwrap_err(),
+        "too many malformed SO PINs; aborting"
+    );
+}
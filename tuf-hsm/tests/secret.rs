@@ -0,0 +1,82 @@
+use lazy_static::lazy_static;
+
+use tuf_hsm::pkcs11::profile::NITROKEY_PROFILE;
+use tuf_hsm::secret::{disable_core_dumps, Secret};
+
+use std::fs;
+use std::sync::Mutex;
+
+lazy_static! {
+    // The tests that make Secrets, which lock memory, run one at a time so
+    // that locked_kb can tell what each one locked.
+    static ref SECRETS: Mutex<()> = Mutex::new(());
+}
+
+// How much of the process's memory is locked, in kB (VmLck), where Linux says.
+fn locked_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmLck:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[test]
+fn secrets_stay_secret() {
+    let _secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    let secret = Secret::from("correct horse battery staple");
+    assert_eq!(secret.expose(), "correct horse battery staple");
+    assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+    assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret(<redacted>))");
+
+    assert_eq!(secret.clone(), secret);
+    assert!(secret != Secret::from("hunter2"));
+    assert_eq!(Secret::new(String::new()).expose(), "");
+}
+
+#[test]
+fn shared_pages_stay_locked() {
+    let _secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    let before = match locked_kb() {
+        Some(kb) => kb,
+        None => return,
+    };
+
+    // Two short Secrets, which the allocator will put side by side.
+    let first = Secret::from("first");
+    let second = Secret::from("second");
+    if locked_kb() == Some(before) {
+        eprintln!("mlock isn't permitted here; skipping");
+        return;
+    }
+
+    // Whatever page the second is on is still locked.
+    drop(first);
+    assert!(locked_kb().unwrap() > before);
+
+    drop(second);
+    assert_eq!(locked_kb().unwrap(), before);
+}
+
+#[test]
+fn disables_core_dumps() {
+    disable_core_dumps().unwrap();
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 1,
+        rlim_max: 1,
+    };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) }, 0);
+    assert_eq!((limit.rlim_cur, limit.rlim_max), (0, 0));
+}
+
+#[test]
+fn pin_errors_leave_out_the_pin() {
+    for pin in &["12345", "0123456789abcdeg"] {
+        let err = NITROKEY_PROFILE.check_so_pin(pin).unwrap_err();
+        assert_eq!(err, "invalid SO PIN (expected 16 hex digits)");
+
+        let err = NITROKEY_PROFILE.check_user_pin(pin).unwrap_err();
+        assert!(!err.contains(pin));
+    }
+}
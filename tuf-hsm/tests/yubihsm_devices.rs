@@ -0,0 +1,111 @@
+use tuf_hsm::error::Error;
+use tuf_hsm::yubihsm::devices::{parse_device_info, select_serial, DeviceInfo, Transport};
+
+fn serials(serials: &[&str]) -> Vec<String> {
+    serials.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn selects_the_only_device() {
+    assert_eq!(
+        select_serial(&serials(&["0013200461"]), None).unwrap(),
+        "0013200461"
+    );
+    assert_eq!(
+        select_serial(&[], None).unwrap_err(),
+        Error::DeviceNotFound(String::from("no YubiHSMs detected"))
+    );
+}
+
+#[test]
+fn selects_devices_by_serial() {
+    let detected = serials(&["0013200461", "0013200462"]);
+
+    assert_eq!(
+        select_serial(&detected, None).unwrap_err(),
+        Error::MultipleDevices(String::from(
+            "more than one YubiHSM detected (0013200461, 0013200462); pass --serial to pick one"
+        ))
+    );
+    assert_eq!(
+        select_serial(&detected, Some("0013200462")).unwrap(),
+        "0013200462"
+    );
+    assert_eq!(
+        select_serial(&detected, Some("13200461")).unwrap(),
+        "0013200461"
+    );
+    assert_eq!(
+        select_serial(&detected, Some("13200463")).unwrap_err(),
+        Error::DeviceNotFound(String::from(
+            "no YubiHSM with serial number 13200463 detected (found 0013200461, 0013200462)"
+        ))
+    );
+
+    // Two devices claiming the same serial number is suspicious, not a choice.
+    assert_eq!(
+        select_serial(&serials(&["0013200461", "0013200461"]), Some("0013200461")).unwrap_err(),
+        Error::MultipleDevices(String::from(
+            "more than one YubiHSM with serial number 0013200461 detected; refusing to continue"
+        ))
+    );
+}
+
+#[test]
+fn parses_device_info() {
+    // Firmware 2.0.2, serial number 13200461, a 62-entry log, and a few algorithms.
+    let response = [
+        0x86, 0x00, 0x0c, 0x02, 0x00, 0x02, 0x00, 0xc9, 0x6c, 0x4d, 0x3e, 0x01, 0x09, 0x0c, 0x2e,
+    ];
+    assert_eq!(
+        parse_device_info(&response).unwrap(),
+        DeviceInfo {
+            firmware: String::from("2.0.2"),
+            serial_number: String::from("0013200461"),
+        }
+    );
+
+    assert_eq!(
+        parse_device_info(&[0x7f, 0x00, 0x01, 0x03]).unwrap_err(),
+        "HSM returned error 0x03 for device info"
+    );
+    assert_eq!(
+        parse_device_info(&[0x86, 0x00]).unwrap_err(),
+        "malformed device info response"
+    );
+}
+
+#[test]
+fn parses_transports() {
+    assert_eq!(Transport::parse("usb").unwrap(), Transport::Usb);
+    assert_eq!(
+        Transport::parse("http://127.0.0.1:12345").unwrap(),
+        Transport::Http {
+            addr: String::from("127.0.0.1"),
+            port: 12345
+        }
+    );
+    assert_eq!(
+        Transport::parse("http://ceremony-station/").unwrap(),
+        Transport::Http {
+            addr: String::from("ceremony-station"),
+            port: 12345
+        }
+    );
+
+    for spec in &[
+        "",
+        "USB",
+        "https://127.0.0.1:12345",
+        "http://",
+        "http://127.0.0.1:port",
+        "http://127.0.0.1:70000",
+        "http://127.0.0.1:12345/connector/api",
+    ] {
+        assert!(
+            Transport::parse(spec).is_err(),
+            "{} should be rejected",
+            spec
+        );
+    }
+}
@@ -0,0 +1,1258 @@
+// End-to-end provisioning tests against the yubihsm crate's MockHsm.
+//
+// NOTE: The MockHsm (as of yubihsm 0.32) only simulates a subset of the
+// YubiHSM 2: it can't generate EC keys or sign attestation certificates.
+// MockClient below passes everything else through to the MockHsm and records
+// key generation itself, handing back deterministic public keys and
+// attestations so that the written products can be checked byte-for-byte.
+
+use ring::digest;
+use yubihsm::asymmetric::{self, PublicKey};
+use yubihsm::attestation::Certificate;
+use yubihsm::authentication::{self, key::Key, DEFAULT_AUTHENTICATION_KEY_ID};
+use yubihsm::capability::Capability;
+use yubihsm::client::{Client, Error, ErrorKind};
+use yubihsm::connector::Connector;
+use yubihsm::device;
+use yubihsm::domain::Domain;
+use yubihsm::object::{self, Id, Label, Origin, Type};
+use yubihsm::{opaque, Algorithm, Credentials};
+
+use tuf_hsm::airgap::Override;
+use tuf_hsm::error;
+use tuf_hsm::journal::{self, Journal, Stage};
+use tuf_hsm::manifest::{self, Manifest};
+use tuf_hsm::prompt::{Prompt, SecretKind};
+use tuf_hsm::secret::Secret;
+use tuf_hsm::transcript;
+use tuf_hsm::yubihsm::backend::YubiHsm;
+use tuf_hsm::yubihsm::hsm::{Device, Hsm};
+use tuf_hsm::yubihsm::pubkey::{spki_der, spki_pem};
+use tuf_hsm::yubihsm::role::{default_roles, Role};
+use tuf_hsm::yubihsm::{
+    new_auth_key, new_keypair_with_attestation, provision, KeyType, Options, TUF_AUTH_KEY_ID,
+    TUF_ROOT_KEY_ID, TUF_TARGETS_KEY_ID,
+};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+const SERIAL: &str = "0123456789";
+const PASSWORD: &str = "correct horse battery staple";
+const DEVICE_CERT: &[u8] = b"mock device attestation certificate";
+
+#[derive(Debug)]
+struct GeneratedKey {
+    label: Label,
+    domains: Domain,
+    capabilities: Capability,
+    algorithm: asymmetric::Algorithm,
+}
+
+type Generated = Rc<RefCell<BTreeMap<Id, GeneratedKey>>>;
+
+// NOTE: The MockHsm panics (rather than failing authentication) when a session
+// is opened with a deleted auth key, so we keep track of those ourselves.
+type DeletedAuthKeys = Rc<RefCell<BTreeSet<Id>>>;
+
+#[derive(Clone)]
+struct MockDevice {
+    connector: Connector,
+    generated: Generated,
+    deleted_auth_keys: DeletedAuthKeys,
+
+    // Whether opening a session fails before reaching the HSM, as if it were
+    // unplugged.
+    unreachable: Rc<Cell<bool>>,
+}
+
+impl MockDevice {
+    fn new() -> Self {
+        MockDevice {
+            connector: Connector::mockhsm(),
+            generated: Rc::new(RefCell::new(BTreeMap::new())),
+            deleted_auth_keys: Rc::new(RefCell::new(BTreeSet::new())),
+            unreachable: Rc::new(Cell::new(false)),
+        }
+    }
+
+    fn admin(&self) -> Client {
+        Client::open(
+            self.connector.clone(),
+            Credentials::from_password(TUF_AUTH_KEY_ID, PASSWORD.as_bytes()),
+            true,
+        )
+        .expect("couldn't authenticate with the new auth key")
+    }
+}
+
+impl Device for MockDevice {
+    type Client = MockClient;
+    type Config = ();
+
+    fn discover(_config: &()) -> Result<(Self, String), error::Error> {
+        Ok((MockDevice::new(), SERIAL.into()))
+    }
+
+    fn open(&self, credentials: Credentials) -> Result<MockClient, Error> {
+        if self.unreachable.get() {
+            return Err(ErrorKind::ConnectorError.into());
+        }
+
+        if self
+            .deleted_auth_keys
+            .borrow()
+            .contains(&credentials.authentication_key_id)
+        {
+            return Err(ErrorKind::AuthenticationError.into());
+        }
+
+        Ok(MockClient {
+            client: Client::open(self.connector.clone(), credentials, true)?,
+            generated: self.generated.clone(),
+            deleted_auth_keys: self.deleted_auth_keys.clone(),
+        })
+    }
+}
+
+struct MockClient {
+    client: Client,
+    generated: Generated,
+    deleted_auth_keys: DeletedAuthKeys,
+}
+
+fn mock_point(algorithm: asymmetric::Algorithm, key_id: Id) -> Vec<u8> {
+    let len = match algorithm {
+        asymmetric::Algorithm::EcP256 => 64,
+        asymmetric::Algorithm::EcP384 => 96,
+        asymmetric::Algorithm::Ed25519 => 32,
+        other => panic!("unexpected algorithm: {:?}", other),
+    };
+
+    vec![key_id as u8; len]
+}
+
+fn mock_attestation(key_id: Id) -> Vec<u8> {
+    format!("mock attestation for object {}", key_id).into_bytes()
+}
+
+impl Hsm for MockClient {
+    fn reset_device(&self) -> Result<(), Error> {
+        self.generated.borrow_mut().clear();
+        self.deleted_auth_keys.borrow_mut().clear();
+        self.client.reset_device()
+    }
+
+    fn put_authentication_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: authentication::Algorithm,
+        authentication_key: Key,
+    ) -> Result<Id, Error> {
+        self.client.put_authentication_key(
+            key_id,
+            label,
+            domains,
+            capabilities,
+            delegated_capabilities,
+            algorithm,
+            authentication_key,
+        )
+    }
+
+    fn delete_object(&self, object_id: Id, object_type: Type) -> Result<(), Error> {
+        self.client.delete_object(object_id, object_type)?;
+        if object_type == Type::AuthenticationKey {
+            self.deleted_auth_keys.borrow_mut().insert(object_id);
+        }
+
+        Ok(())
+    }
+
+    fn generate_asymmetric_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Id, Error> {
+        // Make sure that we're still talking to an authenticated session.
+        self.client.connect()?;
+
+        let previous = self.generated.borrow_mut().insert(
+            key_id,
+            GeneratedKey {
+                label,
+                domains,
+                capabilities,
+                algorithm,
+            },
+        );
+        assert!(previous.is_none(), "object {} generated twice", key_id);
+
+        Ok(key_id)
+    }
+
+    fn get_public_key(&self, key_id: Id) -> Result<PublicKey, Error> {
+        let generated = self.generated.borrow();
+        let key = generated
+            .get(&key_id)
+            .unwrap_or_else(|| panic!("no generated key with object ID {}", key_id));
+
+        Ok(PublicKey {
+            algorithm: key.algorithm,
+            bytes: mock_point(key.algorithm, key_id),
+        })
+    }
+
+    fn sign_attestation_certificate(
+        &self,
+        key_id: Id,
+        attestation_key_id: Option<Id>,
+    ) -> Result<Certificate, Error> {
+        assert_eq!(attestation_key_id, None);
+        assert!(self.generated.borrow().contains_key(&key_id));
+
+        Ok(Certificate(mock_attestation(key_id)))
+    }
+
+    fn get_opaque(&self, object_id: Id) -> Result<Vec<u8>, Error> {
+        match object_id {
+            0 => Ok(DEVICE_CERT.to_vec()),
+            _ => self.client.get_opaque(object_id),
+        }
+    }
+
+    fn list_objects(&self, filters: &[object::Filter]) -> Result<Vec<object::Entry>, Error> {
+        let mut objects = self.client.list_objects(filters)?;
+        objects.extend(self.generated.borrow().keys().map(|key_id| object::Entry {
+            object_id: *key_id,
+            object_type: Type::AsymmetricKey,
+            sequence: 0,
+        }));
+
+        Ok(objects)
+    }
+
+    fn get_object_info(&self, object_id: Id, object_type: Type) -> Result<object::Info, Error> {
+        match (object_type, self.generated.borrow().get(&object_id)) {
+            (Type::AsymmetricKey, Some(key)) => Ok(object::Info {
+                capabilities: key.capabilities,
+                object_id,
+                length: 0,
+                domains: key.domains,
+                object_type,
+                algorithm: Algorithm::Asymmetric(key.algorithm),
+                sequence: 0,
+                origin: Origin::Generated,
+                label: key.label.clone(),
+                delegated_capabilities: Capability::empty(),
+            }),
+            _ => self.client.get_object_info(object_id, object_type),
+        }
+    }
+
+    fn device_info(&self) -> Result<device::Info, Error> {
+        self.client.device_info()
+    }
+}
+
+#[derive(Default)]
+struct ScriptedPrompt {
+    confirmations: VecDeque<bool>,
+    passwords: VecDeque<String>,
+}
+
+impl ScriptedPrompt {
+    fn new(confirmations: &[bool], passwords: &[&str]) -> Self {
+        ScriptedPrompt {
+            confirmations: confirmations.iter().cloned().collect(),
+            passwords: passwords.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn assert_exhausted(&self) {
+        assert!(self.confirmations.is_empty(), "unused confirmations");
+        assert!(self.passwords.is_empty(), "unused passwords");
+    }
+}
+
+impl Prompt for ScriptedPrompt {
+    fn confirm(&mut self, msg: &str) -> Result<bool, String> {
+        Ok(self
+            .confirmations
+            .pop_front()
+            .unwrap_or_else(|| panic!("unexpected confirmation: {}", msg)))
+    }
+
+    fn password(&mut self, _kind: SecretKind, prompt: &str) -> Result<Secret, String> {
+        Ok(self
+            .passwords
+            .pop_front()
+            .map(Secret::new)
+            .unwrap_or_else(|| panic!("unexpected password prompt: {}", prompt)))
+    }
+}
+
+fn options(key_type: KeyType, products_dir: &Path) -> Options {
+    Options {
+        roles: default_roles(key_type),
+        products_dir: products_dir.into(),
+        write_der: false,
+        resume: false,
+        airgap_override: None,
+    }
+}
+
+// Options for picking up an interrupted run with --resume.
+fn resume_options(key_type: KeyType, products_dir: &Path) -> Options {
+    Options {
+        resume: true,
+        ..options(key_type, products_dir)
+    }
+}
+
+// The device as provision sees it, without any wait after a factory reset.
+fn hsm(device: &MockDevice) -> YubiHsm<MockDevice> {
+    YubiHsm::new(device.clone(), SERIAL.into(), Duration::from_millis(0))
+}
+
+fn default_client(device: &MockDevice) -> Result<Client, Error> {
+    Client::open(device.connector.clone(), Credentials::default(), false)
+}
+
+// Leave something on the HSM that only a factory reset will remove.
+fn plant_object(device: &MockDevice) {
+    default_client(device)
+        .unwrap()
+        .put_opaque(
+            100,
+            Label::from_bytes(b"leftover").unwrap(),
+            Domain::DOM1,
+            Capability::default(),
+            opaque::Algorithm::Data,
+            b"stale data".to_vec(),
+        )
+        .unwrap();
+}
+
+fn product(products_dir: &Path, suffix: &str) -> Vec<u8> {
+    let path = products_dir
+        .join(SERIAL)
+        .join(format!("{}_{}", SERIAL, suffix));
+    fs::read(&path).unwrap_or_else(|e| panic!("couldn't read {:?}: {}", path, e))
+}
+
+fn provisions(key_type: KeyType, algorithm: asymmetric::Algorithm, signing: Capability) {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    plant_object(&device);
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    let options = Options {
+        write_der: true,
+        ..options(key_type, products_dir.path())
+    };
+    provision(&mut hsm(&device), &mut prompt, &options).unwrap();
+    prompt.assert_exhausted();
+
+    // The default auth key is gone, and so is everything from before the reset.
+    let client = device.admin();
+    assert!(client
+        .get_object_info(DEFAULT_AUTHENTICATION_KEY_ID, Type::AuthenticationKey)
+        .is_err());
+    assert!(client.get_object_info(100, Type::Opaque).is_err());
+
+    // The new auth key has exactly the capabilities that provisioning needs.
+    let auth_key = client
+        .get_object_info(TUF_AUTH_KEY_ID, Type::AuthenticationKey)
+        .unwrap();
+    assert_eq!(auth_key.label, Label::from_bytes(b"tuf-authkey").unwrap());
+    assert_eq!(auth_key.domains, Domain::DOM1);
+    assert_eq!(
+        auth_key.capabilities,
+        Capability::GET_OPAQUE
+            | Capability::GENERATE_ASYMMETRIC_KEY
+            | signing
+            | Capability::SIGN_ATTESTATION_CERTIFICATE
+            | Capability::DELETE_AUTHENTICATION_KEY
+    );
+    assert_eq!(auth_key.delegated_capabilities, signing);
+
+    let objects = client.list_objects(&[]).unwrap();
+    assert_eq!(objects.len(), 1);
+
+    // Both TUF keys were generated with the requested curve.
+    let generated = device.generated.borrow();
+    assert_eq!(generated.len(), 2);
+    for (key_id, label) in &[
+        (TUF_ROOT_KEY_ID, "tuf-root"),
+        (TUF_TARGETS_KEY_ID, "tuf-targets"),
+    ] {
+        let key = &generated[key_id];
+        assert_eq!(key.label, Label::from_bytes(label.as_bytes()).unwrap());
+        assert_eq!(key.domains, Domain::DOM1);
+        assert_eq!(key.capabilities, signing);
+        assert_eq!(key.algorithm, algorithm);
+    }
+
+    // And all nine products made it to disk, next to the journal, transcript and
+    // manifest.
+    assert_eq!(
+        fs::read_dir(products_dir.path().join(SERIAL))
+            .unwrap()
+            .count(),
+        12
+    );
+    assert_eq!(product(products_dir.path(), "cert.der"), DEVICE_CERT);
+    assert_eq!(
+        product(products_dir.path(), "root_attestation.der"),
+        mock_attestation(TUF_ROOT_KEY_ID)
+    );
+    assert_eq!(
+        product(products_dir.path(), "targets_attestation.der"),
+        mock_attestation(TUF_TARGETS_KEY_ID)
+    );
+
+    for (key_id, role) in &[(TUF_ROOT_KEY_ID, "root"), (TUF_TARGETS_KEY_ID, "targets")] {
+        // The raw public keys are written as uncompressed SEC1 points (or as-is,
+        // for Ed25519)...
+        let mut raw = match key_type {
+            KeyType::Ed25519 => vec![],
+            _ => vec![0x04],
+        };
+        raw.extend(mock_point(algorithm, *key_id));
+        assert_eq!(
+            product(products_dir.path(), &format!("{}_pubkey.pub", role)),
+            raw
+        );
+
+        // ...and alongside them, as SubjectPublicKeyInfos.
+        let spki = spki_der(key_type, &raw);
+        assert!(spki.ends_with(&raw));
+        assert_eq!(
+            product(products_dir.path(), &format!("{}_pubkey.der", role)),
+            spki
+        );
+        assert_eq!(
+            product(products_dir.path(), &format!("{}_pubkey.pem", role)),
+            spki_pem(&spki).into_bytes()
+        );
+    }
+}
+
+#[test]
+fn der_is_optional() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap();
+    prompt.assert_exhausted();
+
+    let output_dir = products_dir.path().join(SERIAL);
+    assert!(output_dir
+        .join(format!("{}_root_pubkey.pem", SERIAL))
+        .exists());
+    assert!(!output_dir
+        .join(format!("{}_root_pubkey.der", SERIAL))
+        .exists());
+    assert_eq!(fs::read_dir(output_dir).unwrap().count(), 10);
+}
+
+#[test]
+fn provisions_p256() {
+    provisions(
+        KeyType::P256,
+        asymmetric::Algorithm::EcP256,
+        Capability::SIGN_ECDSA,
+    );
+}
+
+#[test]
+fn provisions_p384() {
+    provisions(
+        KeyType::P384,
+        asymmetric::Algorithm::EcP384,
+        Capability::SIGN_ECDSA,
+    );
+}
+
+#[test]
+fn provisions_ed25519() {
+    provisions(
+        KeyType::Ed25519,
+        asymmetric::Algorithm::Ed25519,
+        Capability::SIGN_EDDSA,
+    );
+}
+
+#[test]
+fn provisions_extra_roles() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    let options = Options {
+        roles: vec![
+            Role::new("root", 3, "tuf-root", KeyType::P384),
+            Role::new("root-next", 5, "tuf-root-2021", KeyType::Ed25519),
+            Role::new("bins", 6, "tuf-bins", KeyType::P256),
+        ],
+        ..options(KeyType::P256, products_dir.path())
+    };
+    provision(&mut hsm(&device), &mut prompt, &options).unwrap();
+    prompt.assert_exhausted();
+
+    // Mixed key types mean that the auth key must delegate both kinds of signing.
+    let auth_key = device
+        .admin()
+        .get_object_info(TUF_AUTH_KEY_ID, Type::AuthenticationKey)
+        .unwrap();
+    assert_eq!(
+        auth_key.delegated_capabilities,
+        Capability::SIGN_ECDSA | Capability::SIGN_EDDSA
+    );
+
+    let generated = device.generated.borrow();
+    assert_eq!(generated.len(), 3);
+    assert_eq!(generated[&5].algorithm, asymmetric::Algorithm::Ed25519);
+    assert_eq!(generated[&5].capabilities, Capability::SIGN_EDDSA);
+    assert_eq!(generated[&6].label, Label::from_bytes(b"tuf-bins").unwrap());
+
+    // Three files per role, plus the HSM's own certificate, the journal, the
+    // transcript and the manifest.
+    assert_eq!(
+        fs::read_dir(products_dir.path().join(SERIAL))
+            .unwrap()
+            .count(),
+        13
+    );
+    assert_eq!(
+        product(products_dir.path(), "root-next_attestation.der"),
+        mock_attestation(5)
+    );
+    assert_eq!(
+        product(products_dir.path(), "root-next_pubkey.pub"),
+        mock_point(asymmetric::Algorithm::Ed25519, 5)
+    );
+    assert!(!products_dir
+        .path()
+        .join(SERIAL)
+        .join(format!("{}_targets_pubkey.pub", SERIAL))
+        .exists());
+}
+
+#[test]
+fn refuses_colliding_roles() {
+    for (roles, expected) in &[
+        (
+            vec![
+                Role::new("root", 3, "tuf-root", KeyType::P256),
+                Role::new("targets", 3, "tuf-targets", KeyType::P256),
+            ],
+            "object ID 3 is used by more than one role",
+        ),
+        (
+            vec![
+                Role::new("root", 3, "tuf-root", KeyType::P256),
+                Role::new("targets", 4, "tuf-root", KeyType::P256),
+            ],
+            "label \"tuf-root\" is used by more than one role",
+        ),
+        (
+            vec![
+                Role::new("root", 3, "tuf-root", KeyType::P256),
+                Role::new("root", 4, "tuf-root-2", KeyType::P256),
+            ],
+            "role root is listed more than once",
+        ),
+    ] {
+        let products_dir = tempfile::tempdir().unwrap();
+        let device = MockDevice::new();
+        plant_object(&device);
+
+        // Nothing is asked, reset, or written.
+        let mut prompt = ScriptedPrompt::default();
+        let options = Options {
+            roles: roles.clone(),
+            ..options(KeyType::P256, products_dir.path())
+        };
+        assert_eq!(
+            provision(&mut hsm(&device), &mut prompt, &options).unwrap_err(),
+            error::Error::Other(expected.to_string())
+        );
+
+        let client = default_client(&device).unwrap();
+        assert!(client.get_object_info(100, Type::Opaque).is_ok());
+        assert!(!products_dir.path().join(SERIAL).exists());
+    }
+}
+
+#[test]
+fn declining_reset_leaves_device_untouched() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    plant_object(&device);
+
+    let mut prompt = ScriptedPrompt::new(&[false], &[]);
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        error::Error::UserAbort(String::from("user interrupted provisioning"))
+    );
+    prompt.assert_exhausted();
+
+    let client = default_client(&device).unwrap();
+    assert!(client.get_object_info(100, Type::Opaque).is_ok());
+}
+
+#[test]
+fn mismatched_passwords_keep_default_auth_key() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, "something else"]);
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        error::Error::CredentialMismatch(String::from("supplied passwords don't match!"))
+    );
+    prompt.assert_exhausted();
+
+    let client = default_client(&device).unwrap();
+    assert!(client
+        .get_object_info(TUF_AUTH_KEY_ID, Type::AuthenticationKey)
+        .is_err());
+    assert!(device.generated.borrow().is_empty());
+}
+
+#[test]
+fn refuses_to_overwrite_attestations() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let output_dir = products_dir.path().join(SERIAL);
+    fs::create_dir_all(&output_dir).unwrap();
+    fs::write(
+        output_dir.join(format!("{}_root_attestation.der", SERIAL)),
+        b"",
+    )
+    .unwrap();
+
+    let device = MockDevice::new();
+    plant_object(&device);
+
+    let mut prompt = ScriptedPrompt::default();
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert!(matches!(err, error::Error::OutputExists(ref msg)
+        if msg.starts_with("Attestation file already exists")));
+
+    let client = default_client(&device).unwrap();
+    assert!(client.get_object_info(100, Type::Opaque).is_ok());
+}
+
+// Leaves the device (and journal) as if provisioning died right after
+// replacing the default auth key, optionally after generating the keys for
+// some roles.
+fn interrupt(device: &MockDevice, products_dir: &Path, generated: &[Role]) {
+    let output_dir = products_dir.join(SERIAL);
+    fs::create_dir_all(&output_dir).unwrap();
+    let mut journal = Journal::open(&journal::path(&output_dir, SERIAL)).unwrap();
+    journal.begin(&Stage::Provision).unwrap();
+
+    let mut prompt = ScriptedPrompt::new(&[true], &[PASSWORD, PASSWORD]);
+    new_auth_key(device, &mut prompt, Capability::SIGN_ECDSA, &mut journal).unwrap();
+    prompt.assert_exhausted();
+
+    let client = device
+        .open(Credentials::from_password(
+            TUF_AUTH_KEY_ID,
+            PASSWORD.as_bytes(),
+        ))
+        .unwrap();
+    for role in generated {
+        journal
+            .record(Stage::Keygen(role.name.clone()), || {
+                new_keypair_with_attestation(role, &client)
+            })
+            .unwrap();
+    }
+}
+
+// Appends raw lines to the journal, as if an earlier run had written them.
+fn append_journal(products_dir: &Path, lines: &str) {
+    let output_dir = products_dir.join(SERIAL);
+    fs::create_dir_all(&output_dir).unwrap();
+
+    let path = journal::path(&output_dir, SERIAL);
+    let mut contents = fs::read_to_string(&path).unwrap_or_default();
+    contents.push_str(lines);
+    fs::write(path, contents).unwrap();
+}
+
+fn write_product(products_dir: &Path, suffix: &str, contents: &[u8]) {
+    let output_dir = products_dir.join(SERIAL);
+    fs::create_dir_all(&output_dir).unwrap();
+    fs::write(output_dir.join(format!("{}_{}", SERIAL, suffix)), contents).unwrap();
+}
+
+#[test]
+fn resumes_after_auth_key_replacement() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    interrupt(&device, products_dir.path(), &[]);
+
+    // Resuming asks for the new auth key's password once, and never resets.
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD]);
+    provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &resume_options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap();
+    prompt.assert_exhausted();
+
+    assert_eq!(device.generated.borrow().len(), 2);
+    assert_eq!(
+        fs::read_dir(products_dir.path().join(SERIAL))
+            .unwrap()
+            .count(),
+        10
+    );
+    assert_eq!(
+        product(products_dir.path(), "targets_attestation.der"),
+        mock_attestation(TUF_TARGETS_KEY_ID)
+    );
+}
+
+#[test]
+fn resumes_after_partial_key_generation() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    let roles = default_roles(KeyType::P384);
+    interrupt(&device, products_dir.path(), &roles[..1]);
+
+    // The earlier run got as far as writing the root key's attestation.
+    write_product(products_dir.path(), "cert.der", DEVICE_CERT);
+    write_product(
+        products_dir.path(),
+        "root_attestation.der",
+        b"earlier attestation",
+    );
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD]);
+    provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &resume_options(KeyType::P384, products_dir.path()),
+    )
+    .unwrap();
+    prompt.assert_exhausted();
+
+    // MockClient panics if a key is generated twice, so only targets was new.
+    assert_eq!(device.generated.borrow().len(), 2);
+
+    // What was already written is kept, and the rest is filled in.
+    assert_eq!(
+        product(products_dir.path(), "root_attestation.der"),
+        b"earlier attestation"
+    );
+    let mut raw = vec![0x04];
+    raw.extend(mock_point(asymmetric::Algorithm::EcP384, TUF_ROOT_KEY_ID));
+    assert_eq!(product(products_dir.path(), "root_pubkey.pub"), raw);
+    assert_eq!(
+        fs::read_dir(products_dir.path().join(SERIAL))
+            .unwrap()
+            .count(),
+        10
+    );
+}
+
+#[test]
+fn refuses_to_resume_onto_unexpected_state() {
+    let mismatched = Role::new("root", TUF_ROOT_KEY_ID, "not-tuf-root", KeyType::P256);
+
+    for (generated, written, expected) in &[
+        (
+            vec![mismatched],
+            vec![],
+            "object 3 isn't the root key that we'd have generated",
+        ),
+        (
+            vec![],
+            vec![("root_pubkey.pub", b"stale".to_vec())],
+            "0123456789_root_pubkey.pub exists, but the root key isn't on the HSM",
+        ),
+        (
+            default_roles(KeyType::P256),
+            vec![("targets_pubkey.pem", b"stale".to_vec())],
+            "_targets_pubkey.pem\" doesn't match the key on the HSM; aborting",
+        ),
+    ] {
+        let products_dir = tempfile::tempdir().unwrap();
+        let device = MockDevice::new();
+        interrupt(&device, products_dir.path(), generated);
+        for (suffix, contents) in written {
+            write_product(products_dir.path(), suffix, contents);
+        }
+
+        let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD]);
+        let err = provision(
+            &mut hsm(&device),
+            &mut prompt,
+            &resume_options(KeyType::P256, products_dir.path()),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, error::Error::ManualIntervention(ref msg) if msg.contains(expected)),
+            "unexpected error: {:?}",
+            err
+        );
+    }
+}
+
+#[test]
+fn replaced_auth_key_without_a_journal_is_an_auth_failure() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    interrupt(&device, products_dir.path(), &[]);
+    fs::remove_file(journal::path(&products_dir.path().join(SERIAL), SERIAL)).unwrap();
+
+    // Nothing is offered or asked.
+    let mut prompt = ScriptedPrompt::default();
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, error::Error::AuthFailure(ref msg) if msg.ends_with("try a physical reset")),
+        "unexpected error: {:?}",
+        err
+    );
+}
+
+#[test]
+fn transport_errors_are_not_resumed() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    interrupt(&device, products_dir.path(), &[]);
+    device.unreachable.set(true);
+
+    let mut prompt = ScriptedPrompt::default();
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &resume_options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, error::Error::Device(ref msg)
+            if msg.starts_with("unable to open a client connection with the HSM: connector error")),
+        "unexpected error: {}",
+        err
+    );
+}
+
+#[test]
+fn declining_resume_leaves_device_untouched() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    interrupt(&device, products_dir.path(), &[]);
+
+    let mut prompt = ScriptedPrompt::new(&[false], &[]);
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &resume_options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        error::Error::UserAbort(String::from("user interrupted provisioning"))
+    );
+    prompt.assert_exhausted();
+
+    // Nothing but the journal and transcript is written.
+    assert!(device.generated.borrow().is_empty());
+    assert_eq!(
+        fs::read_dir(products_dir.path().join(SERIAL))
+            .unwrap()
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn journals_every_stage() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap();
+
+    let output_dir = products_dir.path().join(SERIAL);
+    let recovery = journal::read(&journal::path(&output_dir, SERIAL))
+        .unwrap()
+        .unwrap();
+    assert!(recovery.finished());
+    for stage in &[
+        Stage::Reset,
+        Stage::AuthKey,
+        Stage::Keygen("root".into()),
+        Stage::Attestation("root".into()),
+        Stage::Keygen("targets".into()),
+        Stage::Attestation("targets".into()),
+        Stage::Write("cert.der".into()),
+        Stage::Write("targets_pubkey.pem".into()),
+    ] {
+        assert!(recovery.completed(stage), "{} wasn't journaled", stage);
+    }
+
+    // Resuming a finished run makes no sense.
+    let mut prompt = ScriptedPrompt::default();
+    let options = Options {
+        resume: true,
+        ..options(KeyType::P256, products_dir.path())
+    };
+    let err = provision(&mut hsm(&device), &mut prompt, &options).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("already provisioned; nothing to resume"));
+}
+
+#[test]
+fn transcribes_every_stage() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap();
+
+    let output_dir = products_dir.path().join(SERIAL);
+    let contents = fs::read_to_string(transcript::path(&output_dir, SERIAL)).unwrap();
+    assert!(!contents.contains(PASSWORD));
+
+    let events: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(events.iter().all(|event| event["time"].is_u64()));
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec![
+            "device-discovered",
+            "reset-confirmed",
+            "credential-changed",
+            "key-generated",
+            "attestation-produced",
+            "key-generated",
+            "attestation-produced",
+            "file-written",
+            "file-written",
+            "file-written",
+            "file-written",
+            "file-written",
+            "file-written",
+            "file-written",
+        ]
+    );
+
+    assert_eq!(events[0]["vendor"], "yubihsm");
+    assert_eq!(events[0]["serial_number"], SERIAL);
+    assert_eq!(events[2]["credential"], "auth-key");
+    assert_eq!(events[2]["object_id"], TUF_AUTH_KEY_ID.to_string());
+    assert_eq!(events[3]["role"], "root");
+    assert_eq!(events[3]["object_id"], TUF_ROOT_KEY_ID.to_string());
+    assert_eq!(events[3]["algorithm"], "p256");
+    assert_eq!(events[6]["role"], "targets");
+
+    // Every written file's digest matches what's on disk.
+    for event in &events[7..] {
+        let file = fs::read(output_dir.join(event["file"].as_str().unwrap())).unwrap();
+        assert_eq!(event["size"], file.len());
+        assert_eq!(
+            event["sha256"],
+            transcript::hex(digest::digest(&digest::SHA256, &file).as_ref())
+        );
+    }
+}
+
+#[test]
+fn writes_a_manifest() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P384, products_dir.path()),
+    )
+    .unwrap();
+
+    let output_dir = products_dir.path().join(SERIAL);
+    let manifest = Manifest::read(&manifest::path(&output_dir, SERIAL)).unwrap();
+
+    // The MockHsm reports itself as firmware 2.0.0.
+    assert_eq!(
+        manifest.device,
+        manifest::Device {
+            vendor: String::from("yubihsm"),
+            serial_number: String::from(SERIAL),
+            firmware: String::from("2.0.0"),
+        }
+    );
+    assert_eq!(
+        manifest.roles,
+        vec![
+            manifest::Role {
+                name: String::from("root"),
+                object_id: TUF_ROOT_KEY_ID.to_string(),
+                label: String::from("tuf-root"),
+                key_type: String::from("p384"),
+            },
+            manifest::Role {
+                name: String::from("targets"),
+                object_id: TUF_TARGETS_KEY_ID.to_string(),
+                label: String::from("tuf-targets"),
+                key_type: String::from("p384"),
+            },
+        ]
+    );
+
+    // Every other file but the journal and transcript is listed, and the
+    // products check out against it.
+    let files = manifest
+        .products
+        .iter()
+        .map(|product| product.file.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 7);
+    assert!(files.contains(&"0123456789_root_pubkey.pem"));
+    assert!(!files.contains(&"0123456789_journal.log"));
+    assert!(!files.contains(&"0123456789_transcript.jsonl"));
+    assert!(manifest::verify(&output_dir, &manifest).unwrap().passed());
+}
+
+#[test]
+fn records_airgap_overrides() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    let options = Options {
+        airgap_override: Some(Override {
+            reason: "rehearsal on a laptop".into(),
+            findings: vec!["network interface wlan0 is up (operstate up)".into()],
+        }),
+        ..options(KeyType::P256, products_dir.path())
+    };
+    provision(&mut hsm(&device), &mut prompt, &options).unwrap();
+
+    let record = String::from_utf8(product(products_dir.path(), "airgap_override.log")).unwrap();
+    let lines = record.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" air-gap preflight overridden: rehearsal on a laptop"));
+    assert!(lines[1].ends_with("   found: network interface wlan0 is up (operstate up)"));
+}
+
+#[test]
+fn interrupted_runs_need_resume() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    plant_object(&device);
+    append_journal(
+        products_dir.path(),
+        "1600000000 begin provision\n1600000001 begin reset\n",
+    );
+
+    let mut prompt = ScriptedPrompt::default();
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("an earlier run was interrupted during reset; rerun with --resume"),
+        "unexpected error: {}",
+        err
+    );
+
+    // Nothing is asked or reset.
+    let client = default_client(&device).unwrap();
+    assert!(client.get_object_info(100, Type::Opaque).is_ok());
+
+    // An interrupted reset is safe to redo.
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    let options = Options {
+        resume: true,
+        ..options(KeyType::P256, products_dir.path())
+    };
+    provision(&mut hsm(&device), &mut prompt, &options).unwrap();
+    prompt.assert_exhausted();
+    assert_eq!(device.generated.borrow().len(), 2);
+}
+
+#[test]
+fn a_run_that_only_began_needs_resume() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    append_journal(products_dir.path(), "1600000000 begin provision\n");
+
+    let mut prompt = ScriptedPrompt::default();
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, error::Error::Journal(ref msg)
+            if msg.ends_with("an earlier run was interrupted during provision; rerun with --resume")),
+        "unexpected error: {}",
+        err
+    );
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD, PASSWORD, PASSWORD]);
+    let options = Options {
+        resume: true,
+        ..options(KeyType::P256, products_dir.path())
+    };
+    provision(&mut hsm(&device), &mut prompt, &options).unwrap();
+    prompt.assert_exhausted();
+}
+
+#[test]
+fn nothing_to_resume_without_a_journal() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+
+    let mut prompt = ScriptedPrompt::default();
+    let options = Options {
+        resume: true,
+        ..options(KeyType::P256, products_dir.path())
+    };
+    let err = provision(&mut hsm(&device), &mut prompt, &options).unwrap_err();
+    assert!(err.to_string().ends_with("so there's nothing to resume"));
+}
+
+#[test]
+fn resumes_an_interrupted_write() {
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    let roles = default_roles(KeyType::P256);
+    interrupt(&device, products_dir.path(), &roles);
+
+    // The earlier run died partway through writing the root public key.
+    write_product(products_dir.path(), "root_pubkey.pub", b"\x04trunc");
+    append_journal(
+        products_dir.path(),
+        "1600000000 begin write root_pubkey.pub\n",
+    );
+
+    let mut prompt = ScriptedPrompt::new(&[true, true], &[PASSWORD]);
+    let options = Options {
+        resume: true,
+        ..options(KeyType::P256, products_dir.path())
+    };
+    provision(&mut hsm(&device), &mut prompt, &options).unwrap();
+    prompt.assert_exhausted();
+
+    let mut raw = vec![0x04];
+    raw.extend(mock_point(asymmetric::Algorithm::EcP256, TUF_ROOT_KEY_ID));
+    assert_eq!(product(products_dir.path(), "root_pubkey.pub"), raw);
+}
+
+#[test]
+fn journal_mismatches_need_manual_intervention() {
+    // The journal says the auth key was replaced, but the default one works.
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    append_journal(
+        products_dir.path(),
+        "1600000000 begin provision\n1600000001 begin auth-key\n1600000002 end auth-key\n",
+    );
+
+    let mut prompt = ScriptedPrompt::default();
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &resume_options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("Manual intervention required"),
+        "{}",
+        err
+    );
+
+    // The journal says the root key was generated, but it isn't on the HSM.
+    let products_dir = tempfile::tempdir().unwrap();
+    let device = MockDevice::new();
+    interrupt(&device, products_dir.path(), &[]);
+    append_journal(
+        products_dir.path(),
+        "1600000003 begin keygen root\n1600000004 end keygen root\n",
+    );
+
+    let mut prompt = ScriptedPrompt::new(&[true], &[PASSWORD]);
+    let err = provision(
+        &mut hsm(&device),
+        &mut prompt,
+        &resume_options(KeyType::P256, products_dir.path()),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        error::Error::ManualIntervention(String::from(
+            "the journal says that the root key was generated, but it isn't on the HSM; \
+             manual intervention required"
+        ))
+    );
+    assert!(device.generated.borrow().is_empty());
+}
@@ -0,0 +1,134 @@
+use tuf_hsm::yubihsm::plan::{load_body, parse_body};
+use tuf_hsm::yubihsm::role::{default_roles, Role};
+use tuf_hsm::yubihsm::KeyType;
+
+use std::path::{Path, PathBuf};
+
+const PLAN: &str = r#"
+[[body]]
+id = "YubiHSM2-1"
+vendor = "yubihsm"
+serial = "0013200461"
+type = "p256"
+
+[[body]]
+id = "YubiHSM2-2"
+vendor = "yubihsm"
+serial = "13200460"
+type = "p384"
+output_dir = "/media/ceremony-products"
+
+[[body.role]]
+name = "root"
+id = 3
+label = "tuf-root"
+
+[[body.role]]
+name = "snapshot"
+id = 5
+label = "tuf-snapshot"
+type = "ed25519"
+
+[[body]]
+id = "Nitrokey HSM-4"
+vendor = "nitrokey"
+serial = "DENK0102947"
+type = "p384"
+"#;
+
+#[test]
+fn loads_bodies() {
+    let body = parse_body(PLAN, "YubiHSM2-1").unwrap();
+    assert_eq!(body.id, "YubiHSM2-1");
+    assert_eq!(body.key_type, KeyType::P256);
+    assert_eq!(body.roles, default_roles(KeyType::P256));
+    assert_eq!(body.products_dir, PathBuf::from("ceremony-products"));
+
+    let body = parse_body(PLAN, "YubiHSM2-2").unwrap();
+    assert_eq!(
+        body.roles,
+        [
+            Role::new("root", 3, "tuf-root", KeyType::P384),
+            Role::new("snapshot", 5, "tuf-snapshot", KeyType::Ed25519),
+        ]
+    );
+    assert_eq!(body.products_dir, PathBuf::from("/media/ceremony-products"));
+}
+
+#[test]
+fn checks_serial_numbers() {
+    let body = parse_body(PLAN, "YubiHSM2-1").unwrap();
+    body.check_serial("0013200461").unwrap();
+    body.check_serial("13200461").unwrap();
+    assert_eq!(
+        body.check_serial("0013200462").unwrap_err(),
+        "the plan expects YubiHSM2-1 to have serial number 0013200461, \
+         but this HSM's is 0013200462; aborting"
+    );
+
+    // The plan's serial number can leave off the leading zeros, too.
+    parse_body(PLAN, "YubiHSM2-2")
+        .unwrap()
+        .check_serial("0013200460")
+        .unwrap();
+}
+
+#[test]
+fn refuses_other_vendors_and_unknown_bodies() {
+    assert_eq!(
+        parse_body(PLAN, "Nitrokey HSM-4").unwrap_err(),
+        "the plan says that Nitrokey HSM-4 is a nitrokey HSM, not a yubihsm; aborting"
+    );
+    assert_eq!(
+        parse_body(PLAN, "YubiHSM2-4").unwrap_err(),
+        "no body YubiHSM2-4 in the plan"
+    );
+}
+
+#[test]
+fn refuses_bad_plans() {
+    for (plan, expected) in &[
+        (
+            "[[body]]\nid = \"a\"\nvendor = \"yubihsm\"\nserial = \"1\"\ntype = \"p521\"\n",
+            "body a: key type \"p521\" must be one of p256, p384, ed25519",
+        ),
+        (
+            "[[body]]\nid = \"a\"\nvendor = \"yubihsm\"\nserial = \"1\"\ntype = \"p256\"\n\
+             [[body]]\nid = \"a\"\nvendor = \"yubihsm\"\nserial = \"2\"\ntype = \"p256\"\n",
+            "body a is listed more than once in the plan",
+        ),
+        (
+            "[[body]]\nid = \"a\"\nvendor = \"yubihsm\"\nserial = \"1\"\ntype = \"p256\"\n\
+             [[body.role]]\nname = \"root\"\nid = 3\nlabel = \"tuf-root\"\n\
+             [[body.role]]\nname = \"targets\"\nid = 3\nlabel = \"tuf-targets\"\n",
+            "body a: object ID 3 is used by more than one role",
+        ),
+        (
+            "[[body]]\nid = \"a\"\nvendor = \"yubihsm\"\nserial = \"1\"\ntype = \"p256\"\n\
+             [[body.role]]\nname = \"root\"\nid = 70000\nlabel = \"tuf-root\"\n",
+            "body a: role root has a bad object ID: 70000",
+        ),
+    ] {
+        assert_eq!(parse_body(plan, "a").unwrap_err(), *expected);
+    }
+
+    // Typos in field names are errors, not silently ignored.
+    assert!(parse_body(
+        "[[body]]\nid = \"a\"\nvendor = \"yubihsm\"\nserial = \"1\"\nkey_type = \"p256\"\n",
+        "a"
+    )
+    .unwrap_err()
+    .starts_with("malformed plan"));
+}
+
+#[test]
+fn loads_the_runbook_plan() {
+    let plan = Path::new(env!("CARGO_MANIFEST_DIR")).join("../ceremony-plan.toml");
+    for (body_id, key_type) in &[
+        ("YubiHSM2-1", KeyType::P256),
+        ("YubiHSM2-2", KeyType::P384),
+        ("YubiHSM2-3", KeyType::P256),
+    ] {
+        assert_eq!(load_body(&plan, body_id).unwrap().key_type, *key_type);
+    }
+}
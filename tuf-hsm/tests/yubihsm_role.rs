@@ -0,0 +1,79 @@
+use tuf_hsm::yubihsm::role::{check_roles, default_roles, parse, Role};
+use tuf_hsm::yubihsm::KeyType;
+
+#[test]
+fn parses_roles() {
+    assert_eq!(
+        parse("snapshot:5:tuf-snapshot", KeyType::P256).unwrap(),
+        Role::new("snapshot", 5, "tuf-snapshot", KeyType::P256)
+    );
+    assert_eq!(
+        parse("timestamp:6:tuf-timestamp:ed25519", KeyType::P256).unwrap(),
+        Role::new("timestamp", 6, "tuf-timestamp", KeyType::Ed25519)
+    );
+
+    for spec in &[
+        "snapshot",
+        "snapshot:5",
+        "snapshot:five:tuf-snapshot",
+        "snapshot:70000:tuf-snapshot",
+        "snapshot:5:tuf-snapshot:rsa2048",
+        "snapshot:5:tuf-snapshot:p256:extra",
+    ] {
+        assert!(
+            parse(spec, KeyType::P256).is_err(),
+            "{} should be rejected",
+            spec
+        );
+    }
+}
+
+#[test]
+fn default_roles_are_valid() {
+    let roles = default_roles(KeyType::P384);
+    check_roles(&roles).unwrap();
+    assert_eq!(
+        roles,
+        [
+            Role::new("root", 3, "tuf-root", KeyType::P384),
+            Role::new("targets", 4, "tuf-targets", KeyType::P384),
+        ]
+    );
+    assert_eq!(roles[0].file_suffix("pubkey.pem"), "root_pubkey.pem");
+}
+
+#[test]
+fn rejects_unusable_roles() {
+    for (role, expected) in &[
+        (
+            Role::new("", 5, "tuf-snapshot", KeyType::P256),
+            "bad role name \"\": use letters, digits, '-' and '_'",
+        ),
+        (
+            Role::new("../snapshot", 5, "tuf-snapshot", KeyType::P256),
+            "bad role name \"../snapshot\": use letters, digits, '-' and '_'",
+        ),
+        (
+            Role::new("snapshot", 0, "tuf-snapshot", KeyType::P256),
+            "role snapshot can't use object ID 0",
+        ),
+        (
+            Role::new("snapshot", 5, "", KeyType::P256),
+            "role snapshot needs a label",
+        ),
+    ] {
+        assert_eq!(
+            check_roles(std::slice::from_ref(role)).unwrap_err(),
+            *expected
+        );
+    }
+
+    let long_label = "x".repeat(41);
+    assert!(
+        check_roles(&[Role::new("snapshot", 5, &long_label, KeyType::P256)])
+            .unwrap_err()
+            .starts_with("role snapshot has a bad label")
+    );
+
+    assert_eq!(check_roles(&[]).unwrap_err(), "no roles to provision");
+}
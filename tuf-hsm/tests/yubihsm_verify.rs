@@ -0,0 +1,349 @@
+// Tests for `yubihsm-provision verify`.
+//
+// tests/fixtures/0000000001 is a synthetic set of products, signed by the
+// throwaway "Test YubiHSM" CAs in tests/fixtures/test-ca-certs.pem (all made
+// with `openssl req`/`openssl x509`). The real products from the 2020-10-30
+// ceremony are checked too, both up to the HSM's own certificate and all the
+// way to the Yubico CAs embedded in this build (ignored until the build embeds
+// them). imported_root_attestation.der
+// is a root attestation for 0000000001 that claims an imported key with an
+// extra capability. tests/fixtures/0000000002 is like 0000000001, but with
+// Ed25519 keys.
+
+use yubihsm::capability::Capability;
+use yubihsm::domain::Domain;
+use yubihsm::object::Origin;
+
+use tuf_hsm::error::Error;
+use tuf_hsm::yubihsm::cli::{self, verify_app};
+use tuf_hsm::yubihsm::role::{default_roles, Role};
+use tuf_hsm::yubihsm::verify::{
+    load_certs, verify_products, yubico_ca_certs, Report, YUBICO_CA_FINGERPRINTS,
+};
+use tuf_hsm::yubihsm::{KeyType, TUF_ROOT_KEY_ID};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+// 0000000001 holds P-384 keys for the default roles.
+fn roles() -> Vec<Role> {
+    default_roles(KeyType::P384)
+}
+
+fn test_cas() -> Vec<Vec<u8>> {
+    load_certs(&fs::read(fixtures().join("test-ca-certs.pem")).unwrap()).unwrap()
+}
+
+// Copies a products directory somewhere that we can tamper with it.
+fn copy_products(products_dir: &Path) -> (tempfile::TempDir, PathBuf) {
+    let tempdir = tempfile::tempdir().unwrap();
+    let copy = tempdir.path().join(products_dir.file_name().unwrap());
+    fs::create_dir(&copy).unwrap();
+    for entry in fs::read_dir(products_dir).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), copy.join(entry.file_name())).unwrap();
+    }
+    (tempdir, copy)
+}
+
+fn failures(report: &Report) -> Vec<&str> {
+    report
+        .checks
+        .iter()
+        .filter(|check| check.result.is_err())
+        .map(|check| check.description.as_str())
+        .collect()
+}
+
+#[test]
+fn verifies_full_chain() {
+    let report = verify_products(&fixtures().join("0000000001"), &roles(), &test_cas()).unwrap();
+    assert_eq!(report.serial_number, "0000000001");
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.checks.len(), 9);
+
+    let (filename, root) = &report.attestations[0];
+    assert_eq!(filename, "0000000001_root_attestation.der");
+    assert_eq!(root.serial_number, 1);
+    assert_eq!(root.firmware_version, "2.2.0");
+    assert_eq!(root.origin, Origin::Generated);
+    assert_eq!(root.domains, Domain::DOM1);
+    assert_eq!(root.capabilities, Capability::SIGN_ECDSA);
+    assert_eq!(root.object_id, TUF_ROOT_KEY_ID);
+    assert_eq!(root.label, "tuf-root");
+
+    // Every product is checksummed, public keys included.
+    assert_eq!(report.checksums.len(), 7);
+    let cert_checksum = report
+        .checksums
+        .iter()
+        .find(|c| c.filename == "0000000001_cert.der")
+        .unwrap();
+    assert_eq!(cert_checksum.sha256.len(), 64);
+    assert_eq!(cert_checksum.sha1.len(), 40);
+}
+
+#[test]
+fn verifies_ed25519_keys() {
+    let report = verify_products(
+        &fixtures().join("0000000002"),
+        &default_roles(KeyType::Ed25519),
+        &test_cas(),
+    )
+    .unwrap();
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.checks.len(), 9);
+
+    for (_, attestation) in &report.attestations {
+        assert_eq!(attestation.serial_number, 2);
+        assert_eq!(attestation.capabilities, Capability::SIGN_EDDSA);
+    }
+}
+
+#[test]
+fn requires_yubico_cas() {
+    // Without the intermediate, nothing chains.
+    let mut cas = test_cas();
+    cas.pop();
+    let report = verify_products(&fixtures().join("0000000001"), &roles(), &cas).unwrap();
+    assert!(!report.passed());
+    assert!(report.checks[0].result.is_err());
+    assert!(report.checks[1].result.is_err());
+
+    // The attestations are still checked against the HSM's certificate.
+    assert_eq!(failures(&report).len(), 2);
+}
+
+#[test]
+fn rejects_tampered_attestation() {
+    let (_tempdir, products_dir) = copy_products(&fixtures().join("0000000001"));
+
+    let attestation = products_dir.join("0000000001_targets_attestation.der");
+    let mut der = fs::read(&attestation).unwrap();
+    // Flip a bit in the signature, which is at the end of the certificate.
+    let last = der.len() - 1;
+    der[last] ^= 0x01;
+    fs::write(&attestation, der).unwrap();
+
+    let report = verify_products(&products_dir, &roles(), &test_cas()).unwrap();
+    assert_eq!(
+        failures(&report),
+        ["0000000001_targets_attestation.der is signed by 0000000001_cert.der"]
+    );
+    assert!(report.checks[6]
+        .result
+        .as_ref()
+        .unwrap_err()
+        .contains("doesn't verify"));
+}
+
+#[test]
+fn rejects_foreign_device_cert() {
+    // Swap in another HSM's certificate: the attestations no longer match it.
+    let (_tempdir, products_dir) = copy_products(&fixtures().join("0000000001"));
+    fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../ceremony/2020-10-30/ceremony-products/0013200460/0013200460_cert.der"),
+        products_dir.join("0000000001_cert.der"),
+    )
+    .unwrap();
+
+    let report = verify_products(&products_dir, &roles(), &test_cas()).unwrap();
+    assert_eq!(
+        failures(&report),
+        [
+            "0000000001_cert.der chains to a Yubico root CA",
+            "0000000001_cert.der belongs to HSM 1",
+            "0000000001_root_attestation.der is signed by 0000000001_cert.der",
+            "0000000001_targets_attestation.der is signed by 0000000001_cert.der",
+        ]
+    );
+}
+
+#[test]
+fn rejects_swapped_public_keys() {
+    let (_tempdir, products_dir) = copy_products(&fixtures().join("0000000001"));
+    let root = products_dir.join("0000000001_root_pubkey.pub");
+    let targets = products_dir.join("0000000001_targets_pubkey.pub");
+    let root_pubkey = fs::read(&root).unwrap();
+    fs::copy(&targets, &root).unwrap();
+    fs::write(&targets, root_pubkey).unwrap();
+
+    let report = verify_products(&products_dir, &roles(), &test_cas()).unwrap();
+    assert_eq!(
+        failures(&report),
+        [
+            "0000000001_root_attestation.der certifies the key in 0000000001_root_pubkey.pub",
+            "0000000001_targets_attestation.der certifies the key in 0000000001_targets_pubkey.pub",
+        ]
+    );
+}
+
+#[test]
+fn rejects_swapped_attestations() {
+    let (_tempdir, products_dir) = copy_products(&fixtures().join("0000000001"));
+    let root = products_dir.join("0000000001_root_attestation.der");
+    let targets = products_dir.join("0000000001_targets_attestation.der");
+    let root_attestation = fs::read(&root).unwrap();
+    fs::copy(&targets, &root).unwrap();
+    fs::write(&targets, root_attestation).unwrap();
+
+    let report = verify_products(&products_dir, &roles(), &test_cas()).unwrap();
+    let errors: Vec<_> = report
+        .checks
+        .iter()
+        .filter_map(|check| check.result.as_ref().err())
+        .collect();
+    assert_eq!(errors.len(), 4);
+    assert_eq!(
+        errors[1],
+        "object ID is 4, not 3; label is \"tuf-targets\", not \"tuf-root\""
+    );
+}
+
+#[test]
+fn rejects_imported_keys_and_extra_capabilities() {
+    let (_tempdir, products_dir) = copy_products(&fixtures().join("0000000001"));
+    fs::copy(
+        fixtures().join("imported_root_attestation.der"),
+        products_dir.join("0000000001_root_attestation.der"),
+    )
+    .unwrap();
+
+    let report = verify_products(&products_dir, &roles(), &test_cas()).unwrap();
+    assert_eq!(failures(&report).len(), 1);
+    assert_eq!(
+        report.checks[5].result.as_ref().unwrap_err(),
+        "origin is Imported, not Generated; \
+         capabilities are SIGN_ECDSA | EXPORTABLE_UNDER_WRAP, not SIGN_ECDSA"
+    );
+}
+
+#[test]
+fn rejects_wrong_key_type() {
+    let report = verify_products(
+        &fixtures().join("0000000001"),
+        &default_roles(KeyType::P256),
+        &test_cas(),
+    )
+    .unwrap();
+    assert_eq!(
+        failures(&report),
+        [
+            "0000000001_root_attestation.der certifies the key in 0000000001_root_pubkey.pub",
+            "0000000001_targets_attestation.der certifies the key in 0000000001_targets_pubkey.pub",
+        ]
+    );
+    assert_eq!(
+        report.checks[4].result.as_ref().unwrap_err(),
+        "certified key isn't a P256 key"
+    );
+}
+
+#[test]
+fn verifies_extra_roles() {
+    // A role that we weren't given isn't looked for...
+    let report = verify_products(
+        &fixtures().join("0000000001"),
+        &[Role::new(
+            "root",
+            TUF_ROOT_KEY_ID,
+            "tuf-root",
+            KeyType::P384,
+        )],
+        &test_cas(),
+    )
+    .unwrap();
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.checks.len(), 6);
+
+    // ...and one that we were given must be there.
+    let mut roles = roles();
+    roles.push(Role::new("snapshot", 5, "tuf-snapshot", KeyType::P384));
+    let err = verify_products(&fixtures().join("0000000001"), &roles, &test_cas())
+        .err()
+        .unwrap();
+    assert!(err.contains("0000000001_snapshot_pubkey.pub"));
+}
+
+#[test]
+fn missing_products_are_errors() {
+    let (_tempdir, products_dir) = copy_products(&fixtures().join("0000000001"));
+    fs::remove_file(products_dir.join("0000000001_root_attestation.der")).unwrap();
+
+    let err = verify_products(&products_dir, &roles(), &test_cas())
+        .err()
+        .unwrap();
+    assert!(err.contains("0000000001_root_attestation.der"));
+}
+
+#[test]
+#[ignore = "needs Yubico's CA certificates in assets/yubihsm2-ca-certs.pem"]
+fn embeds_pinned_yubico_cas() {
+    let cas = yubico_ca_certs().unwrap();
+    assert!(!cas.is_empty());
+    assert_eq!(cas.len(), YUBICO_CA_FINGERPRINTS.len());
+}
+
+#[test]
+fn verify_takes_cas_from_the_command_line() {
+    let products = fixtures().join("0000000001");
+    let ca = fixtures().join("test-ca-certs.pem");
+    let args = |ca: Option<&Path>| {
+        let mut args = vec!["verify", "--type", "p384", products.to_str().unwrap()];
+        if let Some(ca) = ca {
+            args.extend(["--ca", ca.to_str().unwrap()]);
+        }
+        verify_app("verify").get_matches_from(args)
+    };
+
+    assert!(cli::verify(&args(Some(&ca))).is_ok());
+    if yubico_ca_certs().unwrap().is_empty() {
+        assert!(matches!(
+            cli::verify(&args(None)),
+            Err(Error::Attestation(_))
+        ));
+    }
+}
+
+fn ceremony_2020() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../ceremony/2020-10-30/ceremony-products")
+}
+
+const CEREMONY_2020_HSMS: &[(&str, KeyType)] = &[
+    ("0013200460", KeyType::P384),
+    ("0013200461", KeyType::P256),
+    ("0013200462", KeyType::P256),
+];
+
+#[test]
+#[ignore = "needs Yubico's CA certificates in assets/yubihsm2-ca-certs.pem"]
+fn verifies_2020_ceremony_products() {
+    let cas = yubico_ca_certs().unwrap();
+    for (serial, key_type) in CEREMONY_2020_HSMS {
+        let report = verify_products(
+            &ceremony_2020().join(serial),
+            &default_roles(*key_type),
+            &cas,
+        )
+        .unwrap();
+        assert!(report.passed(), "{}", report);
+    }
+}
+
+#[test]
+fn verifies_2020_ceremony_attestations() {
+    let ceremony = ceremony_2020();
+    for (serial, key_type) in CEREMONY_2020_HSMS {
+        // Everything but the Yubico CA checks passes.
+        let report =
+            verify_products(&ceremony.join(serial), &default_roles(*key_type), &[]).unwrap();
+        assert_eq!(failures(&report).len(), 2, "{}", report);
+        assert!(report.checks[0].result.is_err());
+        assert!(report.checks[1].result.is_err());
+    }
+}